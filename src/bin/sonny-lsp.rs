@@ -0,0 +1,443 @@
+// A language server for Sonny files. It speaks the Language Server Protocol
+// over stdin and stdout, and provides diagnostics, go-to-definition, hover,
+// and completion.
+
+#[macro_use]
+extern crate serde_json;
+extern crate sonny;
+
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use serde_json::Value;
+
+use sonny::{
+    error::{ErrorSeverity, ErrorSpec},
+    lexer::{CodeLocation, KEYWORDS},
+    Builder, Chain, ChainLinks, ChainName, Error, Parser,
+};
+
+// An open document and the results of parsing it
+struct Document {
+    path: String,
+    text: String,
+    builder: Builder,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Document {
+        let path = uri_to_path(uri);
+        let mut builder = Parser::from_source(&path, &text, Builder::new()).parse_items(false);
+        // Lints are only useful once the code compiles
+        if !builder.diagnostics.iter().any(Error::is_fatal) {
+            let warnings = builder.lint();
+            builder.diagnostics.extend(warnings);
+        }
+        Document {
+            path,
+            text,
+            builder,
+        }
+    }
+    // The name of the document's file, which is what locations refer to it by
+    fn file_name(&self) -> &str {
+        file_name(&self.path)
+    }
+    // Find the chain that is referred to or declared at a byte offset
+    fn chain_at(&self, offset: usize) -> Option<&Chain> {
+        let covers = |loc: &CodeLocation| {
+            *loc.path == *self.path && loc.span.start <= offset && offset <= loc.span.end
+        };
+        self.builder
+            .references
+            .iter()
+            .find(|(loc, _)| covers(loc))
+            .and_then(|(_, name)| self.builder.chains.get(name))
+            .or_else(|| {
+                self.builder
+                    .chains
+                    .values()
+                    .find(|chain| covers(&chain.location))
+            })
+    }
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut documents: HashMap<String, Document> = HashMap::new();
+    while let Some(message) = read_message(&mut input) {
+        let id = message.get("id").cloned();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match message["method"].as_str() {
+            Some("initialize") => respond(
+                id,
+                json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "definitionProvider": true,
+                        "hoverProvider": true,
+                        "completionProvider": { "triggerCharacters": [":"] }
+                    },
+                    "serverInfo": { "name": "sonny-lsp" }
+                }),
+            ),
+            Some("shutdown") => respond(id, Value::Null),
+            Some("exit") => return,
+            Some("textDocument/didOpen") => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                let document = Document::new(&uri, text.to_string());
+                publish_diagnostics(&uri, &document);
+                documents.insert(uri, document);
+            }
+            Some("textDocument/didChange") => {
+                // The whole document is sent with every change
+                if let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                {
+                    let document = Document::new(&uri, text.to_string());
+                    publish_diagnostics(&uri, &document);
+                    documents.insert(uri, document);
+                }
+            }
+            Some("textDocument/didClose") => {
+                documents.remove(&uri);
+                notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                );
+            }
+            Some("textDocument/definition") => {
+                let result = documents
+                    .get(&uri)
+                    .and_then(|document| definition(document, &params["position"]));
+                respond(id, result.unwrap_or(Value::Null));
+            }
+            Some("textDocument/hover") => {
+                let result = documents
+                    .get(&uri)
+                    .and_then(|document| hover(document, &params["position"]));
+                respond(id, result.unwrap_or(Value::Null));
+            }
+            Some("textDocument/completion") => {
+                let result = documents
+                    .get(&uri)
+                    .map(completion)
+                    .unwrap_or_else(|| Value::Array(Vec::new()));
+                respond(id, result);
+            }
+            // Requests that are not supported still need a response
+            Some(_) if id.is_some() => write_message(&json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": "Method not found" }
+            })),
+            _ => (),
+        }
+    }
+}
+
+// Send the errors and warnings found in a document to the client
+fn publish_diagnostics(uri: &str, document: &Document) {
+    let diagnostics: Vec<Value> = document
+        .builder
+        .diagnostics
+        .iter()
+        // Library files do not need an out chain
+        .filter(|error| !matches!(error.spec, ErrorSpec::NoOutChain))
+        .map(|error| {
+            let mut message = error.spec.to_string();
+            if let Some(help) = error.spec.help() {
+                message.push_str("\nhelp: ");
+                message.push_str(help);
+            }
+            // Errors in other files are shown at the start of this one
+            let error_range = match error.location {
+                Some(ref loc) if *loc.path == *document.path => range(loc),
+                Some(ref loc) => {
+                    message = format!("{}: {}", loc, message);
+                    json!({
+                        "start": { "line": 0, "character": 0 },
+                        "end": { "line": 0, "character": 0 }
+                    })
+                }
+                None => json!({
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 }
+                }),
+            };
+            let related: Vec<Value> = error
+                .labels
+                .iter()
+                .map(|(loc, text)| {
+                    json!({
+                        "location": {
+                            "uri": path_to_uri(&loc.path),
+                            "range": range(loc)
+                        },
+                        "message": text
+                    })
+                })
+                .collect();
+            json!({
+                "range": error_range,
+                "severity": match error.severity {
+                    ErrorSeverity::Fatal => 1,
+                    ErrorSeverity::Warning => 2,
+                    ErrorSeverity::Debug | ErrorSeverity::Print => 3,
+                },
+                "code": error.spec.code(),
+                "source": "sonny",
+                "message": message,
+                "relatedInformation": related
+            })
+        })
+        .collect();
+    notify(
+        "textDocument/publishDiagnostics",
+        json!({ "uri": uri, "diagnostics": diagnostics }),
+    );
+}
+
+// Find where the chain at a position was declared
+fn definition(document: &Document, position: &Value) -> Option<Value> {
+    let offset = offset(&document.text, position)?;
+    let chain = document.chain_at(offset)?;
+    Some(json!({
+        "uri": path_to_uri(&chain.location.path),
+        "range": range(&chain.location)
+    }))
+}
+
+// Describe the chain at a position. Notes chains show their duration,
+// and other chains show how many backlink arguments they take.
+fn hover(document: &Document, position: &Value) -> Option<Value> {
+    let offset = offset(&document.text, position)?;
+    let chain = document.chain_at(offset)?;
+    let name = match chain.name {
+        ChainName::Scoped(ref name) => name.clone(),
+        ChainName::Anonymous(..) => return None,
+    };
+    let description = match chain.links {
+        ChainLinks::OnlyNotes(ref notes_or_ids, period) => {
+            let count = document.builder.flatten_notes(notes_or_ids, 0.0).len();
+            format!(
+                "Notes: {} note{} lasting {} seconds",
+                count,
+                if count == 1 { "" } else { "s" },
+                period.duration()
+            )
+        }
+        ChainLinks::Generic(ref links) => {
+            let arity = document.builder.arity(&chain.name);
+            format!(
+                "{} link{}, takes {} backlink argument{}",
+                links.len(),
+                if links.len() == 1 { "" } else { "s" },
+                arity,
+                if arity == 1 { "" } else { "s" }
+            )
+        }
+    };
+    Some(json!({
+        "contents": {
+            "kind": "markdown",
+            "value": format!("```\n{}\n```\n{}", name, description)
+        }
+    }))
+}
+
+// List the keywords and the names of all of the chains in scope
+fn completion(document: &Document) -> Value {
+    let builder = &document.builder;
+    let own_prefix = format!(
+        "{}::",
+        Path::new(document.file_name())
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+    );
+    let mut names: Vec<String> = builder
+        .chains
+        .keys()
+        .filter_map(|name| match *name {
+            ChainName::Scoped(ref full) => {
+                // Use the shortest name that refers to the chain
+                let short = full.split("::").last().unwrap_or_default();
+                let short_name = ChainName::Scoped(short.to_string());
+                Some(
+                    if builder.find_chain(&short_name).map(|c| &c.name) == Some(name) {
+                        short.to_string()
+                    } else if full.starts_with(&own_prefix) {
+                        full[own_prefix.len()..].to_string()
+                    } else {
+                        full.clone()
+                    },
+                )
+            }
+            ChainName::Anonymous(..) => None,
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    let chains = names
+        .into_iter()
+        .map(|name| json!({ "label": name, "kind": 3, "detail": "chain" }));
+    let keywords = KEYWORDS
+        .iter()
+        .map(|&(keyword, _)| json!({ "label": keyword, "kind": 14 }));
+    Value::Array(chains.chain(keywords).collect())
+}
+
+// Convert a location into a range in the client's terms, where lines
+// start at 0 and characters are counted in UTF-16 code units
+fn range(loc: &CodeLocation) -> Value {
+    let (end_line, end_column) = loc.end_line_column();
+    json!({
+        "start": position(&loc.source, loc.line, loc.column),
+        "end": position(&loc.source, end_line, end_column)
+    })
+}
+
+// Convert a line and a character column, which both start at 1,
+// into a position in the client's terms
+fn position(source: &str, line: usize, column: usize) -> Value {
+    let character: usize = source
+        .lines()
+        .nth(line.saturating_sub(1))
+        .unwrap_or_default()
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(char::len_utf16)
+        .sum();
+    json!({ "line": line.saturating_sub(1), "character": character })
+}
+
+// Convert a position from the client into a byte offset in the text
+fn offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let mut offset = 0;
+    for (i, line_text) in text.split('\n').enumerate() {
+        if i == line {
+            let mut units = 0;
+            for (byte, c) in line_text.char_indices() {
+                if units >= character {
+                    return Some(offset + byte);
+                }
+                units += c.len_utf16();
+            }
+            return Some(offset + line_text.len());
+        }
+        offset += line_text.len() + 1;
+    }
+    None
+}
+
+// The last part of a path
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+// Convert a file uri into a path
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.trim_start_matches("file://");
+    // Decode percent-encoded bytes
+    let mut bytes = Vec::new();
+    let mut chars = path.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            if let Some(decoded) = std::str::from_utf8(&hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                bytes.push(decoded);
+                continue;
+            }
+            bytes.push(b);
+            bytes.extend(hex);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Convert a path into a file uri
+fn path_to_uri(path: &str) -> String {
+    let path = Path::new(path);
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut uri = String::from("file://");
+    for b in absolute.to_string_lossy().bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+// Read a message from the client. Returns None when the input has ended.
+fn read_message<R: BufRead>(input: &mut R) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut content = vec![0; length?];
+    input.read_exact(&mut content).ok()?;
+    Some(serde_json::from_slice(&content).unwrap_or(Value::Null))
+}
+
+// Write a message to the client
+fn write_message(message: &Value) {
+    let content = message.to_string();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let _ = write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    );
+    let _ = output.flush();
+}
+
+// Respond to a request
+fn respond(id: Option<Value>, result: Value) {
+    write_message(&json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+}
+
+// Send a notification
+fn notify(method: &str, params: Value) {
+    write_message(&json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+}
@@ -0,0 +1,239 @@
+// This module contains the analysis of how many arguments chains take
+
+use std::collections::HashSet;
+
+use builder::*;
+
+impl Builder {
+    // The number of links before a use of a chain that its backlinks
+    // refer to. This is the number of arguments the chain takes.
+    // Notes chains do not take any arguments.
+    pub fn arity(&self, name: &ChainName) -> usize {
+        self.chain_arity(name, &mut Vec::new())
+    }
+    // The number of arguments a chain needs. The chains that are being
+    // found are kept track of so that chains which use themselves end.
+    fn chain_arity(&self, name: &ChainName, finding: &mut Vec<ChainName>) -> usize {
+        let chain = match self.find_chain(name) {
+            Some(chain) if !finding.contains(&chain.name) => chain,
+            _ => return 0,
+        };
+        finding.push(chain.name.clone());
+        let arity = match chain.links {
+            ChainLinks::Generic(ref expressions) => expressions
+                .iter()
+                .enumerate()
+                // Each link can also refer to the links before it
+                .map(|(i, expression)| {
+                    self.operation_arity(&expression.0, finding)
+                        .saturating_sub(i)
+                })
+                .max()
+                .unwrap_or(0),
+            ChainLinks::OnlyNotes(..) => 0,
+        };
+        finding.pop();
+        arity
+    }
+    // The number of arguments an operation needs
+    fn operation_arity(&self, operation: &Operation, finding: &mut Vec<ChainName>) -> usize {
+        let (a, b, c) = operation.operands();
+        Some(a)
+            .into_iter()
+            .chain(b)
+            .chain(c)
+            .map(|operand| self.operand_arity(operand, finding))
+            .max()
+            .unwrap_or(0)
+    }
+    // The number of arguments an operand needs
+    fn operand_arity(&self, operand: &Operand, finding: &mut Vec<ChainName>) -> usize {
+        match *operand {
+            Operand::BackLink(num, _) => num,
+            // Chains that are used get the same arguments
+            Operand::Id(ref name, _) => self.chain_arity(name, finding),
+            Operand::Expression(ref expression) => self.operation_arity(&expression.0, finding),
+            Operand::Array(ref expressions) => expressions
+                .iter()
+                .map(|expression| self.operation_arity(&expression.0, finding))
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+    // Find the backlinks that refer to more links than there are when a
+    // chain is evaluated with no arguments, like the out chain is. Each
+    // chain that it uses is checked with the arguments it gets where it
+    // is used, which is labeled in the errors.
+    pub fn check_backlinks(&self, name: &ChainName) -> Vec<Error> {
+        let mut errors = Vec::new();
+        if self.arity(name) > 0 {
+            self.check_chain_backlinks(name, 0, None, &mut HashSet::new(), &mut errors);
+        }
+        errors
+    }
+    // Find the chains that feed links to a chain they use, but not as many
+    // as it needs. A chain that refers to its own arguments with backlinks
+    // can pass them on to the chains it uses, and a chain used from the
+    // first link is only forwarded arguments, but a chain like "g" in
+    //
+    // f: !1 + !2
+    // g: 1 -> f
+    //
+    // gives "f" only some of its links and would take the rest from
+    // wherever it is used. Each use is checked with the links before it.
+    pub fn check_used_backlinks(&self) -> Vec<Error> {
+        let mut chains: Vec<&Chain> = self.chains.values().collect();
+        chains.sort_by(|a, b| {
+            (&a.location.path, a.location.span.start)
+                .cmp(&(&b.location.path, b.location.span.start))
+        });
+        let mut errors = Vec::new();
+        let mut checked = HashSet::new();
+        for chain in chains {
+            let expressions = match chain.links {
+                ChainLinks::Generic(ref expressions) => expressions,
+                ChainLinks::OnlyNotes(..) => continue,
+            };
+            if expressions
+                .iter()
+                .any(|expression| own_backlinks(&expression.0))
+            {
+                continue;
+            }
+            for (i, expression) in expressions.iter().enumerate().skip(1) {
+                let mut uses = Vec::new();
+                operation_uses(&expression.0, &mut uses);
+                for (used, loc) in uses {
+                    if self.arity(used) > i {
+                        self.check_chain_backlinks(
+                            used,
+                            i,
+                            Some((loc, i)),
+                            &mut checked,
+                            &mut errors,
+                        );
+                    }
+                }
+            }
+        }
+        errors
+    }
+    fn check_chain_backlinks(
+        &self,
+        name: &ChainName,
+        args: usize,
+        used_at: Option<(&CodeLocation, usize)>,
+        checked: &mut HashSet<(ChainName, usize)>,
+        errors: &mut Vec<Error>,
+    ) {
+        let chain = match self.find_chain(name) {
+            Some(chain) => chain,
+            None => return,
+        };
+        if !checked.insert((chain.name.clone(), args)) {
+            return;
+        }
+        if let ChainLinks::Generic(ref expressions) = chain.links {
+            for (i, expression) in expressions.iter().enumerate() {
+                // Each link gets the links before it and then the arguments
+                let check = BacklinkCheck {
+                    name: &chain.name,
+                    links: i + args,
+                    used_at,
+                };
+                self.check_operation_backlinks(&expression.0, &check, checked, errors);
+            }
+        }
+    }
+    fn check_operation_backlinks(
+        &self,
+        operation: &Operation,
+        check: &BacklinkCheck,
+        checked: &mut HashSet<(ChainName, usize)>,
+        errors: &mut Vec<Error>,
+    ) {
+        let (a, b, c) = operation.operands();
+        for operand in Some(a).into_iter().chain(b).chain(c) {
+            match *operand {
+                Operand::BackLink(num, ref loc) if num > check.links => {
+                    let mut error =
+                        Error::new(UnsatisfiedBacklink(check.name.clone(), num, check.links))
+                            .on_line(loc.clone())
+                            .at_compile_time();
+                    if let Some((used_at, args)) = check.used_at {
+                        error = error.with_label(
+                            used_at.clone(),
+                            format!(
+                                "used here, where {} link{} before it",
+                                args,
+                                if args == 1 { " is" } else { "s are" }
+                            ),
+                        );
+                    }
+                    errors.push(error);
+                }
+                Operand::Id(ref used, ref loc) if self.arity(used) > check.links => self
+                    .check_chain_backlinks(
+                        used,
+                        check.links,
+                        Some((loc, check.links)),
+                        checked,
+                        errors,
+                    ),
+                Operand::Expression(ref expression) => {
+                    self.check_operation_backlinks(&expression.0, check, checked, errors)
+                }
+                Operand::Array(ref expressions) => {
+                    for expression in expressions {
+                        self.check_operation_backlinks(&expression.0, check, checked, errors);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+// The chain whose backlinks are being checked, how many links they can
+// refer to, and where the chain is used with how many arguments
+struct BacklinkCheck<'a> {
+    name: &'a ChainName,
+    links: usize,
+    used_at: Option<(&'a CodeLocation, usize)>,
+}
+
+// Whether an operation has backlinks of its own, not counting the ones
+// in the chains it uses
+fn own_backlinks(operation: &Operation) -> bool {
+    let (a, b, c) = operation.operands();
+    Some(a)
+        .into_iter()
+        .chain(b)
+        .chain(c)
+        .any(|operand| match *operand {
+            Operand::BackLink(..) => true,
+            Operand::Expression(ref expression) => own_backlinks(&expression.0),
+            Operand::Array(ref expressions) => expressions
+                .iter()
+                .any(|expression| own_backlinks(&expression.0)),
+            _ => false,
+        })
+}
+
+// The chains an operation uses by name and where
+fn operation_uses<'a>(operation: &'a Operation, uses: &mut Vec<(&'a ChainName, &'a CodeLocation)>) {
+    let (a, b, c) = operation.operands();
+    for operand in Some(a).into_iter().chain(b).chain(c) {
+        match *operand {
+            Operand::Id(ref name, ref loc) => uses.push((name, loc)),
+            Operand::Expression(ref expression) => operation_uses(&expression.0, uses),
+            Operand::Array(ref expressions) => {
+                for expression in expressions {
+                    operation_uses(&expression.0, uses);
+                }
+            }
+            _ => (),
+        }
+    }
+}
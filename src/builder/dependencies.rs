@@ -0,0 +1,172 @@
+// This module contains the analysis of which chains a chain uses
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use builder::*;
+
+impl Builder {
+    // The names of a chain and every chain that it uses, directly or
+    // through other chains. Each chain is listed once, before the chains
+    // that it uses.
+    pub fn dependencies(&self, name: &ChainName) -> Vec<ChainName> {
+        let mut found = Vec::new();
+        let mut visited = HashSet::new();
+        self.collect_dependencies(name, &mut found, &mut visited);
+        found
+    }
+    fn collect_dependencies(
+        &self,
+        name: &ChainName,
+        found: &mut Vec<ChainName>,
+        visited: &mut HashSet<ChainName>,
+    ) {
+        let chain = match self.find_chain(name) {
+            Some(chain) => chain,
+            None => return,
+        };
+        if !visited.insert(chain.name.clone()) {
+            return;
+        }
+        found.push(chain.name.clone());
+        let mut used = Vec::new();
+        match chain.links {
+            ChainLinks::Generic(ref expressions) => {
+                for expression in expressions {
+                    used_in_operation(&expression.0, &mut used);
+                }
+            }
+            ChainLinks::OnlyNotes(ref notes_or_ids, _) => {
+                for notes_or_id in notes_or_ids {
+                    if let NotesOrId::Id(ref name) = *notes_or_id {
+                        used.push(name.clone());
+                    }
+                }
+            }
+        }
+        for name in used {
+            self.collect_dependencies(&name, found, visited);
+        }
+    }
+    // The chains that use a chain, directly or through other chains
+    pub fn dependents(&self, name: &ChainName) -> Vec<ChainName> {
+        let mut dependents: Vec<ChainName> = self
+            .chains
+            .keys()
+            .filter(|chain| *chain != name && self.dependencies(chain).contains(name))
+            .cloned()
+            .collect();
+        dependents.sort_by_key(|name| name.to_string());
+        dependents
+    }
+    // The first chain that needs a chain to be made of notes, because it
+    // takes its properties or has it among its notes, and where
+    pub fn notes_user(&self, name: &ChainName) -> Option<(ChainName, CodeLocation)> {
+        let mut chains: Vec<&Chain> = self.chains.values().collect();
+        chains.sort_by(|a, b| {
+            (&a.location.path, a.location.span.start)
+                .cmp(&(&b.location.path, b.location.span.start))
+        });
+        chains.into_iter().find_map(|chain| {
+            let loc = match chain.links {
+                ChainLinks::Generic(ref expressions) => expressions
+                    .iter()
+                    .find_map(|expression| properties_in_operation(&expression.0, name)),
+                ChainLinks::OnlyNotes(ref notes_or_ids, _) => notes_or_ids
+                    .iter()
+                    .any(|notes_or_id| match *notes_or_id {
+                        NotesOrId::Id(ref used) => used == name,
+                        _ => false,
+                    })
+                    .then(|| chain.location.clone()),
+            };
+            loc.map(|loc| (chain.name.clone(), loc))
+        })
+    }
+    // A hash of everything that affects the output of a chain, which is the
+    // contents of it and every chain that it uses. Where things are declared
+    // is not included, so moving code around does not change the signature.
+    pub fn signature(&self, name: &ChainName) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.tempo.to_bits().hash(&mut hasher);
+        for name in self.dependencies(name) {
+            let chain = &self.chains[&name];
+            name.hash(&mut hasher);
+            chain.play.hash(&mut hasher);
+            match chain.links {
+                ChainLinks::Generic(ref expressions) => {
+                    for expression in expressions {
+                        hash_operation(&expression.0, &mut hasher);
+                    }
+                }
+                ChainLinks::OnlyNotes(ref notes_or_ids, period) => {
+                    format!("{:?}{:?}", notes_or_ids, period).hash(&mut hasher)
+                }
+            }
+            for (transform, _) in &chain.transforms {
+                format!("{:?}", transform).hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+// Find the names of the chains used in an operation
+fn used_in_operation(operation: &Operation, used: &mut Vec<ChainName>) {
+    let (a, b, c) = operation.operands();
+    for operand in Some(a).into_iter().chain(b).chain(c) {
+        match *operand {
+            Operand::Id(ref name, _) | Operand::Properties(ref name, _) => used.push(name.clone()),
+            Operand::Expression(ref expression) => used_in_operation(&expression.0, used),
+            Operand::Array(ref expressions) => {
+                for expression in expressions {
+                    used_in_operation(&expression.0, used);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// Find where the properties of a chain are taken in an operation
+fn properties_in_operation(operation: &Operation, name: &ChainName) -> Option<CodeLocation> {
+    let (a, b, c) = operation.operands();
+    Some(a)
+        .into_iter()
+        .chain(b)
+        .chain(c)
+        .find_map(|operand| match *operand {
+            Operand::Properties(ref used, ref loc) if used == name => Some(loc.clone()),
+            Operand::Expression(ref expression) => properties_in_operation(&expression.0, name),
+            Operand::Array(ref expressions) => expressions
+                .iter()
+                .find_map(|expression| properties_in_operation(&expression.0, name)),
+            _ => None,
+        })
+}
+
+// Hash an operation and its operands without their locations
+fn hash_operation<H: Hasher>(operation: &Operation, hasher: &mut H) {
+    mem::discriminant(operation).hash(hasher);
+    let (a, b, c) = operation.operands();
+    for operand in Some(a).into_iter().chain(b).chain(c) {
+        mem::discriminant(operand).hash(hasher);
+        match *operand {
+            Operand::Var(ref var) => format!("{:?}", var).hash(hasher),
+            Operand::Id(ref name, _) | Operand::Properties(ref name, _) => name.hash(hasher),
+            Operand::BackLink(num, _) => num.hash(hasher),
+            Operand::Notes(ref notes) => format!("{:?}", notes).hash(hasher),
+            Operand::Expression(ref expression) => hash_operation(&expression.0, hasher),
+            Operand::Array(ref expressions) => {
+                expressions.len().hash(hasher);
+                for expression in expressions {
+                    hash_operation(&expression.0, hasher);
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -10,75 +10,69 @@ use error::*;
 
 type Variables = Vec<Variable>;
 
-impl ChainLinks {
-    // When called on OnlyNotes links, this function returns the note whos period contains
-    // the given time. When initially called, the start offset should usually be 0.0, but
-    // recursive calls increment it as more links are searched through.
-    pub fn find_note(&self, time: f64, start_offset: f64, builder: &Builder) -> Option<Note> {
-        if let ChainLinks::OnlyNotes(ref notes_or_ids, period) = *self {
-            // If this period of these links does not contain the note, then immediately return.
-            if period.start + start_offset > time || period.end + start_offset <= time {
-                None
-            } else {
-                let mut local_offset = start_offset;
-                // For each note or id
-                for notes_or_id in notes_or_ids {
-                    match notes_or_id {
-                        // If the link is an Id
-                        NotesOrId::Id(ref id) => {
-                            // Find the associated chain
-                            if let Some(chain) = builder.find_chain(id) {
-                                // Make sure the chain has OnlyNote links
-                                if let ChainLinks::OnlyNotes(.., period) = chain.links {
-                                    let this_offset = local_offset;
-                                    // Increase the local offset by the links' period
-                                    local_offset += period.duration();
-                                    // Recursively call this function
-                                    if let Some(note) =
-                                        chain.links.find_note(time, this_offset, builder)
-                                    {
-                                        return Some(note);
-                                    }
-                                } else {
-                                    panic!(
-                                        "somehow, an OnlyNotes chain had the id of a generic chain"
-                                    );
-                                }
-                            } else {
-                                panic!("Unable to find '{}'", id);
-                            }
-                        }
-                        // If the link is Notes
-                        NotesOrId::Notes(ref notes) => {
-                            // Search the notes for one whose period contains the time.
-                            for note in notes {
-                                if note.period.start + local_offset <= time
-                                    && note.period.end + local_offset > time
-                                {
-                                    return Some(Note {
-                                        pitches: note.pitches.clone(),
-                                        period: Period {
-                                            start: note.period.start + local_offset,
-                                            end: note.period.end + local_offset,
-                                        },
-                                    });
-                                }
-                            }
-                            panic!("Unable to find note in notes even though it should have been there.");
+impl Builder {
+    // Find the note of a notes chain whose period contains the given time.
+    // The start offset is added to the periods of the chain's notes.
+    pub fn find_note(
+        &self,
+        name: &ChainName,
+        time: f64,
+        start_offset: f64,
+    ) -> SonnyResult<Option<Note>> {
+        let chain = self
+            .find_chain(name)
+            .ok_or_else(|| Error::new(CantFindChain(name.clone())))?;
+        let (notes_or_ids, period) = match chain.links {
+            ChainLinks::OnlyNotes(ref notes_or_ids, period) => (notes_or_ids, period),
+            ChainLinks::Generic(..) => {
+                return Err(Error::new(PropertiesOfGenericChain(chain.name.clone())))
+            }
+        };
+        // If this period of these links does not contain the note, then immediately return.
+        if period.start + start_offset > time || period.end + start_offset <= time {
+            return Ok(None);
+        }
+        // The time from the start of the chain that has been searched so far
+        let mut position = 0.0;
+        for notes_or_id in notes_or_ids {
+            match notes_or_id {
+                // Search the chain with the id, which starts where the
+                // previous notes end
+                NotesOrId::Id(ref id) => {
+                    if let Some(note) = self.find_note(id, time, start_offset + position)? {
+                        return Ok(Some(note));
+                    }
+                    if let Some(ChainLinks::OnlyNotes(.., period)) =
+                        self.find_chain(id).map(|chain| &chain.links)
+                    {
+                        position += period.duration();
+                    }
+                }
+                // Search the notes for one whose period contains the time.
+                // Their periods already start from the start of the chain.
+                NotesOrId::Notes(ref notes) => {
+                    for note in notes {
+                        if note.period.start + start_offset <= time
+                            && note.period.end + start_offset > time
+                        {
+                            return Ok(Some(Note {
+                                pitches: note.pitches.clone(),
+                                period: Period {
+                                    start: note.period.start + start_offset,
+                                    end: note.period.end + start_offset,
+                                },
+                                velocity: note.velocity,
+                            }));
                         }
                     }
+                    if let Some(last) = notes.last() {
+                        position = last.period.end;
+                    }
                 }
-                panic!(
-                    "Unable to find note in notes or ids even though it should have been there."
-                );
             }
-        } else {
-            panic!("Can find notes in generic chain");
         }
+        Ok(None)
     }
-}
-
-impl Builder {
     // Evalutates an oeprand with the given arguments and depth
     #[allow(clippy::too_many_arguments)]
     fn evaluate_operand(
@@ -91,52 +85,38 @@ impl Builder {
         buffer_size: usize,
         sample_rate: f64,
     ) -> SonnyResult<Variables> {
-        use Operand::*;
+        use builder::Operand::*;
         Ok(match *operand {
             // for Nums, simply return the num
             Var(ref x) => vec![x.clone(); buffer_size + window_size],
             // for Ids, call the associated function
-            Id(ref id) => {
-                self.evaluate_chain(id, args, time, window_size, buffer_size, sample_rate)?
-            }
+            Id(ref id, ref loc) => self
+                .evaluate_chain(id, args, time, window_size, buffer_size, sample_rate)
+                .map_err(|error| error.or_on_line(loc))?,
             // for Notes Properties...
-            Properties(ref id) => {
-                if let Some(chain) = self.find_chain(id) {
-                    (0..(buffer_size + window_size))
-                        .collect::<Vec<usize>>()
-                        .into_par_iter()
-                        .map(|i| time + i as f64 / sample_rate)
-                        .map(|t| {
-                            // Ensure that this is in fact an OnlyNotes chain.
-                            // This check should always succeed because the parser
-                            // checks it during the building phase
-                            if let ChainLinks::OnlyNotes(..) = chain.links {
-                                // Try to find the note and return it if it is found
-                                if let Some(note) = chain.links.find_note(t, 0.0, &self) {
-                                    Variable::Array(vec![
-                                        Variable::Array(
-                                            note.pitches
-                                                .iter()
-                                                .map(|p| Variable::Number(*p))
-                                                .collect(),
-                                        ),
-                                        Variable::Number(note.period.start),
-                                        Variable::Number(note.period.end),
-                                        Variable::Number(note.period.duration()),
-                                    ])
-                                // return zero if time is after the period of the notes
-                                } else {
-                                    Variable::Array(vec![Variable::Number(0.0); 4])
-                                }
-                            } else {
-                                panic!("Reference chain is not a note chain");
-                            }
-                        })
-                        .collect()
-                } else {
-                    panic!("Unknown id {:?}", id)
-                }
-            }
+            Properties(ref id, ref loc) => (0..(buffer_size + window_size))
+                .collect::<Vec<usize>>()
+                .into_par_iter()
+                .map(|i| time + i as f64 / sample_rate)
+                .map(|t| {
+                    // Try to find the note and return it if it is found
+                    Ok(if let Some(note) = self.find_note(id, t, 0.0)? {
+                        Variable::Array(vec![
+                            Variable::Array(
+                                note.pitches.iter().map(|p| Variable::Number(*p)).collect(),
+                            ),
+                            Variable::Number(note.period.start),
+                            Variable::Number(note.period.end),
+                            Variable::Number(note.period.duration()),
+                            Variable::Number(note.velocity),
+                        ])
+                    // return zero if time is after the period of the notes
+                    } else {
+                        Variable::Array(vec![Variable::Number(0.0); 5])
+                    })
+                })
+                .collect::<SonnyResult<Variables>>()
+                .map_err(|error| error.or_on_line(loc))?,
             // For time, simply return the time
             Time => (0..(buffer_size + window_size))
                 .collect::<Vec<usize>>()
@@ -148,7 +128,7 @@ impl Builder {
             // For buffer size, simply return the buffer size
             BufferSize => vec![Variable::Number(buffer_size as f64); buffer_size + window_size],
             // For sample rate, simply return the sample rate
-            SampleRate => vec![Variable::Number(sample_rate as f64); buffer_size + window_size],
+            SampleRate => vec![Variable::Number(sample_rate); buffer_size + window_size],
             // For window index, simpe return a sequential array up to the window size
             WindowIndex => (0..(buffer_size + window_size))
                 .map(|x| Variable::Number(f64::from(x as u32)))
@@ -254,8 +234,8 @@ impl Builder {
         // For ternary operator, if the predicate window is all true
         // or all false, only evaluate one body expression
         let dont_eval = if let Ternary(..) = expression.0 {
-            let any_false = x.iter().any(|x| *x == Variable::Number(0.0));
-            let any_true = x.iter().any(|x| *x != Variable::Number(0.0));
+            let any_false = x.iter().any(|x| !x.is_true());
+            let any_true = x.iter().any(|x| x.is_true());
             if any_false {
                 if any_true {
                     None
@@ -304,6 +284,9 @@ impl Builder {
         };
 
         // Evaluate Operation
+        let _timer = self
+            .profiler
+            .operation(&expression.0, buffer_size + window_size);
         Ok(match expression.0 {
             Add(..) => x
                 .into_par_iter()
@@ -348,13 +331,7 @@ impl Builder {
             LessThan(..) => x
                 .into_par_iter()
                 .zip(y.expect("failed to unwrap y in less than").into_par_iter())
-                .map(|(x, y)| {
-                    if x < y {
-                        Variable::Number(1.0)
-                    } else {
-                        Variable::Number(0.0)
-                    }
-                })
+                .map(|(x, y)| Variable::Bool(x < y))
                 .collect(),
             GreaterThan(..) => x
                 .into_par_iter()
@@ -362,13 +339,7 @@ impl Builder {
                     y.expect("failed to unwrap y in greater than")
                         .into_par_iter(),
                 )
-                .map(|(x, y)| {
-                    if x > y {
-                        Variable::Number(1.0)
-                    } else {
-                        Variable::Number(0.0)
-                    }
-                })
+                .map(|(x, y)| Variable::Bool(x > y))
                 .collect(),
             LessThanOrEqual(..) => x
                 .into_par_iter()
@@ -376,13 +347,7 @@ impl Builder {
                     y.expect("failed to unwrap y in less than or equal")
                         .into_par_iter(),
                 )
-                .map(|(x, y)| {
-                    if x <= y {
-                        Variable::Number(1.0)
-                    } else {
-                        Variable::Number(0.0)
-                    }
-                })
+                .map(|(x, y)| Variable::Bool(x <= y))
                 .collect(),
             GreaterThanOrEqual(..) => x
                 .into_par_iter()
@@ -390,35 +355,17 @@ impl Builder {
                     y.expect("failed to unwrap y in greater than or equal")
                         .into_par_iter(),
                 )
-                .map(|(x, y)| {
-                    if x >= y {
-                        Variable::Number(1.0)
-                    } else {
-                        Variable::Number(0.0)
-                    }
-                })
+                .map(|(x, y)| Variable::Bool(x >= y))
                 .collect(),
             Equal(..) => x
                 .into_par_iter()
                 .zip(y.expect("failed to unwrap y in equal").into_par_iter())
-                .map(|(x, y)| {
-                    if x == y {
-                        Variable::Number(1.0)
-                    } else {
-                        Variable::Number(0.0)
-                    }
-                })
+                .map(|(x, y)| Variable::Bool(x == y))
                 .collect(),
             NotEqual(..) => x
                 .into_par_iter()
                 .zip(y.expect("failed to unwrap y in not equal").into_par_iter())
-                .map(|(x, y)| {
-                    if x != y {
-                        Variable::Number(1.0)
-                    } else {
-                        Variable::Number(0.0)
-                    }
-                })
+                .map(|(x, y)| Variable::Bool(x != y))
                 .collect(),
             And(..) => x
                 .into_par_iter()
@@ -443,7 +390,7 @@ impl Builder {
                     .into_par_iter()
                     .zip(y.expect("failed to unwrap y in ternay").into_par_iter())
                     .zip(z.expect("failed to unwrap z in ternay").into_par_iter())
-                    .map(|((x, y), z)| if x != Variable::Number(0.0) { y } else { z })
+                    .map(|((x, y), z)| if x.is_true() { y } else { z })
                     .collect(),
                 Some(b) => match b {
                     b'y' => z.expect("failed to unwrap z in ternay"),
@@ -459,7 +406,7 @@ impl Builder {
                         if y < Variable::Number(0.0) {
                             Err(Error::new(ErrorSpec::NegativeIndex(f64::from(y) as i32)))
                         } else if y < x.len() {
-                            Ok(x[y].clone())
+                            Ok(x.at(y))
                         } else {
                             Err(Error::new(ErrorSpec::IndexOutOfBounds(
                                 f64::from(y) as usize,
@@ -508,16 +455,13 @@ impl Builder {
             }
             Average(..) => x.into_par_iter().map(|x| x.average()).collect(),
             FFT(..) => {
-                let mut input: Vec<Complex<f64>> = x
-                    .iter()
-                    .map(|sample| {
-                        if let Variable::Number(s) = *sample {
-                            Complex::new(s, 0.0)
-                        } else {
-                            panic!("tried to run fft on array")
-                        }
-                    })
-                    .collect();
+                let mut input: Vec<Complex<f64>> = Vec::with_capacity(x.len());
+                for sample in &x {
+                    match *sample {
+                        Variable::Array(..) => return Err(Error::new(ErrorSpec::FftOfArray)),
+                        ref sample => input.push(Complex::new(sample.as_number(), 0.0)),
+                    }
+                }
                 let mut output = vec![Complex::zero(); x.len()];
                 let mut planner = FFTplanner::new(false);
                 let fft = planner.plan_fft(x.len());
@@ -538,19 +482,32 @@ impl Builder {
                             .take((buffer_size + window_size) / 2)
                             .map(|x| Variable::Number((x.re.powf(2.0) + x.im.powf(2.0)).powf(0.5)))
                             .collect();
-                        let max = bins
-                            .iter()
-                            .max_by(|a, b| a.partial_cmp(b).expect("NaN in fft"))
-                            .expect("empty fft bins")
-                            .clone();
-                        bins.into_iter().map(|x| x / max.clone()).collect()
+                        if bins.iter().any(|bin| bin.as_number().is_nan()) {
+                            return Err(Error::new(ErrorSpec::NanInFft));
+                        }
+                        let max = bins.iter().map(Variable::as_number).fold(0.0, f64::max);
+                        bins.into_iter()
+                            .map(|x| x / Variable::Number(max))
+                            .collect()
                     }),
                 ]);
                 vec![fft_result; buffer_size + window_size]
             }
             Window(..) => vec![Variable::Array(x); buffer_size + window_size],
-            Debug(..) => return Err(Error::new(ErrorSpec::DebugVar(x[0].clone()))),
-            Print(..) => return Err(Error::new(ErrorSpec::DebugString(x[0].clone()))),
+            Debug(..) | Print(..) => {
+                self.tracer.record(expression, &x, time, sample_rate);
+                x
+            }
+            Assert(..) => {
+                if let Some(i) = x.iter().position(|x| !x.all_true()) {
+                    return Err(Error::new(ErrorSpec::AssertionFailed(
+                        x[i].clone(),
+                        time + i as f64 / sample_rate,
+                    ))
+                    .on_line(expression.1.clone()));
+                }
+                x
+            }
             Concatenate(..) => x
                 .into_par_iter()
                 .zip(
@@ -565,6 +522,36 @@ impl Builder {
                 .zip(y.expect("unable to unwrap y in find").into_par_iter())
                 .map(|(x, y)| x.find(y))
                 .collect(),
+            Tangent(..) => x.into_par_iter().map(|x| x.tan()).collect(),
+            ArcSine(..) => x.into_par_iter().map(|x| x.asin()).collect(),
+            ArcCosine(..) => x.into_par_iter().map(|x| x.acos()).collect(),
+            ArcTangent(..) => x.into_par_iter().map(|x| x.atan()).collect(),
+            ArcTangent2(..) => x
+                .into_par_iter()
+                .zip(y.expect("unable to unwrap y in atan2").into_par_iter())
+                .map(|(x, y)| x.atan2(y))
+                .collect(),
+            Exponential(..) => x.into_par_iter().map(|x| x.exp()).collect(),
+            SquareRoot(..) => x.into_par_iter().map(|x| x.sqrt()).collect(),
+            Sign(..) => x.into_par_iter().map(|x| x.sign()).collect(),
+            Round(..) => x.into_par_iter().map(|x| x.round()).collect(),
+            Fraction(..) => x.into_par_iter().map(|x| x.fract()).collect(),
+            Clamp(..) => x
+                .into_par_iter()
+                .zip(y.expect("failed to unwrap y in clamp").into_par_iter())
+                .zip(z.expect("failed to unwrap z in clamp").into_par_iter())
+                .map(|((x, y), z)| x.clamp(y, z))
+                .collect(),
+            Lerp(..) => x
+                .into_par_iter()
+                .zip(y.expect("failed to unwrap y in lerp").into_par_iter())
+                .zip(z.expect("failed to unwrap z in lerp").into_par_iter())
+                .map(|((x, y), z)| x.lerp(y, z))
+                .collect(),
+            DecibelsToGain(..) => x.into_par_iter().map(|x| x.db_to_gain()).collect(),
+            GainToDecibels(..) => x.into_par_iter().map(|x| x.gain_to_db()).collect(),
+            MidiToFrequency(..) => x.into_par_iter().map(|x| x.midi_to_freq()).collect(),
+            FrequencyToMidi(..) => x.into_par_iter().map(|x| x.freq_to_midi()).collect(),
         })
     }
 
@@ -578,21 +565,25 @@ impl Builder {
         buffer_size: usize,
         sample_rate: f64,
     ) -> SonnyResult<Variables> {
-        Ok(if let Some(chain) = self.find_chain(name) {
-            match chain.links {
-                ChainLinks::Generic(ref expressions) => {
-                    let mut results: Vec<Variables> = Vec::new();
-                    for (_i, expression) in expressions.iter().enumerate() {
-                        let mut results_collector: Vec<Variables> = Vec::new();
-                        {
-                            // Create the args to be passed to the evaluate_expression() call
-                            let mut these_args: Vec<&Variables> = Vec::new();
-                            // Add all previous arg results of this chain reversed
-                            these_args.extend(results.iter().rev());
-                            // Add the args coming into this chain
-                            these_args.extend(args);
+        let chain = self
+            .find_chain(name)
+            .ok_or_else(|| Error::new(CantFindChain(name.clone())))?;
+        let _timer = self.profiler.chain(chain, buffer_size + window_size);
+        let result = match chain.links {
+            ChainLinks::Generic(ref expressions) => {
+                let mut results: Vec<Variables> = Vec::new();
+                for expression in expressions.iter() {
+                    let mut results_collector: Vec<Variables> = Vec::new();
+                    {
+                        // Create the args to be passed to the evaluate_expression() call
+                        let mut these_args: Vec<&Variables> = Vec::new();
+                        // Add all previous arg results of this chain reversed
+                        these_args.extend(results.iter().rev());
+                        // Add the args coming into this chain
+                        these_args.extend(args);
 
-                            results_collector.push(self.evaluate_expression(
+                        results_collector.push(
+                            self.evaluate_expression(
                                 expression,
                                 name,
                                 &these_args,
@@ -600,32 +591,32 @@ impl Builder {
                                 window_size,
                                 buffer_size,
                                 sample_rate,
-                            )?);
-                        }
-                        results.extend(results_collector.into_iter());
+                            )
+                            .map_err(|error| error.or_on_line(&chain.location))?,
+                        );
                     }
-                    results
-                        .into_iter()
-                        .last()
-                        .expect("generic chain gave no last result")
+                    results.extend(results_collector);
                 }
-                ChainLinks::OnlyNotes(..) => (0..(buffer_size + window_size))
-                    .collect::<Vec<usize>>()
-                    .into_par_iter()
-                    .map(|i| time + i as f64 / sample_rate)
-                    .map(|t| {
-                        Variable::Array(
-                            chain
-                                .links
-                                .find_note(t, 0.0, &self)
-                                .map(|n| n.pitches.into_iter().map(Variable::Number).collect())
-                                .unwrap_or_else(Vec::new),
-                        )
-                    })
-                    .collect(),
+                results
+                    .into_iter()
+                    .last()
+                    .expect("generic chain gave no last result")
             }
-        } else {
-            panic!("No function named '{}'", name);
-        })
+            ChainLinks::OnlyNotes(..) => (0..(buffer_size + window_size))
+                .collect::<Vec<usize>>()
+                .into_par_iter()
+                .map(|i| time + i as f64 / sample_rate)
+                .map(|t| {
+                    Ok(Variable::Array(
+                        self.find_note(&chain.name, t, 0.0)?
+                            .map(|n| n.pitches.into_iter().map(Variable::Number).collect())
+                            .unwrap_or_else(Vec::new),
+                    ))
+                })
+                .collect::<SonnyResult<Variables>>()?,
+        };
+        self.tracer
+            .record_chain(&chain.name, &result, time, sample_rate);
+        Ok(result)
     }
 }
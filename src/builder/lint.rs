@@ -0,0 +1,114 @@
+// This module contains checks for code that compiles but is probably
+// not what was meant, like chains that are never used
+
+use std::collections::HashSet;
+
+use builder::*;
+
+impl Builder {
+    // Find the warnings in the file that was parsed first. Chains in
+    // included files are only checked where they affect that file.
+    pub fn lint(&self) -> Vec<Error> {
+        let main_file = match self.files.first() {
+            Some(file) => file.as_str(),
+            None => return Vec::new(),
+        };
+        let mut warnings = Vec::new();
+        self.lint_uses(main_file, &mut warnings);
+        if let Some(out) = self.out_chain() {
+            self.lint_unused(main_file, out, &mut warnings);
+            let dependencies = self.dependencies(out);
+            if !dependencies
+                .iter()
+                .any(|name| matches!(self.chains[name].links, ChainLinks::OnlyNotes(..)))
+            {
+                let chain = &self.chains[out];
+                warnings.push(
+                    Error::new(OutChainWithoutNotes(chain.name.clone()))
+                        .on_line(chain.location.clone()),
+                );
+            }
+        }
+        warnings.sort_by_key(|warning| {
+            warning
+                .location
+                .as_ref()
+                .map(|loc| (loc.path.clone(), loc.line, loc.column))
+        });
+        warnings
+    }
+    // Find uses that do not refer to any chains, and chains whose
+    // names refer to chains brought into scope by a use instead
+    fn lint_uses(&self, main_file: &str, warnings: &mut Vec<Error>) {
+        for (name_in_scope, loc) in &self.uses {
+            if *loc.path != *main_file {
+                continue;
+            }
+            let prefix = format!("{}::", name_in_scope.name);
+            if name_in_scope.contents {
+                if !self.chains.keys().any(|name| match name {
+                    ChainName::Scoped(name) => name.starts_with(&prefix),
+                    ChainName::Anonymous(..) => false,
+                }) {
+                    warnings.push(
+                        Error::new(UnresolvedUse(format!("{}*", prefix))).on_line(loc.clone()),
+                    );
+                }
+            } else if !self
+                .chains
+                .contains_key(&ChainName::Scoped(name_in_scope.name.clone()))
+            {
+                warnings.push(
+                    Error::new(UnresolvedUse(name_in_scope.name.clone())).on_line(loc.clone()),
+                );
+            }
+            // Uses are searched before the file's own chains
+            for chain in self.chains.values() {
+                let short_name = match chain.name {
+                    ChainName::Scoped(ref name) if *chain.location.path == *main_file => {
+                        match name.rfind("::") {
+                            Some(i) => &name[i + 2..],
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                let other = if name_in_scope.contents {
+                    ChainName::Scoped(format!("{}{}", prefix, short_name))
+                } else if name_in_scope.name.split("::").last() == Some(short_name) {
+                    ChainName::Scoped(name_in_scope.name.clone())
+                } else {
+                    continue;
+                };
+                if other != chain.name && self.chains.contains_key(&other) {
+                    warnings.push(
+                        Error::new(ShadowedChain(chain.name.clone(), other))
+                            .on_line(chain.location.clone())
+                            .with_label(loc.clone(), "brought into scope here"),
+                    );
+                }
+            }
+        }
+    }
+    // Find the named chains in the main file that neither the out chain
+    // nor any tests use
+    fn lint_unused(&self, main_file: &str, out: &ChainName, warnings: &mut Vec<Error>) {
+        let used: HashSet<ChainName> = Some(out)
+            .into_iter()
+            .chain(self.tests.iter().map(|test| &test.chain))
+            .flat_map(|name| self.dependencies(name))
+            .collect();
+        for chain in self.chains.values() {
+            // Each file's top-level chain is not named by the user
+            let inner = match chain.name {
+                ChainName::Scoped(ref name) => name.contains("::"),
+                ChainName::Anonymous(..) => false,
+            };
+            if inner && *chain.location.path == *main_file && !used.contains(&chain.name) {
+                warnings.push(
+                    Error::new(UnusedChain(chain.name.clone())).on_line(chain.location.clone()),
+                );
+            }
+        }
+    }
+}
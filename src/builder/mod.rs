@@ -1,19 +1,28 @@
+pub mod arity;
+pub mod dependencies;
 pub mod evaluate;
+pub mod lint;
+pub mod profile;
+pub mod rhythm;
+pub mod shape;
+pub mod test;
+pub mod trace;
+pub mod transform;
 pub mod variable;
 
-use std::{collections::HashMap, f64, fmt};
+use std::{collections::HashMap, f64, fmt, sync::Arc};
 
 use error::{ErrorSpec::*, *};
 use lexer::CodeLocation;
 
-use self::variable::*;
+use self::{profile::*, trace::*, transform::*, variable::*};
 
 // Different types of operands
 #[derive(Debug, Clone)]
 pub enum Operand {
     Var(Variable),
-    Id(ChainName),
-    Properties(ChainName),
+    Id(ChainName, CodeLocation),
+    Properties(ChainName, CodeLocation),
     BackLink(usize, CodeLocation),
     Time,
     WindowSize,
@@ -56,13 +65,31 @@ pub enum Operation {
     Index(Operand, Operand),
     SubArray(Operand, Operand, Operand),
     Average(Operand),
+    #[allow(clippy::upper_case_acronyms)]
     FFT(Operand),
     Window(Operand),
     Debug(Operand),
     Print(Operand),
+    Assert(Operand),
     Concatenate(Operand, Operand),
     Length(Operand),
     Find(Operand, Operand),
+    Tangent(Operand),
+    ArcSine(Operand),
+    ArcCosine(Operand),
+    ArcTangent(Operand),
+    ArcTangent2(Operand, Operand),
+    Exponential(Operand),
+    SquareRoot(Operand),
+    Sign(Operand),
+    Round(Operand),
+    Fraction(Operand),
+    Clamp(Operand, Operand, Operand),
+    Lerp(Operand, Operand, Operand),
+    DecibelsToGain(Operand),
+    GainToDecibels(Operand),
+    MidiToFrequency(Operand),
+    FrequencyToMidi(Operand),
 }
 
 impl Operation {
@@ -71,11 +98,34 @@ impl Operation {
         use self::Operation::*;
         match *self {
             // One argument operations
-            Negate(ref a) | Sine(ref a) | Cosine(ref a) | Ceiling(ref a) | Floor(ref a)
-            | AbsoluteValue(ref a) | Logarithm(ref a) | Operand(ref a) | Average(ref a)
-            | FFT(ref a) | Window(ref a) | Debug(ref a) | Print(ref a) | Length(ref a) => {
-                (a, None, None)
-            }
+            Negate(ref a)
+            | Sine(ref a)
+            | Cosine(ref a)
+            | Ceiling(ref a)
+            | Floor(ref a)
+            | AbsoluteValue(ref a)
+            | Logarithm(ref a)
+            | Operand(ref a)
+            | Average(ref a)
+            | FFT(ref a)
+            | Window(ref a)
+            | Debug(ref a)
+            | Print(ref a)
+            | Assert(ref a)
+            | Length(ref a)
+            | Tangent(ref a)
+            | ArcSine(ref a)
+            | ArcCosine(ref a)
+            | ArcTangent(ref a)
+            | Exponential(ref a)
+            | SquareRoot(ref a)
+            | Sign(ref a)
+            | Round(ref a)
+            | Fraction(ref a)
+            | DecibelsToGain(ref a)
+            | GainToDecibels(ref a)
+            | MidiToFrequency(ref a)
+            | FrequencyToMidi(ref a) => (a, None, None),
             // Two argument operations
             Add(ref a, ref b)
             | Subtract(ref a, ref b)
@@ -95,9 +145,13 @@ impl Operation {
             | And(ref a, ref b)
             | Index(ref a, ref b)
             | Concatenate(ref a, ref b)
-            | Find(ref a, ref b) => (a, Some(b), None),
+            | Find(ref a, ref b)
+            | ArcTangent2(ref a, ref b) => (a, Some(b), None),
             // Three arguement operations
-            Ternary(ref a, ref b, ref c) | SubArray(ref a, ref b, ref c) => (a, Some(b), Some(c)),
+            Ternary(ref a, ref b, ref c)
+            | SubArray(ref a, ref b, ref c)
+            | Clamp(ref a, ref b, ref c)
+            | Lerp(ref a, ref b, ref c) => (a, Some(b), Some(c)),
         }
     }
 }
@@ -120,17 +174,17 @@ impl Period {
     }
 }
 
-// A pitch with a period
+// A pitch with a period and a velocity
 #[derive(Debug, Clone)]
 pub struct Note {
     pub pitches: Vec<f64>,
     pub period: Period,
+    pub velocity: f64,
 }
 
-// Expression may have more in the future, but for now,
-// they just hold a top-lvel operation
+// An expression holds a top-level operation and the code it was parsed from
 #[derive(Debug, Clone)]
-pub struct Expression(pub Operation);
+pub struct Expression(pub Operation, pub CodeLocation);
 
 // A name that a chain can have
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -177,6 +231,22 @@ pub struct Chain {
     pub name: ChainName,
     pub links: ChainLinks,
     pub play: bool,
+    // Transforms to apply to the chain's notes and the lines they
+    // were declared on
+    pub transforms: Vec<(NoteTransform, CodeLocation)>,
+    // Where the chain was declared
+    pub location: CodeLocation,
+}
+
+// A test declared with "test", which passes if its chain can be
+// evaluated at each of its times without any assertions failing
+#[derive(Debug, Clone)]
+pub struct Test {
+    pub name: String,
+    pub chain: ChainName,
+    pub times: Vec<f64>,
+    // Where the test was declared
+    pub location: CodeLocation,
 }
 
 // A name that is in scope with a marker telling whether it is the
@@ -191,7 +261,7 @@ pub struct NameInScope {
 // The main builder with manages the initialization, contruction,
 // and finalization of chains. Most of its methods are called by
 // the parser.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Builder {
     // The chains that are currently being built and are not yet finalized
     curr_chains: Vec<Chain>,
@@ -212,6 +282,26 @@ pub struct Builder {
     pub end_time: f64,
     // The optional line on which the out chain was declared
     pub out_declared: Option<CodeLocation>,
+    // The errors and warnings found while building
+    pub diagnostics: Vec<Error>,
+    // Every place a chain is referred to by name, and the chain
+    pub references: Vec<(CodeLocation, ChainName)>,
+    // The paths of all of the files that have been parsed
+    pub files: Vec<String>,
+    // The names brought into scope with "use" and where
+    pub uses: Vec<(NameInScope, CodeLocation)>,
+    // The tests declared in every file
+    pub tests: Vec<Test>,
+    // Records traced values while chains are evaluated
+    pub tracer: Arc<Tracer>,
+    // Measures the time spent evaluating chains
+    pub profiler: Arc<Profiler>,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
 }
 
 impl Builder {
@@ -226,6 +316,13 @@ impl Builder {
             tempo: 120.0,
             end_time: 1.0,
             out_declared: None,
+            diagnostics: Vec::new(),
+            references: Vec::new(),
+            files: Vec::new(),
+            uses: Vec::new(),
+            tests: Vec::new(),
+            tracer: Arc::new(Tracer::default()),
+            profiler: Arc::new(Profiler::default()),
         }
     }
     // Initializes a new chain
@@ -237,7 +334,7 @@ impl Builder {
         // Figure out what it should be named
         let return_name = if let Some(cn) = chain_name {
             if self.anon_chain_depth > 0 {
-                return Err(Error::new(NamedChainInAnonChain(cn)).on_line(line));
+                return Err(Error::new(NamedChainInAnonChain(cn)).on_line(line.clone()));
             }
             ChainName::Scoped({
                 let final_name = if !self.curr_chains.is_empty() && !self.names_in_scope.is_empty()
@@ -250,38 +347,47 @@ impl Builder {
                 } else {
                     cn
                 };
-                self.names_in_scope.push(NameInScope {
-                    name: final_name.clone(),
-                    contents: true,
-                });
                 final_name
             })
         } else {
             ChainName::Anonymous({
                 self.next_anon_chain += 1;
-                self.anon_chain_depth += 1;
                 self.next_anon_chain - 1
             })
         };
         // Make sure another chain does not already have that name
-        if self.find_chain(&return_name).is_some() {
-            return Err(Error::new(ChainRedeclaration(return_name)));
+        if let Some(chain) = self.find_chain(&return_name) {
+            return Err(Error::new(ChainRedeclaration(return_name))
+                .on_line(line)
+                .with_label(chain.location.clone(), "previously declared here"));
+        }
+        // Put the chain's name in scope
+        match return_name {
+            ChainName::Scoped(ref name) => self.names_in_scope.push(NameInScope {
+                name: name.clone(),
+                contents: true,
+            }),
+            ChainName::Anonymous(..) => self.anon_chain_depth += 1,
         }
         // Push the chain onto the current chains
         self.curr_chains.push(Chain {
             name: return_name.clone(),
             links: ChainLinks::Generic(Vec::new()),
             play: false,
+            transforms: Vec::new(),
+            location: line,
         });
         Ok(return_name)
     }
     // Cleans up a chain's state when its construction is finished
-    pub fn finalize_chain(&mut self) {
+    pub fn finalize_chain(&mut self) -> SonnyResult<()> {
         let mut chain = self.curr_chains.pop().expect("No chain to finalize");
         // Turn chain into a Notes chain if necessary
         let mut convert = true;
         let mut only_notes: Vec<NotesOrId> = Vec::new();
         let mut curr_time = 0.0;
+        // A chain that is used but could not be found
+        let mut missing = None;
         if let ChainLinks::Generic(ref expressions) = chain.links {
             for operation in expressions.iter().map(|expr| &expr.0) {
                 match operation {
@@ -294,13 +400,14 @@ impl Builder {
                                     start: curr_time,
                                     end: curr_time + note.period.duration(),
                                 },
+                                velocity: note.velocity,
                             });
                             curr_time += note.period.duration();
                         }
                         only_notes.push(NotesOrId::Notes(new_notes));
                     }
-                    Operation::Operand(Operand::Id(ref notes_chain_name)) => {
-                        if let Some(ref notes_chain) = self.find_chain(notes_chain_name) {
+                    Operation::Operand(Operand::Id(ref notes_chain_name, _)) => {
+                        if let Some(notes_chain) = self.find_chain(notes_chain_name) {
                             if let ChainLinks::OnlyNotes(ref _notes_or_ids, period) =
                                 notes_chain.links
                             {
@@ -311,7 +418,9 @@ impl Builder {
                                 break;
                             }
                         } else {
-                            panic!("Unable to find '{}'", notes_chain_name);
+                            missing = Some(notes_chain_name.clone());
+                            convert = false;
+                            break;
                         }
                     }
                     _ => {
@@ -323,6 +432,14 @@ impl Builder {
         }
         // Conver the chain's links into OnlyNotes if necessary
         if convert {
+            // Transformed notes are resolved into a single list of notes
+            if !chain.transforms.is_empty() {
+                let mut notes = self.flatten_notes(&only_notes, 0.0);
+                for (transform, _) in &chain.transforms {
+                    transform.apply(&mut notes, curr_time);
+                }
+                only_notes = vec![NotesOrId::Notes(notes)];
+            }
             chain.links = ChainLinks::OnlyNotes(
                 only_notes,
                 Period {
@@ -331,6 +448,22 @@ impl Builder {
                 },
             );
         }
+        // Generic chains cannot be transformed, but they are still inserted
+        // so that chains which use them can be parsed
+        let result = if let Some(name) = missing {
+            Err(Error::new(CantFindChain(name)).on_line(chain.location.clone()))
+        } else if let (ChainLinks::Generic(..), Some((_, loc))) =
+            (&chain.links, chain.transforms.first())
+        {
+            Err(Error::new(TransformOfGenericChain(chain.name.clone())).on_line(loc.clone()))
+        } else {
+            Ok(())
+        };
+        self.end_chain(chain);
+        result
+    }
+    // Takes a chain's name out of scope and inserts it into the finalized chains
+    fn end_chain(&mut self, chain: Chain) {
         // Do something depending on what kind of hame the chain has
         if let ChainName::Anonymous(..) = chain.name {
             self.anon_chain_depth -= 1;
@@ -340,6 +473,22 @@ impl Builder {
         // Insert the chain
         self.chains.insert(chain.name.clone(), chain);
     }
+    // The number of chains that are currently being built
+    pub fn chain_depth(&self) -> usize {
+        self.curr_chains.len()
+    }
+    // Abandons the chains that are being built until only the given number
+    // are left. This is used to recover after a chain fails to parse. The
+    // abandoned chains are kept without any links so that chains which use
+    // them can still be parsed.
+    pub fn discard_chains(&mut self, depth: usize) {
+        while self.curr_chains.len() > depth {
+            let mut chain = self.curr_chains.pop().expect("No chain to discard");
+            chain.links = ChainLinks::Generic(Vec::new());
+            chain.play = false;
+            self.end_chain(chain);
+        }
+    }
     // Finds a chain with the given name. In the chains map, chains are named
     // with their full scoped names. This function finds a chain with only
     // the last part of the scoped name, given that it would actually be in scope.
@@ -356,8 +505,7 @@ impl Builder {
                         // the user to call "gen::sine" by simply writing "sine".
                         if name_in_scope.contents {
                             let test_name = format!("{}::{}", name_in_scope.name, name_str);
-                            if let Some(ref chain) = self.chains.get(&ChainName::Scoped(test_name))
-                            {
+                            if let Some(chain) = self.chains.get(&ChainName::Scoped(test_name)) {
                                 return Some(chain);
                             }
                         // Check if the name is valid if a name in scope ends with it,
@@ -382,6 +530,14 @@ impl Builder {
             panic!("No current chain to set to play");
         }
     }
+    // Adds a new note transform to the most recently created chain
+    pub fn new_transform(&mut self, transform: NoteTransform, line: CodeLocation) {
+        if let Some(chain) = self.curr_chains.last_mut() {
+            chain.transforms.push((transform, line));
+        } else {
+            panic!("No current chain to add transforms to");
+        }
+    }
     // Adds a new expression to the most recently created chain
     pub fn new_expression(&mut self, expression: Expression) {
         if let Some(chain) = self.curr_chains.last_mut() {
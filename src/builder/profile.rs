@@ -0,0 +1,251 @@
+// This module contains the profiler, which measures how much time is spent
+// in each chain and each kind of operation while chains are evaluated
+
+use std::{
+    collections::HashMap,
+    mem::{discriminant, Discriminant},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use builder::*;
+
+// The time and samples spent in a chain
+#[derive(Debug, Clone, Default)]
+pub struct ChainProfile {
+    pub name: String,
+    pub calls: usize,
+    pub samples: usize,
+    // The time spent in the chain, including the chains it calls
+    pub total: Duration,
+    // The time spent in the chain, not including the chains it calls
+    pub own: Duration,
+}
+
+// The time and samples spent in one kind of operation. The time does not
+// include evaluating the operation's operands.
+#[derive(Debug, Clone, Default)]
+pub struct OperationProfile {
+    pub name: String,
+    pub count: usize,
+    pub samples: usize,
+    pub time: Duration,
+}
+
+// A chain that is being evaluated
+#[derive(Debug)]
+struct Frame {
+    name: String,
+    // The time spent in chains that this chain called
+    children: Duration,
+    // The time spent in this chain's operations
+    operations: Duration,
+}
+
+#[derive(Debug, Default)]
+struct ProfileState {
+    // The chains being evaluated, with the innermost one last
+    stack: Vec<Frame>,
+    chains: HashMap<String, ChainProfile>,
+    operations: HashMap<Discriminant<Operation>, OperationProfile>,
+    // The time spent in each stack of chains and operations, not
+    // including the time spent in the frames above it
+    folded: HashMap<String, Duration>,
+}
+
+// Measures time spent evaluating chains. Nothing is measured unless the
+// profiler is enabled. The profiler is shared by copies of a builder.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    enabled: AtomicBool,
+    state: Mutex<ProfileState>,
+}
+
+// Measures a chain or operation until it is dropped, so that time is
+// recorded even if evaluation fails
+pub struct ProfileTimer<'a> {
+    profiler: &'a Profiler,
+    // The operation being measured, or none for a chain
+    operation: Option<(Discriminant<Operation>, &'a Operation)>,
+    samples: usize,
+    start: Instant,
+}
+
+impl Profiler {
+    // Start measuring, and forget anything that was measured
+    pub fn enable(&self) {
+        *self.state.lock().expect("Profiler was poisoned") = ProfileState::default();
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+    // Start measuring a chain that is evaluated for some samples
+    pub fn chain(&self, chain: &Chain, samples: usize) -> Option<ProfileTimer<'_>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let name = match chain.name {
+            ChainName::Scoped(ref name) => name.clone(),
+            ChainName::Anonymous(_) => format!("|{}|", chain.location),
+        };
+        let start = Instant::now();
+        self.state
+            .lock()
+            .expect("Profiler was poisoned")
+            .stack
+            .push(Frame {
+                name,
+                children: Duration::default(),
+                operations: Duration::default(),
+            });
+        Some(ProfileTimer {
+            profiler: self,
+            operation: None,
+            samples,
+            start,
+        })
+    }
+    // Start measuring an operation that is applied to some samples
+    pub fn operation<'a>(
+        &'a self,
+        operation: &'a Operation,
+        samples: usize,
+    ) -> Option<ProfileTimer<'a>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        Some(ProfileTimer {
+            profiler: self,
+            operation: Some((discriminant(operation), operation)),
+            samples,
+            start: Instant::now(),
+        })
+    }
+    // The chains that were measured, with the slowest first
+    pub fn chains(&self) -> Vec<ChainProfile> {
+        let state = self.state.lock().expect("Profiler was poisoned");
+        let mut chains: Vec<ChainProfile> = state.chains.values().cloned().collect();
+        chains.sort_by(|a, b| b.own.cmp(&a.own).then_with(|| a.name.cmp(&b.name)));
+        chains
+    }
+    // The kinds of operations that were measured, with the slowest first
+    pub fn operations(&self) -> Vec<OperationProfile> {
+        let state = self.state.lock().expect("Profiler was poisoned");
+        let mut operations: Vec<OperationProfile> = state.operations.values().cloned().collect();
+        operations.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)));
+        operations
+    }
+    // A report of the chains and operations that were measured as tables,
+    // with the slowest first
+    pub fn report(&self) -> String {
+        let chains = self.chains();
+        let operations = self.operations();
+        let total: Duration = chains.iter().map(|chain| chain.own).sum();
+        let millis = |time: Duration| format!("{:.2} ms", time.as_secs_f64() * 1000.0);
+        let percent = |time: Duration| {
+            if total > Duration::default() {
+                format!("{:.1}%", time.as_secs_f64() / total.as_secs_f64() * 100.0)
+            } else {
+                "0.0%".to_string()
+            }
+        };
+        let mut report = format!(
+            "{:<32} {:>8} {:>10} {:>12} {:>12} {:>7}\n",
+            "chain", "calls", "samples", "total", "self", "self %"
+        );
+        for chain in chains {
+            report.push_str(&format!(
+                "{:<32} {:>8} {:>10} {:>12} {:>12} {:>7}\n",
+                chain.name,
+                chain.calls,
+                chain.samples,
+                millis(chain.total),
+                millis(chain.own),
+                percent(chain.own)
+            ));
+        }
+        report.push_str(&format!(
+            "\n{:<32} {:>8} {:>10} {:>12} {:>7}\n",
+            "operation", "count", "samples", "time", "time %"
+        ));
+        for operation in operations {
+            report.push_str(&format!(
+                "{:<32} {:>8} {:>10} {:>12} {:>7}\n",
+                operation.name,
+                operation.count,
+                operation.samples,
+                millis(operation.time),
+                percent(operation.time)
+            ));
+        }
+        report
+    }
+    // The measured stacks in the folded format that flamegraph tools read,
+    // with one stack and its time in microseconds on each line
+    pub fn folded(&self) -> String {
+        let state = self.state.lock().expect("Profiler was poisoned");
+        let mut stacks: Vec<(&String, &Duration)> = state.folded.iter().collect();
+        stacks.sort();
+        stacks
+            .into_iter()
+            .map(|(stack, time)| format!("{} {}\n", stack, time.as_micros()))
+            .collect()
+    }
+}
+
+impl<'a> Drop for ProfileTimer<'a> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let mut state = self.profiler.state.lock().expect("Profiler was poisoned");
+        let state = &mut *state;
+        let path = state
+            .stack
+            .iter()
+            .map(|frame| frame.name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        if let Some((kind, operation)) = self.operation {
+            let profile = state.operations.entry(kind).or_insert_with(|| {
+                // The name of the operation is its variant's name
+                let name = format!("{:?}", operation);
+                OperationProfile {
+                    name: name.split('(').next().unwrap_or_default().to_string(),
+                    ..OperationProfile::default()
+                }
+            });
+            profile.count += 1;
+            profile.samples += self.samples;
+            profile.time += elapsed;
+            let stack = format!("{};{}", path, profile.name);
+            *state.folded.entry(stack).or_default() += elapsed;
+            if let Some(frame) = state.stack.last_mut() {
+                frame.operations += elapsed;
+            }
+            return;
+        }
+        let frame = state.stack.pop().expect("Profiler stack is empty");
+        let own = elapsed - frame.children.min(elapsed);
+        *state.folded.entry(path).or_default() += own - frame.operations.min(own);
+        let profile = state
+            .chains
+            .entry(frame.name.clone())
+            .or_insert_with(|| ChainProfile {
+                name: frame.name,
+                ..ChainProfile::default()
+            });
+        profile.calls += 1;
+        profile.samples += self.samples;
+        profile.own += own;
+        // Recursive calls are only counted once in the total
+        if !state.stack.iter().any(|outer| outer.name == profile.name) {
+            profile.total += elapsed;
+        }
+        if let Some(parent) = state.stack.last_mut() {
+            parent.children += elapsed;
+        }
+    }
+}
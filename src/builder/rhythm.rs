@@ -0,0 +1,90 @@
+// This module contains generators for rhythmic patterns of notes
+
+use builder::*;
+
+// A single step in a rhythmic pattern
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    // A note with the given velocity
+    Hit(f64),
+    // A step with no note
+    Rest,
+    // A step that extends the previous step
+    Hold,
+}
+
+// Distribute the given number of hits as evenly as possible over the
+// given number of steps. Returns None if there are more hits than steps.
+pub fn euclid(hits: usize, steps: usize) -> Option<Vec<Step>> {
+    if steps == 0 || hits > steps {
+        return None;
+    }
+    Some(
+        (0..steps)
+            .map(|i| {
+                if (i * hits) % steps < hits {
+                    Step::Hit(1.0)
+                } else {
+                    Step::Rest
+                }
+            })
+            .collect(),
+    )
+}
+
+// Parse a pattern string like "x..x..x." into steps. 'x' is a hit, 'o' is
+// a soft hit, '.' is a rest, and '_' holds the previous step. Spaces are
+// ignored. Returns None if the pattern is empty or has an invalid character.
+pub fn step_pattern(pattern: &str) -> Option<Vec<Step>> {
+    let mut steps = Vec::new();
+    for c in pattern.chars().filter(|c| !c.is_whitespace()) {
+        steps.push(match c {
+            'x' => Step::Hit(1.0),
+            'o' => Step::Hit(0.5),
+            '.' => Step::Rest,
+            '_' => Step::Hold,
+            _ => return None,
+        });
+    }
+    if steps.is_empty() {
+        None
+    } else {
+        Some(steps)
+    }
+}
+
+// Turn a list of steps into notes of the given pitches, with each step
+// having the given duration
+pub fn steps_to_notes(steps: &[Step], duration: f64, pitches: &[f64]) -> Vec<Note> {
+    let mut notes: Vec<Note> = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let period = Period {
+            start: i as f64 * duration,
+            end: (i + 1) as f64 * duration,
+        };
+        match *step {
+            Step::Hit(velocity) => notes.push(Note {
+                pitches: pitches.to_vec(),
+                period,
+                velocity,
+            }),
+            Step::Rest => notes.push(Note {
+                pitches: vec![0.0],
+                period,
+                velocity: 1.0,
+            }),
+            Step::Hold => {
+                if let Some(last) = notes.last_mut() {
+                    last.period.end = period.end;
+                } else {
+                    notes.push(Note {
+                        pitches: vec![0.0],
+                        period,
+                        velocity: 1.0,
+                    });
+                }
+            }
+        }
+    }
+    notes
+}
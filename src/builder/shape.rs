@@ -0,0 +1,436 @@
+// This module contains the inference of the shapes of the values that
+// chains produce, which is used to find numbers and arrays that are
+// mixed in ways that do not do what they look like they do
+
+use std::{collections::HashMap, fmt};
+
+use builder::{variable::Variable, *};
+
+// Whether a value is a number, an array, or something else
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Shape {
+    Number,
+    Bool,
+    Text,
+    // An array whose length is known, and the shape of each element
+    Tuple(Vec<Shape>),
+    // An array whose length is not known, like the pitches of a note
+    List(Box<Shape>),
+    // A value whose shape cannot be known before running
+    Unknown,
+}
+
+// Describe the shape for error messages
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Shape::Number => write!(f, "a number"),
+            Shape::Bool => write!(f, "a bool"),
+            Shape::Text => write!(f, "text"),
+            Shape::Tuple(ref shapes) if shapes.len() == 1 => write!(f, "an array of 1 element"),
+            Shape::Tuple(ref shapes) => write!(f, "an array of {} elements", shapes.len()),
+            Shape::List(ref shape) => write!(
+                f,
+                "an array of {}",
+                match **shape {
+                    Shape::Number => "numbers",
+                    Shape::Bool => "bools",
+                    Shape::Text => "text",
+                    Shape::Tuple(..) | Shape::List(..) => "arrays",
+                    Shape::Unknown => "values",
+                }
+            ),
+            Shape::Unknown => write!(f, "a value"),
+        }
+    }
+}
+
+impl Shape {
+    // The shape of each value in a properties array
+    pub fn properties() -> Shape {
+        Shape::Tuple(vec![
+            Shape::List(Box::new(Shape::Number)),
+            Shape::Number,
+            Shape::Number,
+            Shape::Number,
+            Shape::Number,
+        ])
+    }
+    fn is_array(&self) -> bool {
+        matches!(*self, Shape::Tuple(..) | Shape::List(..))
+    }
+    // The shape of a value that is either of two shapes
+    fn or(self, other: Shape) -> Shape {
+        if self == other {
+            self
+        } else {
+            Shape::Unknown
+        }
+    }
+    // The shape of any element of an array
+    fn element(&self) -> Shape {
+        match *self {
+            Shape::Tuple(ref shapes) => shapes
+                .iter()
+                .cloned()
+                .fold(None, |all: Option<Shape>, shape| {
+                    Some(all.map_or(shape.clone(), |all| all.or(shape)))
+                })
+                .unwrap_or(Shape::Unknown),
+            Shape::List(ref shape) => (**shape).clone(),
+            _ => Shape::Unknown,
+        }
+    }
+    // The shape that a function of numbers gives when it is applied to
+    // every number in a value
+    fn map_numbers(&self) -> Shape {
+        match *self {
+            Shape::Tuple(ref shapes) => {
+                Shape::Tuple(shapes.iter().map(Shape::map_numbers).collect())
+            }
+            Shape::List(ref shape) => Shape::List(Box::new(shape.map_numbers())),
+            Shape::Unknown => Shape::Unknown,
+            _ => Shape::Number,
+        }
+    }
+}
+
+// The chains whose shapes have been found, by the shapes of their arguments,
+// and the chains whose shapes are being found, which are used by themselves
+// if they are found again
+type Shapes = HashMap<(ChainName, Vec<Shape>), Option<Shape>>;
+
+impl Builder {
+    // Find the shape of the value a chain produces when it is evaluated
+    // with no arguments, like the out chain is. Each chain that it uses is
+    // checked with the shapes of the arguments it gets where it is used.
+    pub fn shape(&self, name: &ChainName) -> Result<Shape, Vec<Error>> {
+        let mut errors = Vec::new();
+        let shape = self.chain_shape(name, &[], &mut HashMap::new(), &mut errors);
+        if errors.is_empty() {
+            Ok(shape)
+        } else {
+            // Chains used with the same shapes in many places only
+            // need to be reported once
+            let mut reported = Vec::new();
+            errors.retain(|error: &Error| {
+                let key = (
+                    error.spec.to_string(),
+                    error.location.clone().map(|loc| loc.span),
+                );
+                if reported.contains(&key) {
+                    false
+                } else {
+                    reported.push(key);
+                    true
+                }
+            });
+            Err(errors)
+        }
+    }
+    // Check that the output of a chain is a number. Arrays of pitches
+    // are allowed, since chords play their first pitch.
+    pub fn check_output_shape(&self, name: &ChainName) -> Vec<Error> {
+        match self.shape(name) {
+            Ok(shape) => match shape {
+                Shape::Number | Shape::Bool | Shape::Unknown => Vec::new(),
+                Shape::List(ref element) if **element == Shape::Number => Vec::new(),
+                shape => match self.find_chain(name) {
+                    Some(chain) => vec![Error::new(OutputShape(chain.name.clone(), shape))
+                        .on_line(chain.location.clone())],
+                    None => vec![Error::new(CantFindChain(name.clone()))],
+                },
+            },
+            Err(errors) => errors,
+        }
+    }
+    fn chain_shape(
+        &self,
+        name: &ChainName,
+        args: &[Shape],
+        shapes: &mut Shapes,
+        errors: &mut Vec<Error>,
+    ) -> Shape {
+        let chain = match self.find_chain(name) {
+            Some(chain) => chain,
+            None => return Shape::Unknown,
+        };
+        let key = (chain.name.clone(), args.to_vec());
+        if let Some(shape) = shapes.get(&key) {
+            return shape.clone().unwrap_or(Shape::Unknown);
+        }
+        shapes.insert(key.clone(), None);
+        let shape = match chain.links {
+            ChainLinks::Generic(ref expressions) => {
+                let mut results: Vec<Shape> = Vec::new();
+                for expression in expressions {
+                    // Each link gets the links before it and then the arguments
+                    let these_args: Vec<Shape> =
+                        results.iter().rev().chain(args).cloned().collect();
+                    let shape = self.expression_shape(expression, &these_args, shapes, errors);
+                    results.push(shape);
+                }
+                results.pop().unwrap_or(Shape::Unknown)
+            }
+            ChainLinks::OnlyNotes(..) => Shape::List(Box::new(Shape::Number)),
+        };
+        shapes.insert(key, Some(shape.clone()));
+        shape
+    }
+    fn operand_shape(
+        &self,
+        operand: &Operand,
+        args: &[Shape],
+        shapes: &mut Shapes,
+        errors: &mut Vec<Error>,
+    ) -> Shape {
+        match *operand {
+            Operand::Var(Variable::Number(..)) => Shape::Number,
+            Operand::Var(Variable::Bool(..)) => Shape::Bool,
+            Operand::Var(Variable::Text(..)) => Shape::Text,
+            Operand::Var(Variable::Array(..)) => Shape::Unknown,
+            Operand::Id(ref name, _) => self.chain_shape(name, args, shapes, errors),
+            Operand::Properties(..) => Shape::properties(),
+            Operand::BackLink(num, _) => args.get(num - 1).cloned().unwrap_or(Shape::Unknown),
+            Operand::Time
+            | Operand::WindowSize
+            | Operand::BufferSize
+            | Operand::SampleRate
+            | Operand::WindowIndex => Shape::Number,
+            Operand::Notes(..) => Shape::List(Box::new(Shape::Number)),
+            Operand::Expression(ref expression) => {
+                self.expression_shape(expression, args, shapes, errors)
+            }
+            Operand::Array(ref expressions) => Shape::Tuple(
+                expressions
+                    .iter()
+                    .map(|expression| self.expression_shape(expression, args, shapes, errors))
+                    .collect(),
+            ),
+        }
+    }
+    fn expression_shape(
+        &self,
+        expression: &Expression,
+        args: &[Shape],
+        shapes: &mut Shapes,
+        errors: &mut Vec<Error>,
+    ) -> Shape {
+        use builder::Operation::*;
+        let Expression(ref operation, ref loc) = *expression;
+        let (a, b, c) = operation.operands();
+        let a = self.operand_shape(a, args, shapes, errors);
+        let b = b.map(|b| self.operand_shape(b, args, shapes, errors));
+        let c = c.map(|c| self.operand_shape(c, args, shapes, errors));
+        let b = || b.clone().expect("operation has no second operand");
+        let c = || c.clone().expect("operation has no third operand");
+        let mut error =
+            |spec: ErrorSpec| errors.push(Error::new(spec).on_line(loc.clone()).at_compile_time());
+        match *operation {
+            Add(..) => zip(a, b(), "+", &mut error),
+            Subtract(..) => zip(a, b(), "-", &mut error),
+            Multiply(..) => zip(a, b(), "*", &mut error),
+            Divide(..) => zip(a, b(), "/", &mut error),
+            Remainder(..) => zip(a, b(), "%", &mut error),
+            Power(..) => zip(a, b(), "^", &mut error),
+            Min(..) => zip(a, b(), "min", &mut error),
+            Max(..) => zip(a, b(), "max", &mut error),
+            And(..) => zip(a, b(), "&&", &mut error),
+            Or(..) => zip(a, b(), "||", &mut error),
+            ArcTangent2(..) => zip(a, b(), "atan2", &mut error),
+            Clamp(..) => {
+                let low = zip(a, b(), "clamp", &mut error);
+                zip(low, c(), "clamp", &mut error)
+            }
+            Lerp(..) => {
+                let difference = zip(b(), a.clone(), "lerp", &mut error);
+                let scaled = zip(difference, c(), "lerp", &mut error);
+                zip(a, scaled, "lerp", &mut error)
+            }
+            // Arrays are never less or greater than anything
+            LessThan(..) | GreaterThan(..) | LessThanOrEqual(..) | GreaterThanOrEqual(..) => {
+                let op = match *operation {
+                    LessThan(..) => "<",
+                    GreaterThan(..) => ">",
+                    LessThanOrEqual(..) => "<=",
+                    _ => ">=",
+                };
+                for shape in [a, b()] {
+                    if shape.is_array() {
+                        error(CompareArrays(op.to_string(), shape));
+                    }
+                }
+                Shape::Bool
+            }
+            Equal(..) | NotEqual(..) => Shape::Bool,
+            Negate(..) | Sine(..) | Cosine(..) | Floor(..) | Ceiling(..) | AbsoluteValue(..)
+            | Logarithm(..) | Tangent(..) | ArcSine(..) | ArcCosine(..) | ArcTangent(..)
+            | Exponential(..) | SquareRoot(..) | Sign(..) | Round(..) | Fraction(..)
+            | DecibelsToGain(..) | GainToDecibels(..) | MidiToFrequency(..)
+            | FrequencyToMidi(..) => {
+                if a == Shape::Text {
+                    error(MathOnText(function_name(operation)));
+                }
+                a.map_numbers()
+            }
+            Operand(..) | Debug(..) | Print(..) | Assert(..) => a,
+            Ternary(..) => b().or(c()),
+            Index(_, ref index) => match a {
+                Shape::Number | Shape::Bool => {
+                    error(IndexOfNumber(a.clone()));
+                    a
+                }
+                Shape::Tuple(ref shapes) => match constant(index) {
+                    Some(i) if i >= shapes.len() => {
+                        error(IndexOutOfBounds(i, shapes.len()));
+                        Shape::Unknown
+                    }
+                    Some(i) => shapes[i].clone(),
+                    None => a.element(),
+                },
+                Shape::Text => Shape::Text,
+                _ => a.element(),
+            },
+            SubArray(..) => match a {
+                Shape::Number | Shape::Bool => {
+                    error(IndexOfNumber(a.clone()));
+                    a
+                }
+                Shape::Tuple(..) => Shape::List(Box::new(a.element())),
+                _ => a,
+            },
+            Average(..) => match a {
+                Shape::Tuple(..) | Shape::List(..) => a.element().map_numbers(),
+                _ => a,
+            },
+            FFT(..) => {
+                if a.is_array() {
+                    error(FftOfArray);
+                }
+                Shape::Tuple(vec![
+                    Shape::List(Box::new(Shape::Number)),
+                    Shape::List(Box::new(Shape::Number)),
+                ])
+            }
+            Window(..) => Shape::List(Box::new(a)),
+            Concatenate(..) => match (a, b()) {
+                (Shape::Tuple(mut x), Shape::Tuple(y)) => {
+                    x.extend(y);
+                    Shape::Tuple(x)
+                }
+                (Shape::Tuple(mut x), y) if !y.is_array() && y != Shape::Unknown => {
+                    x.push(y);
+                    Shape::Tuple(x)
+                }
+                (Shape::Text, ref y) | (ref y, Shape::Text) if !y.is_array() => Shape::Text,
+                (x, Shape::Tuple(y)) if !x.is_array() && x != Shape::Unknown => {
+                    Shape::Tuple(Some(x).into_iter().chain(y).collect())
+                }
+                (x, y) if x.is_array() || y.is_array() => {
+                    let x = if x.is_array() { x.element() } else { x };
+                    let y = if y.is_array() { y.element() } else { y };
+                    Shape::List(Box::new(x.or(y)))
+                }
+                (Shape::Unknown, _) | (_, Shape::Unknown) => Shape::Unknown,
+                (x, y) => Shape::Tuple(vec![x, y]),
+            },
+            Length(..) | Find(..) => Shape::Number,
+        }
+    }
+}
+
+// The shape of the result of a function that is applied to every pair
+// of values in two values, where single values are used with every
+// element of an array
+fn zip<F>(a: Shape, b: Shape, op: &str, error: &mut F) -> Shape
+where
+    F: FnMut(ErrorSpec),
+{
+    match (a, b) {
+        (Shape::Tuple(x), Shape::Tuple(y)) => {
+            if x.len() != y.len() {
+                error(ArrayLengthMismatch(op.to_string(), x.len(), y.len()));
+            }
+            Shape::Tuple(
+                x.into_iter()
+                    .zip(y)
+                    .map(|(x, y)| zip(x, y, op, error))
+                    .collect(),
+            )
+        }
+        (Shape::Tuple(x), Shape::List(y)) => Shape::Tuple(
+            x.into_iter()
+                .map(|x| zip(x, (*y).clone(), op, error))
+                .collect(),
+        ),
+        (Shape::List(x), Shape::Tuple(y)) => Shape::Tuple(
+            y.into_iter()
+                .map(|y| zip((*x).clone(), y, op, error))
+                .collect(),
+        ),
+        (Shape::List(x), Shape::List(y)) => Shape::List(Box::new(zip(*x, *y, op, error))),
+        (Shape::Tuple(x), y) => Shape::Tuple(
+            x.into_iter()
+                .map(|x| zip(x, y.clone(), op, error))
+                .collect(),
+        ),
+        (x, Shape::Tuple(y)) => Shape::Tuple(
+            y.into_iter()
+                .map(|y| zip(x.clone(), y, op, error))
+                .collect(),
+        ),
+        (Shape::List(x), y) => Shape::List(Box::new(zip(*x, y, op, error))),
+        (x, Shape::List(y)) => Shape::List(Box::new(zip(x, *y, op, error))),
+        // Text is not a number, so math with it gives NaN
+        (Shape::Text, _) | (_, Shape::Text) => {
+            error(MathOnText(op.to_string()));
+            Shape::Unknown
+        }
+        (Shape::Unknown, _) | (_, Shape::Unknown) => Shape::Unknown,
+        // The minimum and maximum of bools are bools
+        (Shape::Bool, Shape::Bool) if op == "min" || op == "max" || op == "&&" || op == "||" => {
+            Shape::Bool
+        }
+        _ => Shape::Number,
+    }
+}
+
+// The value of an index that is a whole number written in the code
+fn constant(operand: &Operand) -> Option<usize> {
+    match *operand {
+        Operand::Var(Variable::Number(x)) if x >= 0.0 && x.fract() == 0.0 => Some(x as usize),
+        Operand::Expression(ref expression) => match expression.0 {
+            Operation::Operand(ref operand) => constant(operand),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The name of the keyword or operator of an operation on a single value
+fn function_name(operation: &Operation) -> String {
+    use builder::Operation::*;
+    match *operation {
+        Negate(..) => "-",
+        Sine(..) => "sin",
+        Cosine(..) => "cos",
+        Floor(..) => "floor",
+        Ceiling(..) => "ceil",
+        AbsoluteValue(..) => "abs",
+        Logarithm(..) => "log",
+        Tangent(..) => "tan",
+        ArcSine(..) => "asin",
+        ArcCosine(..) => "acos",
+        ArcTangent(..) => "atan",
+        Exponential(..) => "exp",
+        SquareRoot(..) => "sqrt",
+        Sign(..) => "sign",
+        Round(..) => "round",
+        Fraction(..) => "fract",
+        DecibelsToGain(..) => "dbtoa",
+        GainToDecibels(..) => "atodb",
+        MidiToFrequency(..) => "mtof",
+        _ => "ftom",
+    }
+    .to_string()
+}
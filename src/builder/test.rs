@@ -0,0 +1,29 @@
+// This module contains the running of tests declared with "test"
+
+use builder::*;
+
+impl Builder {
+    // The tests declared in a file, in the order they were declared. Files
+    // are told apart by their paths, since included files in different
+    // folders can have the same name.
+    pub fn tests_in(&self, path: &str) -> Vec<&Test> {
+        self.tests
+            .iter()
+            .filter(|test| *test.location.path == *path)
+            .collect()
+    }
+    // Evaluate a test's chain at each of its times. The errors from the
+    // times at which it fails, like failed assertions, are returned.
+    pub fn run_test(&self, test: &Test, sample_rate: f64) -> Vec<Error> {
+        test.times
+            .iter()
+            .filter_map(|&time| {
+                self.tracer.restart();
+                self.evaluate_chain(&test.chain, &[], time, 1, 0, sample_rate)
+                    .err()
+                    // Failures are reported like errors in the code
+                    .map(Error::at_compile_time)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,166 @@
+// This module contains the tracer, which records the values of "debug"
+// and "print" expressions and of a traced chain while rendering goes on
+
+use std::{collections::HashMap, sync::Mutex};
+
+use builder::{variable::*, *};
+
+// When the values of "debug" and "print" expressions are recorded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceMode {
+    // Once every so many seconds
+    Every(f64),
+    // Whenever the value changes
+    OnChange,
+}
+
+impl Default for TraceMode {
+    fn default() -> TraceMode {
+        TraceMode::Every(0.25)
+    }
+}
+
+// The last value that a "debug" or "print" expression had
+#[derive(Debug)]
+struct LastValue {
+    // The time of the last sample that was seen
+    seen: f64,
+    // The time and value of the last sample that was recorded
+    recorded: f64,
+    value: Variable,
+}
+
+#[derive(Debug, Default)]
+struct TraceState {
+    mode: TraceMode,
+    // The recorded values of "debug" and "print" expressions
+    messages: Vec<Error>,
+    // The last values of "debug" and "print" expressions by where they are
+    last: HashMap<(String, usize), LastValue>,
+    // The chain whose every sample is recorded, and the samples
+    chain: Option<ChainName>,
+    samples: Vec<(f64, Variable)>,
+}
+
+// Records values while chains are evaluated. The tracer is shared by
+// copies of a builder.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    state: Mutex<TraceState>,
+}
+
+impl Tracer {
+    // Set when values are recorded and which chain is traced, and forget
+    // anything that was recorded
+    pub fn configure(&self, mode: TraceMode, chain: Option<ChainName>) {
+        *self.state.lock().expect("Tracer was poisoned") = TraceState {
+            mode,
+            chain,
+            ..TraceState::default()
+        };
+    }
+    // Forget the last values that were seen, so that values at earlier
+    // times are recorded again. This is used when chains are evaluated
+    // at times out of order, like in the REPL.
+    pub fn restart(&self) {
+        self.state.lock().expect("Tracer was poisoned").last.clear();
+    }
+    // Record the values of a "debug" or "print" expression in a window
+    // that starts at a time
+    pub fn record(
+        &self,
+        expression: &Expression,
+        values: &[Variable],
+        time: f64,
+        sample_rate: f64,
+    ) {
+        let mut state = self.state.lock().expect("Tracer was poisoned");
+        let state = &mut *state;
+        let loc = &expression.1;
+        let key = (loc.file.clone(), loc.span.start);
+        for (i, value) in values.iter().enumerate() {
+            let t = time + i as f64 / sample_rate;
+            let record = match state.last.get_mut(&key) {
+                // Samples in the buffer before a window were already seen
+                Some(ref last) if t <= last.seen + 0.5 / sample_rate => false,
+                Some(last) => {
+                    last.seen = t;
+                    match state.mode {
+                        TraceMode::Every(period) => t >= last.recorded + period,
+                        TraceMode::OnChange => {
+                            format!("{:?}", value) != format!("{:?}", last.value)
+                        }
+                    }
+                }
+                None => true,
+            };
+            if !record {
+                continue;
+            }
+            state.last.insert(
+                key.clone(),
+                LastValue {
+                    seen: t,
+                    recorded: t,
+                    value: value.clone(),
+                },
+            );
+            let spec = if let Operation::Print(..) = expression.0 {
+                ErrorSpec::DebugString(value.clone(), t)
+            } else {
+                ErrorSpec::DebugVar(value.clone(), t)
+            };
+            state.messages.push(Error::new(spec).on_line(loc.clone()));
+        }
+    }
+    // Record the values of a chain in a window that starts at a time if
+    // it is the chain being traced
+    pub fn record_chain(&self, name: &ChainName, values: &[Variable], time: f64, sample_rate: f64) {
+        let mut state = self.state.lock().expect("Tracer was poisoned");
+        if state.chain.as_ref() != Some(name) {
+            return;
+        }
+        let last = state.samples.last().map(|sample| sample.0);
+        for (i, value) in values.iter().enumerate() {
+            let t = time + i as f64 / sample_rate;
+            if last.is_none_or(|last| t > last + 0.5 / sample_rate) {
+                state.samples.push((t, value.clone()));
+            }
+        }
+    }
+    // Take the values of "debug" and "print" expressions that have been
+    // recorded since the last time they were taken
+    pub fn take_messages(&self) -> Vec<Error> {
+        let mut state = self.state.lock().expect("Tracer was poisoned");
+        state.messages.drain(..).collect()
+    }
+    // Take the samples of the traced chain
+    pub fn take_samples(&self) -> Vec<(f64, Variable)> {
+        let mut state = self.state.lock().expect("Tracer was poisoned");
+        state.samples.drain(..).collect()
+    }
+}
+
+// Format samples as CSV with a time column and a value column. Arrays
+// are flattened into as many value columns as they need.
+pub fn samples_to_csv(samples: &[(f64, Variable)]) -> String {
+    fn flatten(value: &Variable, columns: &mut Vec<String>) {
+        match *value {
+            Variable::Array(ref values) => {
+                for value in values {
+                    flatten(value, columns);
+                }
+            }
+            Variable::Text(ref text) => columns.push(format!("\"{}\"", text.replace('"', "\"\""))),
+            ref value => columns.push(value.as_number().to_string()),
+        }
+    }
+    let mut csv = String::from("time,value\n");
+    for (time, value) in samples {
+        let mut columns = vec![time.to_string()];
+        flatten(value, &mut columns);
+        csv.push_str(&columns.join(","));
+        csv.push('\n');
+    }
+    csv
+}
@@ -0,0 +1,119 @@
+// This module contains transformations of the timing and velocity of notes
+
+use std::f64;
+
+use builder::*;
+
+// A transformation applied to all of the notes in a notes chain
+// when the chain is finalized
+#[derive(Debug, Clone, Copy)]
+pub enum NoteTransform {
+    // Swings pairs of notes of the given unit duration so that the first
+    // note of each pair takes up the given ratio of the pair's duration
+    Swing(f64, f64),
+    // Randomly moves the boundaries between notes by up to the given
+    // number of seconds and scales their velocities by up to the given
+    // amount. The random numbers are generated from the given seed.
+    Humanize(f64, f64, u64),
+}
+
+impl NoteTransform {
+    // Apply the transform to a list of notes. The notes must be
+    // contiguous, which they always are in a notes chain. No note is moved
+    // past the given end of the chain.
+    pub fn apply(&self, notes: &mut [Note], end: f64) {
+        match *self {
+            NoteTransform::Swing(unit, ratio) => {
+                for note in notes.iter_mut() {
+                    note.period.start = swing_time(note.period.start, unit, ratio).min(end);
+                    note.period.end = swing_time(note.period.end, unit, ratio).min(end);
+                }
+            }
+            NoteTransform::Humanize(timing, velocity, seed) => {
+                let mut rng = SplitMix64(seed);
+                // Move each boundary between two notes, but never by more
+                // than half of either note so that the order is kept
+                for i in 1..notes.len() {
+                    let max_shift = timing
+                        .min(notes[i - 1].period.duration() / 2.0)
+                        .min(notes[i].period.duration() / 2.0);
+                    let shift = (rng.next_f64() * 2.0 - 1.0) * max_shift;
+                    notes[i - 1].period.end += shift;
+                    notes[i].period.start += shift;
+                }
+                for note in notes.iter_mut() {
+                    note.velocity =
+                        (note.velocity * (1.0 + (rng.next_f64() * 2.0 - 1.0) * velocity)).max(0.0);
+                }
+            }
+        }
+    }
+}
+
+// Maps a time onto its swung time. Times at the start of each pair of
+// units are unchanged, and the time in between is stretched or squashed.
+fn swing_time(time: f64, unit: f64, ratio: f64) -> f64 {
+    let pair = unit * 2.0;
+    let pair_start = (time / pair).floor() * pair;
+    let position = time - pair_start;
+    pair_start
+        + if position < unit {
+            position / unit * ratio * pair
+        } else {
+            ratio * pair + (position - unit) / unit * (1.0 - ratio) * pair
+        }
+}
+
+// A small deterministic random number generator so that humanized
+// notes come out the same every time a file is compiled
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    // Get the next random number in the range [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Builder {
+    // Resolve a list of notes or ids into a flat list of notes. The periods
+    // of the notes are offset by the given start offset.
+    pub fn flatten_notes(&self, notes_or_ids: &[NotesOrId], start_offset: f64) -> Vec<Note> {
+        let mut result = Vec::new();
+        // The time from the start of the list that has been flattened so far
+        let mut position = 0.0;
+        for notes_or_id in notes_or_ids {
+            match notes_or_id {
+                NotesOrId::Id(ref id) => {
+                    if let Some(chain) = self.find_chain(id) {
+                        if let ChainLinks::OnlyNotes(ref inner, period) = chain.links {
+                            result.extend(self.flatten_notes(inner, start_offset + position));
+                            position += period.duration();
+                        }
+                    }
+                }
+                NotesOrId::Notes(ref notes) => {
+                    for note in notes {
+                        result.push(Note {
+                            pitches: note.pitches.clone(),
+                            period: Period {
+                                start: note.period.start + start_offset,
+                                end: note.period.end + start_offset,
+                            },
+                            velocity: note.velocity,
+                        });
+                    }
+                    if let Some(last) = notes.last() {
+                        position = last.period.end;
+                    }
+                }
+            }
+        }
+        result
+    }
+}
@@ -2,9 +2,10 @@ use std::{cmp::Ordering, f64, fmt, ops};
 
 // A universal variable type
 #[derive(Clone)]
-#[allow(dead_code)]
 pub enum Variable {
     Number(f64),
+    Bool(bool),
+    Text(String),
     Array(Vec<Variable>),
 }
 
@@ -13,6 +14,8 @@ impl fmt::Debug for Variable {
         use self::Variable::*;
         match *self {
             Number(x) => x.fmt(f),
+            Bool(b) => b.fmt(f),
+            Text(ref s) => s.fmt(f),
             Array(ref v) => v.fmt(f),
         }
     }
@@ -22,13 +25,16 @@ impl fmt::Display for Variable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Variable::*;
         match *self {
-            Number(x) => write!(f, "{}", x as u8 as char),
+            Number(x) => write!(f, "{}", x),
+            Bool(b) => write!(f, "{}", b),
+            Text(ref s) => write!(f, "{}", s),
             Array(ref v) => write!(
                 f,
-                "{}",
+                "[{}]",
                 v.iter()
-                    .map(|x| f64::from(x.clone()) as u8 as char)
-                    .collect::<String>()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
             ),
         }
     }
@@ -36,36 +42,26 @@ impl fmt::Display for Variable {
 
 impl From<Variable> for f64 {
     fn from(v: Variable) -> f64 {
-        use self::Variable::*;
-        match v {
-            Number(x) => x,
-            Array(x) => x.into_iter().next().unwrap_or(Variable::Number(0.0)).into(),
-        }
+        v.as_number()
     }
 }
 
-impl<'a> From<&'a str> for Variable {
+impl From<&str> for Variable {
     fn from(s: &str) -> Variable {
-        Variable::Array(
-            s.chars()
-                .map(|c| Variable::Number(f64::from(u32::from(c))))
-                .collect(),
-        )
+        Variable::Text(s.to_string())
     }
 }
 
 impl PartialEq for Variable {
     fn eq(&self, b: &Variable) -> bool {
         use self::Variable::*;
-        match self {
-            Number(x) => match *b {
-                Number(y) => *x == y,
-                Array(ref y) => y.iter().all(|y| Number(*x) == *y),
-            },
-            Array(ref x) => match b {
-                Number(y) => x.iter().all(|x| *x == Number(*y)),
-                Array(ref y) => x.iter().zip(y.iter()).all(|(x, y)| x == y),
-            },
+        match (self, b) {
+            (Text(ref x), Text(ref y)) => x == y,
+            (Array(ref x), Array(ref y)) => x.iter().zip(y.iter()).all(|(x, y)| x == y),
+            (Array(ref x), _) => x.iter().all(|x| x == b),
+            (_, Array(ref y)) => y.iter().all(|y| self == y),
+            (Text(..), _) | (_, Text(..)) => false,
+            _ => self.as_number() == b.as_number(),
         }
     }
 }
@@ -73,15 +69,13 @@ impl PartialEq for Variable {
 impl PartialOrd for Variable {
     fn partial_cmp(&self, b: &Variable) -> Option<Ordering> {
         use self::Variable::*;
-        match self {
-            Number(x) => match b {
-                Number(y) => x.partial_cmp(y),
-                Array(..) => None,
-            },
-            Array(..) => match b {
-                Number(..) => None,
-                Array(..) => None,
-            },
+        match (self, b) {
+            (Text(ref x), Text(ref y)) => x.partial_cmp(y),
+            (Number(..), Number(..))
+            | (Number(..), Bool(..))
+            | (Bool(..), Number(..))
+            | (Bool(..), Bool(..)) => self.as_number().partial_cmp(&b.as_number()),
+            _ => None,
         }
     }
 }
@@ -89,290 +83,289 @@ impl PartialOrd for Variable {
 impl ops::Add<Variable> for Variable {
     type Output = Variable;
     fn add(self, b: Variable) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => match b {
-                Number(y) => Number(x + y),
-                Array(y) => Array(y.into_iter().map(|y| Number(x) + y).collect()),
-            },
-            Array(x) => match b {
-                Number(y) => Array(x.into_iter().map(|x| x + Number(y)).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x + y)
-                        .collect(),
-                ),
-            },
-        }
+        self.zip_numbers(b, |x, y| x + y)
     }
 }
 
 impl ops::Sub<Variable> for Variable {
     type Output = Variable;
     fn sub(self, b: Variable) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => match b {
-                Number(y) => Number(x - y),
-                Array(y) => Array(y.into_iter().map(|y| Number(x) - y).collect()),
-            },
-            Array(x) => match b {
-                Number(y) => Array(x.into_iter().map(|x| x - Number(y)).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x - y)
-                        .collect(),
-                ),
-            },
-        }
+        self.zip_numbers(b, |x, y| x - y)
     }
 }
 
 impl ops::Mul<Variable> for Variable {
     type Output = Variable;
     fn mul(self, b: Variable) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => match b {
-                Number(y) => Number(x * y),
-                Array(y) => Array(y.into_iter().map(|y| Number(x) * y).collect()),
-            },
-            Array(x) => match b {
-                Number(y) => Array(x.into_iter().map(|x| x * Number(y)).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x * y)
-                        .collect(),
-                ),
-            },
-        }
+        self.zip_numbers(b, |x, y| x * y)
     }
 }
 
 impl ops::Div<Variable> for Variable {
     type Output = Variable;
     fn div(self, b: Variable) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => match b {
-                Number(y) => Number(x / y),
-                Array(y) => Array(y.into_iter().map(|y| Number(x) / y).collect()),
-            },
-            Array(x) => match b {
-                Number(y) => Array(x.into_iter().map(|x| x / Number(y)).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x / y)
-                        .collect(),
-                ),
-            },
-        }
+        self.zip_numbers(b, |x, y| x / y)
     }
 }
 
 impl ops::Rem<Variable> for Variable {
     type Output = Variable;
     fn rem(self, b: Variable) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => match b {
-                Number(y) => Number(x % y),
-                Array(y) => Array(y.into_iter().map(|y| Number(x) % y).collect()),
-            },
-            Array(x) => match b {
-                Number(y) => Array(x.into_iter().map(|x| x % Number(y)).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x % y)
-                        .collect(),
-                ),
-            },
-        }
+        self.zip_numbers(b, |x, y| x % y)
     }
 }
 
 impl ops::Neg for Variable {
     type Output = Variable;
     fn neg(self) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => Number(-x),
-            Array(x) => Array(x.into_iter().map(|x| -x).collect()),
-        }
+        self.map_numbers(|x| -x)
     }
 }
 
-impl ops::Index<Variable> for Variable {
-    type Output = Variable;
-    fn index(&self, i: Variable) -> &Variable {
+impl Variable {
+    // The numeric value of the variable. Bools are 1 or 0, text is not a
+    // number, and arrays are the value of their first element.
+    pub fn as_number(&self) -> f64 {
         use self::Variable::*;
         match *self {
-            Number(..) => self,
-            Array(ref x) => &x[f64::from(i) as usize],
+            Number(x) => x,
+            Bool(b) => {
+                if b {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Text(..) => f64::NAN,
+            Array(ref x) => x.first().map(|x| x.as_number()).unwrap_or(0.0),
         }
     }
-}
-
-impl Variable {
-    pub fn pow(self, power: Variable) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => match power {
-                Number(y) => Number(x.powf(y)),
-                Array(y) => Array(y.into_iter().map(|y| Number(x).pow(y)).collect()),
-            },
-            Array(x) => match power {
-                Number(y) => Array(x.into_iter().map(|x| x.pow(Number(y))).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x.pow(y))
-                        .collect(),
-                ),
-            },
+    // Whether the variable counts as true in a condition
+    pub fn is_true(&self) -> bool {
+        *self != Variable::Number(0.0)
+    }
+    // Whether the variable is true, or for arrays, whether all of their
+    // elements are
+    pub fn all_true(&self) -> bool {
+        match *self {
+            Variable::Array(ref x) => x.iter().all(Variable::all_true),
+            ref x => x.is_true(),
         }
     }
-    pub fn min(self, power: Variable) -> Variable {
+    // Apply a function to every number in the variable
+    fn map_numbers<F: Fn(f64) -> f64 + Copy>(self, f: F) -> Variable {
         use self::Variable::*;
         match self {
-            Number(x) => match power {
-                Number(y) => Number(x.min(y)),
-                Array(y) => Array(y.into_iter().map(|y| Number(x).min(y)).collect()),
-            },
-            Array(x) => match power {
-                Number(y) => Array(x.into_iter().map(|x| x.min(Number(y))).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x.min(y))
-                        .collect(),
-                ),
-            },
+            Array(x) => Array(x.into_iter().map(|x| x.map_numbers(f)).collect()),
+            x => Number(f(x.as_number())),
         }
     }
-    pub fn max(self, power: Variable) -> Variable {
+    // Apply a function to every pair of non-array values in two variables,
+    // broadcasting single values over arrays
+    fn zip_with<F: Fn(Variable, Variable) -> Variable + Copy>(
+        self,
+        other: Variable,
+        f: F,
+    ) -> Variable {
         use self::Variable::*;
-        match self {
-            Number(x) => match power {
-                Number(y) => Number(x.max(y)),
-                Array(y) => Array(y.into_iter().map(|y| Number(x).max(y)).collect()),
-            },
-            Array(x) => match power {
-                Number(y) => Array(x.into_iter().map(|x| x.max(Number(y))).collect()),
-                Array(y) => Array(
-                    x.into_iter()
-                        .zip(y.into_iter())
-                        .map(|(x, y)| x.max(y))
-                        .collect(),
-                ),
-            },
+        match (self, other) {
+            (Array(x), Array(y)) => Array(
+                x.into_iter()
+                    .zip(y)
+                    .map(|(x, y)| x.zip_with(y, f))
+                    .collect(),
+            ),
+            (Array(x), y) => Array(x.into_iter().map(|x| x.zip_with(y.clone(), f)).collect()),
+            (x, Array(y)) => Array(y.into_iter().map(|y| x.clone().zip_with(y, f)).collect()),
+            (x, y) => f(x, y),
         }
     }
+    // Apply a function to every pair of numbers in two variables,
+    // broadcasting numbers over arrays
+    fn zip_numbers<F: Fn(f64, f64) -> f64 + Copy>(self, other: Variable, f: F) -> Variable {
+        self.zip_with(other, |x, y| {
+            Variable::Number(f(x.as_number(), y.as_number()))
+        })
+    }
+    pub fn pow(self, power: Variable) -> Variable {
+        self.zip_numbers(power, f64::powf)
+    }
+    // The minimum of two variables. For bools, this is a logical AND.
+    pub fn min(self, other: Variable) -> Variable {
+        self.zip_with(other, |x, y| match (x, y) {
+            (Variable::Bool(x), Variable::Bool(y)) => Variable::Bool(x && y),
+            (x, y) => Variable::Number(x.as_number().min(y.as_number())),
+        })
+    }
+    // The maximum of two variables. For bools, this is a logical OR.
+    pub fn max(self, other: Variable) -> Variable {
+        self.zip_with(other, |x, y| match (x, y) {
+            (Variable::Bool(x), Variable::Bool(y)) => Variable::Bool(x || y),
+            (x, y) => Variable::Number(x.as_number().max(y.as_number())),
+        })
+    }
     pub fn ln(self) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => Number(x.log(f64::consts::E)),
-            Array(x) => Array(x.into_iter().map(|x| x.ln()).collect()),
-        }
+        self.map_numbers(f64::ln)
     }
     pub fn sin(self) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => Number(x.sin()),
-            Array(x) => Array(x.into_iter().map(|x| x.sin()).collect()),
-        }
+        self.map_numbers(f64::sin)
     }
     pub fn cos(self) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => Number(x.cos()),
-            Array(x) => Array(x.into_iter().map(|x| x.cos()).collect()),
-        }
+        self.map_numbers(f64::cos)
     }
     pub fn floor(self) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => Number(x.floor()),
-            Array(x) => Array(x.into_iter().map(|x| x.floor()).collect()),
-        }
+        self.map_numbers(f64::floor)
     }
     pub fn ceil(self) -> Variable {
-        use self::Variable::*;
-        match self {
-            Number(x) => Number(x.ceil()),
-            Array(x) => Array(x.into_iter().map(|x| x.ceil()).collect()),
-        }
+        self.map_numbers(f64::ceil)
     }
     pub fn abs(self) -> Variable {
+        self.map_numbers(f64::abs)
+    }
+    pub fn tan(self) -> Variable {
+        self.map_numbers(f64::tan)
+    }
+    pub fn asin(self) -> Variable {
+        self.map_numbers(f64::asin)
+    }
+    pub fn acos(self) -> Variable {
+        self.map_numbers(f64::acos)
+    }
+    pub fn atan(self) -> Variable {
+        self.map_numbers(f64::atan)
+    }
+    pub fn atan2(self, x: Variable) -> Variable {
+        self.zip_numbers(x, f64::atan2)
+    }
+    pub fn exp(self) -> Variable {
+        self.map_numbers(f64::exp)
+    }
+    pub fn sqrt(self) -> Variable {
+        self.map_numbers(f64::sqrt)
+    }
+    pub fn sign(self) -> Variable {
+        self.map_numbers(|x| if x == 0.0 { 0.0 } else { x.signum() })
+    }
+    pub fn round(self) -> Variable {
+        self.map_numbers(f64::round)
+    }
+    // The fractional part of a number, which is always positive
+    pub fn fract(self) -> Variable {
+        self.map_numbers(|x| x - x.floor())
+    }
+    pub fn clamp(self, low: Variable, high: Variable) -> Variable {
+        self.max(low).min(high)
+    }
+    pub fn lerp(self, other: Variable, t: Variable) -> Variable {
+        self.clone() + (other - self) * t
+    }
+    // Convert decibels to gain
+    pub fn db_to_gain(self) -> Variable {
+        self.map_numbers(|x| 10f64.powf(x / 20.0))
+    }
+    // Convert gain to decibels
+    pub fn gain_to_db(self) -> Variable {
+        self.map_numbers(|x| 20.0 * x.log10())
+    }
+    // Convert a midi note number to a frequency in hertz
+    pub fn midi_to_freq(self) -> Variable {
+        self.map_numbers(|x| 440.0 * 2f64.powf((x - 69.0) / 12.0))
+    }
+    // Convert a frequency in hertz to a midi note number
+    pub fn freq_to_midi(self) -> Variable {
+        self.map_numbers(|x| 69.0 + 12.0 * (x / 440.0).log2())
+    }
+    // Get the element at an index. Text is indexed by character, and
+    // anything else that is not an array is its own only element.
+    pub fn at(self, i: Variable) -> Variable {
         use self::Variable::*;
+        let i = f64::from(i) as usize;
         match self {
-            Number(x) => Number(x.abs()),
-            Array(x) => Array(x.into_iter().map(|x| x.abs()).collect()),
+            Array(mut x) => x.swap_remove(i),
+            Text(s) => Text(s.chars().skip(i).take(1).collect()),
+            _ => self,
         }
     }
     pub fn sub_array(self, start: Variable, end: Variable) -> Variable {
         use self::Variable::*;
+        let start = f64::from(start);
+        let end = f64::from(end);
         match self {
-            Number(..) => self,
-            Array(x) => {
-                let start = f64::from(start);
-                let end = f64::from(end);
-                Array(
-                    x.into_iter()
-                        .skip(start as usize)
-                        .take((end - start) as usize)
-                        .collect(),
-                )
-            }
+            Array(x) => Array(
+                x.into_iter()
+                    .skip(start as usize)
+                    .take((end - start) as usize)
+                    .collect(),
+            ),
+            Text(s) => Text(
+                s.chars()
+                    .skip(start as usize)
+                    .take((end - start) as usize)
+                    .collect(),
+            ),
+            _ => self,
         }
     }
     pub fn average(self) -> Variable {
         use self::Variable::*;
         match self {
-            Number(..) => self,
             Array(x) => {
                 let xlen = x.len();
                 x.into_iter().fold(Number(0.0), |sum, val| sum + val) / Number(xlen as f64)
             }
+            _ => self,
         }
     }
+    // Concatenate two variables. Text concatenated with anything that is
+    // not an array produces text.
     pub fn cat(self, other: Variable) -> Variable {
         use self::Variable::*;
-        match self {
-            Number(..) => match other {
-                Number(..) => Array(vec![self, other]),
-                Array(y) => Array(vec![self].into_iter().chain(y.into_iter()).collect()),
-            },
-            Array(x) => match other {
-                Number(..) => Array(x.into_iter().chain(vec![other].into_iter()).collect()),
-                Array(y) => Array(x.into_iter().chain(y.into_iter()).collect()),
-            },
+        match (self, other) {
+            (Array(x), Array(y)) => Array(x.into_iter().chain(y).collect()),
+            (Array(mut x), y) => {
+                x.push(y);
+                Array(x)
+            }
+            (Text(x), y) => Text(format!("{}{}", x, y)),
+            (x, Text(y)) => Text(format!("{}{}", x, y)),
+            (x, Array(y)) => Array(vec![x].into_iter().chain(y).collect()),
+            (x, y) => Array(vec![x, y]),
         }
     }
     pub fn len(&self) -> Variable {
         use self::Variable::*;
         match self {
-            Number(..) => Number(1.0),
             Array(x) => Number(x.len() as f64),
+            Text(s) => Number(s.chars().count() as f64),
+            _ => Number(1.0),
         }
     }
+    // Find the position of a value in a variable, or -1 if it is not there.
+    // Text is searched for other text.
     pub fn find(self, other: Variable) -> Variable {
         use self::Variable::*;
-        match self {
-            Number(..) => match other {
-                Number(..) => Number(if self == other { 0.0 } else { -1.0 }),
-                Array(..) => Number(-1.0),
-            },
-            Array(x) => Number(if let Some(pos) = x.into_iter().position(|x| x == other) {
-                pos as f64
-            } else {
-                -1.0
-            }),
-        }
+        Number(match (self, other) {
+            (Array(x), other) => {
+                if let Some(pos) = x.into_iter().position(|x| x == other) {
+                    pos as f64
+                } else {
+                    -1.0
+                }
+            }
+            (Text(x), Text(y)) => {
+                if let Some(pos) = x.find(&y) {
+                    x[..pos].chars().count() as f64
+                } else {
+                    -1.0
+                }
+            }
+            (x, y) => {
+                if x == y {
+                    0.0
+                } else {
+                    -1.0
+                }
+            }
+        })
     }
 }
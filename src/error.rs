@@ -1,12 +1,16 @@
+use std::fmt;
+
 use colored::*;
 use either::*;
+use serde_json::Value;
 
-use builder::{variable::*, *};
+use builder::{shape::Shape, variable::*, *};
 use lexer::{CodeLocation, Token, TokenType};
 
 #[derive(Debug, Clone)]
 pub enum ErrorSpec {
     FileNotFound(String),
+    InvalidEncoding(String),
     ExpectedFound(Either<TokenType, String>, Token),
     CloseDelimeter(String),
     InvalidDelimeter(String),
@@ -19,22 +23,55 @@ pub enum ErrorSpec {
     UnexpectedEndOfFile,
     ZeroBacklink,
     PropertiesOfGenericChain(ChainName),
+    NotesChainMadeGeneric(ChainName, ChainName),
     DurationOfGenericChain(ChainName),
     NamedChainInAnonChain(String),
     ChainRedeclaration(ChainName),
     CantOpenOutputFile,
-    MultipleOutChains(CodeLocation),
+    CantWriteOutputFile(String),
+    CantEncodeImage(String),
+    MultipleOutChains(Box<CodeLocation>),
     UnsatisfiedBacklink(ChainName, usize, usize),
     UnnamedTopChain,
-    DebugVar(Variable),
-    DebugString(Variable),
+    DebugVar(Variable, f64),
+    DebugString(Variable, f64),
     IndexOutOfBounds(usize, usize),
     NegativeIndex(i32),
+    InvalidTuplet(usize, usize),
+    InvalidTie,
+    DanglingTie,
+    TransformOfGenericChain(ChainName),
+    InvalidSwing(f64, f64),
+    InvalidHumanize(f64, f64),
+    InvalidEuclid(usize, usize),
+    InvalidStepPattern(String),
+    NoOutChain,
+    InvalidNumber(Token),
+    InvalidWholeNumber(Token),
+    InvalidFraction(Token),
+    InvalidRatio(Token),
+    StdNotFound,
+    FftOfArray,
+    NanInFft,
+    FormatChangedCode(String),
+    UnusedChain(ChainName),
+    UnresolvedUse(String),
+    ShadowedChain(ChainName, ChainName),
+    OutChainWithoutNotes(ChainName),
+    ArrayLengthMismatch(String, usize, usize),
+    MathOnText(String),
+    IndexOfNumber(Shape),
+    CompareArrays(String, Shape),
+    OutputShape(ChainName, Shape),
+    InvalidTestLine(String, usize, String),
+    InvalidWavFile(String),
+    AssertionFailed(Variable, f64),
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum ErrorSeverity {
     Fatal,
+    Warning,
     Debug,
     Print,
 }
@@ -51,38 +88,113 @@ pub struct Error {
     pub spec: ErrorSpec,
     pub runtime: ErrorTime,
     pub severity: ErrorSeverity,
-    pub location: Option<CodeLocation>,
+    // The location is boxed to keep results small
+    pub location: Option<Box<CodeLocation>>,
+    // Other locations related to the error and what they are
+    pub labels: Vec<(CodeLocation, String)>,
 }
 
 impl Error {
     pub fn new(spec: ErrorSpec) -> Error {
         use self::{ErrorSeverity::*, ErrorSpec::*, ErrorTime::*};
         let runtime = match spec {
-            UnsatisfiedBacklink(..) | IndexOutOfBounds(..) | NegativeIndex(..) => RunClear,
+            UnsatisfiedBacklink(..)
+            | IndexOutOfBounds(..)
+            | NegativeIndex(..)
+            | FftOfArray
+            | NanInFft
+            | AssertionFailed(..) => RunClear,
             DebugVar(..) | DebugString(..) => Run,
             _ => Compile,
         };
         let severity = match spec {
             DebugVar(..) => Debug,
             DebugString(..) => Print,
+            NoOutChain
+            | UnusedChain(..)
+            | UnresolvedUse(..)
+            | ShadowedChain(..)
+            | OutChainWithoutNotes(..)
+            // Shapes that are mixed wrongly still evaluate to something
+            | ArrayLengthMismatch(..)
+            | MathOnText(..)
+            | IndexOfNumber(..)
+            | CompareArrays(..)
+            | OutputShape(..) => Warning,
             _ => Fatal,
         };
+        let labels = match spec {
+            MultipleOutChains(ref first) => {
+                vec![((**first).clone(), "first output declared here".to_string())]
+            }
+            _ => Vec::new(),
+        };
         Error {
             spec,
             runtime,
             severity,
             location: None,
+            labels,
         }
     }
     pub fn on_line(mut self, line: CodeLocation) -> Error {
-        self.location = Some(line);
+        self.location = Some(Box::new(line));
+        self
+    }
+    // Give the error a location if it does not already have one
+    pub fn or_on_line(self, line: &CodeLocation) -> Error {
+        if self.location.is_some() {
+            self
+        } else {
+            self.on_line(line.clone())
+        }
+    }
+    // Mark an error that is usually found while running as found while compiling
+    pub fn at_compile_time(mut self) -> Error {
+        self.runtime = ErrorTime::Compile;
+        self
+    }
+    // Whether the error stops the file from being compiled or run
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.severity, ErrorSeverity::Fatal)
+    }
+    // Add a secondary label to the error
+    pub fn with_label<S: Into<String>>(mut self, loc: CodeLocation, text: S) -> Error {
+        self.labels.push((loc, text.into()));
         self
     }
+    // Format the error as a single line of JSON
+    pub fn to_json(&self) -> String {
+        use self::{ErrorSeverity::*, ErrorTime::*};
+        json!({
+            "severity": match self.severity {
+                Fatal => "error",
+                Warning => "warning",
+                Debug => "debug",
+                Print => "print",
+            },
+            "phase": match self.runtime {
+                Compile => "compile",
+                Run | RunClear => "run",
+            },
+            "code": self.spec.code(),
+            "message": self.spec.to_string(),
+            "help": self.spec.help(),
+            "location": self.location.as_ref().map(|loc| location_json(loc)),
+            "labels": self
+                .labels
+                .iter()
+                .map(|(loc, text)| json!({ "message": text, "location": location_json(loc) }))
+                .collect::<Vec<_>>(),
+        })
+        .to_string()
+    }
     pub fn report(self) {
-        use self::{ErrorSeverity::*, ErrorSpec::*, ErrorTime::*};
-        // Print the generic error message
+        use self::{ErrorSeverity::*, ErrorTime::*};
+        // Print the error message
         let severity_str = match self.severity {
             Fatal => "Error".red().bold(),
+            Warning => "Warning".yellow().bold(),
             Debug => "Debug".yellow().bold(),
             Print => "Print".yellow().bold(),
         };
@@ -90,81 +202,418 @@ impl Error {
             Run | RunClear => println!(),
             _ => (),
         }
-        let erl = if let Some(ref loc) = self.location {
-            format!(
-                "{}{} in {} on line {}                                         ",
-                match self.runtime {
-                    Compile => "",
-                    Run => "\n",
-                    RunClear => "\r",
-                },
-                severity_str,
-                loc.file.cyan().bold(),
-                format!("{}:{}", loc.line, loc.column).cyan().bold()
-            )
-        } else {
-            format!("{}", severity_str)
-        };
-        println!("{}", erl);
+        println!(
+            "{}{}: {}{}",
+            match self.runtime {
+                Compile => "",
+                Run => "\n",
+                RunClear => "\r",
+            },
+            severity_str,
+            self.spec.to_string().bold(),
+            match self.runtime {
+                Compile => "",
+                _ => "                                         ",
+            }
+        );
+
+        // Print the code around the error and its labels
+        let gutter = self
+            .labels
+            .iter()
+            .map(|label| label.0.line)
+            .chain(self.location.as_ref().map(|loc| loc.line))
+            .max()
+            .unwrap_or(0)
+            .to_string()
+            .len();
+        if let Some(ref loc) = self.location {
+            print_snippet(loc, gutter, "", true);
+            for (label_loc, text) in &self.labels {
+                print_snippet(label_loc, gutter, text, false);
+            }
+        }
+        if let Some(help) = self.spec.help() {
+            println!(
+                "{} {} {} {}",
+                " ".repeat(gutter),
+                "=".blue().bold(),
+                "help:".bold(),
+                help
+            );
+        }
+    }
+}
 
-        // Print the error details
-        match self.spec {
-            FileNotFound(filename) => println!("Unable to find file: '{}'.", filename),
-            ExpectedFound(expected, found) => println!("Expected {}, found {}.", expected, found),
-            CloseDelimeter(found) => println!("Invalid close delimeter: {}.", found),
+// Print a line of code with the given location underlined. The main
+// location of an error is underlined with '^'s and labels with '-'s.
+fn print_snippet(loc: &CodeLocation, gutter: usize, label: &str, main: bool) {
+    let bar = "|".blue().bold();
+    println!(
+        "{}{} {}",
+        " ".repeat(gutter),
+        if main { "-->" } else { ":::" }.blue().bold(),
+        loc
+    );
+    println!("{} {}", " ".repeat(gutter), bar);
+    println!(
+        "{:>width$} {} {}",
+        loc.line.to_string().blue().bold(),
+        bar,
+        loc.line_text(),
+        width = gutter
+    );
+    // Tabs are kept so that the underline lines up with the code
+    let indent: String = loc
+        .line_text()
+        .chars()
+        .take(loc.column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let underline = if main {
+        "^".repeat(loc.width()).red().bold()
+    } else {
+        format!("{} {}", "-".repeat(loc.width()), label)
+            .blue()
+            .bold()
+    };
+    println!("{} {} {}{}", " ".repeat(gutter), bar, indent, underline);
+}
+
+// Format a location as a JSON object. Lines and columns start at 1, and
+// the end line and column are just past the end of the location.
+fn location_json(loc: &CodeLocation) -> Value {
+    let (end_line, end_column) = loc.end_line_column();
+    json!({
+        "file": loc.file,
+        // The file's name is not enough to open it or to tell it apart from
+        // files with the same name in other folders
+        "path": &*loc.path,
+        "line": loc.line,
+        "column": loc.column,
+        "end_line": end_line,
+        "end_column": end_column,
+        "start": loc.span.start,
+        "end": loc.span.end,
+    })
+}
+
+impl ErrorSpec {
+    // A short name for the kind of error that does not change between versions
+    pub fn code(&self) -> &'static str {
+        use self::ErrorSpec::*;
+        match *self {
+            FileNotFound(..) => "file_not_found",
+            InvalidEncoding(..) => "invalid_encoding",
+            ExpectedFound(..) => "expected_found",
+            CloseDelimeter(..) => "close_delimeter",
+            InvalidDelimeter(..) => "invalid_delimeter",
+            InvalidPitch(..) => "invalid_pitch",
+            DurationQuantifier(..) => "duration_quantifier",
+            InvalidBackLink(..) => "invalid_back_link",
+            InvalidKeyword(..) => "invalid_keyword",
+            InvalidTerm(..) => "invalid_term",
+            CantFindChain(..) => "cant_find_chain",
+            UnexpectedEndOfFile => "unexpected_end_of_file",
+            ZeroBacklink => "zero_backlink",
+            PropertiesOfGenericChain(..) => "properties_of_generic_chain",
+            NotesChainMadeGeneric(..) => "notes_chain_made_generic",
+            DurationOfGenericChain(..) => "duration_of_generic_chain",
+            NamedChainInAnonChain(..) => "named_chain_in_anon_chain",
+            ChainRedeclaration(..) => "chain_redeclaration",
+            CantOpenOutputFile => "cant_open_output_file",
+            CantWriteOutputFile(..) => "cant_write_output_file",
+            CantEncodeImage(..) => "cant_encode_image",
+            MultipleOutChains(..) => "multiple_out_chains",
+            UnsatisfiedBacklink(..) => "unsatisfied_backlink",
+            UnnamedTopChain => "unnamed_top_chain",
+            DebugVar(..) => "debug_var",
+            DebugString(..) => "debug_string",
+            IndexOutOfBounds(..) => "index_out_of_bounds",
+            NegativeIndex(..) => "negative_index",
+            InvalidTuplet(..) => "invalid_tuplet",
+            InvalidTie => "invalid_tie",
+            DanglingTie => "dangling_tie",
+            TransformOfGenericChain(..) => "transform_of_generic_chain",
+            InvalidSwing(..) => "invalid_swing",
+            InvalidHumanize(..) => "invalid_humanize",
+            InvalidEuclid(..) => "invalid_euclid",
+            InvalidStepPattern(..) => "invalid_step_pattern",
+            NoOutChain => "no_out_chain",
+            InvalidNumber(..) => "invalid_number",
+            InvalidWholeNumber(..) => "invalid_whole_number",
+            InvalidFraction(..) => "invalid_fraction",
+            InvalidRatio(..) => "invalid_ratio",
+            StdNotFound => "std_not_found",
+            FftOfArray => "fft_of_array",
+            NanInFft => "nan_in_fft",
+            FormatChangedCode(..) => "format_changed_code",
+            UnusedChain(..) => "unused_chain",
+            UnresolvedUse(..) => "unresolved_use",
+            ShadowedChain(..) => "shadowed_chain",
+            OutChainWithoutNotes(..) => "out_chain_without_notes",
+            ArrayLengthMismatch(..) => "array_length_mismatch",
+            MathOnText(..) => "math_on_text",
+            IndexOfNumber(..) => "index_of_number",
+            CompareArrays(..) => "compare_arrays",
+            OutputShape(..) => "output_shape",
+            InvalidTestLine(..) => "invalid_test_line",
+            InvalidWavFile(..) => "invalid_wav_file",
+            AssertionFailed(..) => "assertion_failed",
+        }
+    }
+    // A note that tells the user how the error might be fixed
+    pub fn help(&self) -> Option<&'static str> {
+        use self::ErrorSpec::*;
+        Some(match *self {
+            CloseDelimeter(..) => "this delimeter has no matching open delimeter",
+            InvalidPitch(..) => {
+                "pitches are note names like 'C#4', numbers in hertz, or '_' for a rest"
+            }
+            DurationQuantifier(..) => {
+                "durations are 'w', 'h', 'q', 'e', 's' or 'ts' followed by optional \
+                 dots, a fraction like '3/8', or a number of seconds"
+            }
+            InvalidBackLink(..) | ZeroBacklink => {
+                "backlinks are a '!' followed by a whole number, where '!1' is the previous link"
+            }
+            CantFindChain(..) => {
+                "chains must be declared before they are used, and chains in other \
+                 files must be brought into scope with 'use'"
+            }
+            PropertiesOfGenericChain(..)
+            | DurationOfGenericChain(..)
+            | TransformOfGenericChain(..) => {
+                "only chains made entirely of notes can be used this way"
+            }
+            NotesChainMadeGeneric(..) => {
+                "redeclare the chain with notes, or give the new chain a different name"
+            }
+            NamedChainInAnonChain(..) => {
+                "declare the chain at the top level of the file and refer to it by name"
+            }
+            ChainRedeclaration(..) => "give one of the chains a different name",
+            MultipleOutChains(..) => "only one chain can be sent to 'out'",
+            UnnamedTopChain => "give the chain a name, like 'name: ...'",
+            InvalidTie => "'~' can only join notes of the same pitch",
+            DanglingTie => "remove the '~' or add the note it should be tied to",
+            NoOutChain => "add '-> out' to the end of the chain that should be played",
+            InvalidFraction(..) => {
+                "the bottom of a fraction must be a whole number greater than 0, \
+                 like the 8 in '3/8'"
+            }
+            InvalidRatio(..) => "the bottom of a ratio cannot be 0",
+            StdNotFound => {
+                "the 'std' folder must be near the sonny executable or the current directory"
+            }
+            FftOfArray => "fft can only be taken of chains that produce single numbers",
+            FormatChangedCode(..) => "this is a bug in the formatter, so please report it",
+            UnusedChain(..) => "use the chain in the output chain or remove it",
+            UnresolvedUse(..) => "check the spelling, and make sure the file is included first",
+            ShadowedChain(..) => "give the chain a different name or remove the 'use'",
+            OutChainWithoutNotes(..) => {
+                "use a notes chain in the output chain, or set the end time with '--end'"
+            }
+            ArrayLengthMismatch(..) => {
+                "make the arrays the same length, or index them to use single elements"
+            }
+            MathOnText(..) => "only numbers, bools, and arrays of them can be used in math",
+            IndexOfNumber(..) => "only arrays and text can be indexed",
+            CompareArrays(..) => {
+                "index the array to compare single elements, or use '==' to compare whole arrays"
+            }
+            OutputShape(..) => {
+                "index the output to choose a single number, or use 'avg' to mix an array"
+            }
+            InvalidTestLine(..) => {
+                "tests are written as '<file> <chain> <start> <end> <reference>', \
+                 optionally followed by settings like 'sample_rate=8000'"
+            }
+            InvalidWavFile(..) => "references must be 16-bit mono WAV files, like sonny writes",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for ErrorSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorSpec::*;
+        match self {
+            FileNotFound(filename) => write!(f, "Unable to find file: '{}'.", filename),
+            InvalidEncoding(filename) => {
+                write!(f, "File is not valid UTF-8: '{}'.", filename)
+            }
+            ExpectedFound(expected, found) => write!(f, "Expected {}, found {}.", expected, found),
+            CloseDelimeter(found) => write!(f, "Invalid close delimeter: {}.", found),
             InvalidDelimeter(found) => {
-                println!("Delimeter is not valid in this context: {}.", found)
+                write!(f, "Delimeter is not valid in this context: {}.", found)
+            }
+            InvalidPitch(found) => write!(f, "Expected pitch, found {}.", found),
+            DurationQuantifier(found) => {
+                write!(f, "Expected duration quantifier, found {}.", found)
             }
-            InvalidPitch(found) => println!("Expected pitch, found {}.", found),
-            DurationQuantifier(found) => println!("Expected duration quantifier, found {}.", found),
-            InvalidBackLink(found) => println!("Expected number after '!', found {}.", found),
-            InvalidKeyword(found) => println!("Keyword is invalid in this context: '{}'.", found),
-            InvalidTerm(found) => println!("Invalid term: {}.", found),
+            InvalidBackLink(found) => write!(f, "Expected number after '!', found {}.", found),
+            InvalidKeyword(found) => write!(f, "Keyword is invalid in this context: '{}'.", found),
+            InvalidTerm(found) => write!(f, "Invalid term: {}.", found),
             CantFindChain(chain_name) => {
-                println!("The {} could not be found in this scope.", chain_name)
+                write!(f, "The {} could not be found in this scope.", chain_name)
             }
-            UnexpectedEndOfFile => println!("Unexpected end of file."),
-            ZeroBacklink => println!("Backlinks must be greater than 0."),
-            PropertiesOfGenericChain(chain_name) => println!(
+            UnexpectedEndOfFile => write!(f, "Unexpected end of file."),
+            ZeroBacklink => write!(f, "Backlinks must be greater than 0."),
+            PropertiesOfGenericChain(chain_name) => write!(
+                f,
                 "The {} contains expressions, so properties cannot be taken from it.",
                 chain_name
             ),
-            DurationOfGenericChain(chain_name) => println!(
+            NotesChainMadeGeneric(chain_name, user) => write!(
+                f,
+                "The {} is used as notes by the {}, so it cannot contain expressions.",
+                chain_name, user
+            ),
+            DurationOfGenericChain(chain_name) => write!(
+                f,
                 "The {} contains expressions, so it cannot be used to define a note duration",
                 chain_name
             ),
-            NamedChainInAnonChain(chain_name) => println!(
+            NamedChainInAnonChain(chain_name) => write!(
+                f,
                 "A named chain: '{}' cannot be declared inside an anonymous chain.",
                 chain_name
             ),
-            ChainRedeclaration(chain_name) => println!("Redeclaration of {}.", chain_name),
-            CantOpenOutputFile => println!(
+            ChainRedeclaration(chain_name) => write!(f, "Redeclaration of {}.", chain_name),
+            CantOpenOutputFile => write!(
+                f,
                 "Unable to open output file.\nMake sure you have a default WAV player set."
             ),
-            MultipleOutChains(loc) => println!(
-                "Multiple output chains.\nFirst output declared on line {}.",
-                loc
-            ),
-            UnsatisfiedBacklink(chain_name, expected, found) => println!(
+            CantWriteOutputFile(filename) => {
+                write!(f, "Unable to write output file: '{}'.", filename)
+            }
+            CantEncodeImage(reason) => write!(f, "Unable to encode image: {}.", reason),
+            MultipleOutChains(..) => write!(f, "Multiple output chains."),
+            UnsatisfiedBacklink(chain_name, expected, found) => write!(
+                f,
                 "Backlink \"!{}\" in {} expects at least {} previous link{}, but {} found.",
                 expected,
                 chain_name,
                 expected,
-                if expected > 1 { "s" } else { "" },
+                if *expected > 1 { "s" } else { "" },
                 match found {
                     0 => "none were".to_string(),
                     1 => "only 1 was".to_string(),
                     _ => format!("only {} were", found),
                 }
             ),
-            UnnamedTopChain => println!("Chains within a file's top-level scope must be named."),
-            DebugVar(var) => println!("{:?}", var),
-            DebugString(var) => println!("{}", var),
-            IndexOutOfBounds(i, n) => println!(
+            UnnamedTopChain => write!(f, "Chains within a file's top-level scope must be named."),
+            DebugVar(var, time) => write!(f, "at {} s: {:?}", time, var),
+            DebugString(var, time) => write!(f, "at {} s: {}", time, var),
+            IndexOutOfBounds(i, n) => write!(
+                f,
                 "Index out of bounds. The index is {} but the length is {}.",
                 i, n
             ),
-            NegativeIndex(i) => println!("Index is negative: {}.", i),
+            NegativeIndex(i) => write!(f, "Index is negative: {}.", i),
+            InvalidTuplet(count, span) => write!(
+                f,
+                "Invalid tuplet ratio: {}:{}. Both numbers must be greater than 0.",
+                count, span
+            ),
+            InvalidTie => write!(f, "Tied notes must have the same pitch."),
+            DanglingTie => write!(
+                f,
+                "A tie must be followed by another note in the same list."
+            ),
+            TransformOfGenericChain(chain_name) => write!(
+                f,
+                "The {} contains expressions, so its notes cannot be transformed.",
+                chain_name
+            ),
+            InvalidSwing(unit, ratio) => write!(
+                f,
+                "Invalid swing of {} with ratio {}. The unit must be greater than 0 \
+                 and the ratio must be between 0 and 1.",
+                unit, ratio
+            ),
+            InvalidHumanize(timing, velocity) => write!(
+                f,
+                "Invalid humanize of {} with velocity {}. Both amounts must be \
+                 numbers that are at least 0.",
+                timing, velocity
+            ),
+            InvalidEuclid(hits, steps) => write!(
+                f,
+                "Invalid euclidean rhythm of {} hits in {} steps. \
+                 There must be at least 1 step and no more hits than steps.",
+                hits, steps
+            ),
+            InvalidStepPattern(pattern) => write!(
+                f,
+                "Invalid step pattern: \"{}\". Patterns must have at least one step \
+                 and may only contain 'x', 'o', '.', '_' and spaces.",
+                pattern
+            ),
+            NoOutChain => write!(f, "No chain is sent to 'out', so no audio will be written."),
+            InvalidNumber(found) => write!(f, "Expected number, found {}.", found),
+            InvalidWholeNumber(found) => write!(f, "Expected whole number, found {}.", found),
+            InvalidFraction(found) => {
+                write!(f, "Invalid bottom of duration fraction: {}.", found)
+            }
+            InvalidRatio(found) => write!(f, "Invalid bottom of ratio: {}.", found),
+            StdNotFound => write!(f, "Unable to find the standard library folder."),
+            FftOfArray => write!(f, "Cannot take the fft of a window of arrays."),
+            NanInFft => write!(f, "The input to fft contains values that are not numbers."),
+            FormatChangedCode(filename) => write!(
+                f,
+                "Formatting '{}' would change its code, so it was not formatted.",
+                filename
+            ),
+            UnusedChain(chain_name) => write!(
+                f,
+                "The {} is declared but never used by the output chain.",
+                chain_name
+            ),
+            UnresolvedUse(name) => write!(f, "'use {}' does not refer to any chains.", name),
+            ShadowedChain(chain_name, other) => write!(
+                f,
+                "The {} is shadowed by the {}, which its name refers to instead.",
+                chain_name, other
+            ),
+            OutChainWithoutNotes(chain_name) => write!(
+                f,
+                "The output {} does not use any notes, so nothing decides how long it plays.",
+                chain_name
+            ),
+            ArrayLengthMismatch(op, a, b) => write!(
+                f,
+                "'{}' combines arrays of {} and {} elements, so the extra elements are dropped.",
+                op, a, b
+            ),
+            MathOnText(op) => write!(f, "'{}' cannot be used on text, which is not a number.", op),
+            IndexOfNumber(shape) => {
+                write!(f, "Cannot index {}, because it is not an array.", shape)
+            }
+            CompareArrays(op, shape) => write!(
+                f,
+                "'{}' cannot compare {}, so it is always false.",
+                op, shape
+            ),
+            OutputShape(chain_name, shape) => write!(
+                f,
+                "The output {} produces {}, but only its first number is played.",
+                chain_name, shape
+            ),
+            InvalidTestLine(manifest, line, reason) => {
+                write!(
+                    f,
+                    "Invalid test on line {} of '{}': {}.",
+                    line, manifest, reason
+                )
+            }
+            InvalidWavFile(filename) => write!(f, "Unable to read WAV file: '{}'.", filename),
+            AssertionFailed(value, time) => write!(
+                f,
+                "Assertion failed at {} s, where the value is {:?}.",
+                time, value
+            ),
         }
     }
 }
@@ -0,0 +1,705 @@
+// This module contains the formatter, which prints Sonny source code with
+// a consistent layout. The source is parsed into a lossless syntax tree of
+// tokens that keep the comments written before them, so formatting only
+// ever changes the whitespace between tokens.
+
+use std::{collections::HashSet, mem, ops::Range};
+
+use error::{ErrorSpec::*, *};
+use lexer::{
+    keyword_kind, KeywordKind, Lexer, Token, TokenType, TokenType::*, CONTEXTUAL_KEYWORDS,
+};
+
+// The width that lines are kept within when possible
+const MAX_WIDTH: usize = 100;
+// The number of spaces in each level of indentation
+const INDENT: usize = 4;
+
+// The part a token plays in the layout of the code around it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Open,
+    Close,
+    Comma,
+    Path,
+    Colon,
+    TernaryColon,
+    Arrow,
+    Dot,
+    Tilde,
+    BackLink,
+    Prefix,
+    Function,
+    Binary,
+    Atom,
+}
+
+// A comment and where it was written
+#[derive(Debug, Clone)]
+struct Comment {
+    text: String,
+    // Whether the comment is on the same line as the code before it
+    trailing: bool,
+    // Whether there is a blank line before the comment
+    blank_before: bool,
+}
+
+// A token along with the comments before it
+#[derive(Debug, Clone)]
+struct Tok {
+    token: Token,
+    // The token as it is written in the source
+    text: String,
+    comments: Vec<Comment>,
+    // Whether there is a blank line between the token and the comment
+    // or token before it
+    blank_before: bool,
+    kind: Kind,
+    // Whether the token is used as a keyword
+    keyword: bool,
+    // The number of delimeters the token is inside of
+    depth: usize,
+    // Whether there is a space between the token and the one before it
+    // when they are on the same line
+    space: bool,
+}
+
+// A link in a chain and the "->" before it
+struct Link {
+    arrow: Option<usize>,
+    tokens: Range<usize>,
+}
+
+// Format source code. If formatting would change anything other than
+// whitespace, an error is returned instead.
+pub fn format_source(file: &str, source: &str) -> SonnyResult<String> {
+    let (mut toks, end_comments) = lex(file, source);
+    let items = analyze(&mut toks);
+    let mut formatter = Formatter {
+        toks,
+        lines: Vec::new(),
+        line: String::new(),
+        tempo: 120.0,
+    };
+    for item in items {
+        formatter.item(item);
+    }
+    formatter.comments(end_comments, 0, true);
+    if !formatter.line_is_empty() {
+        formatter.end_line();
+    }
+    let mut formatted = formatter.lines.join("\n");
+    // A newline at the end of a string that is never closed would be part of it
+    let open_string = formatter.toks.last().is_some_and(is_open_string);
+    if !formatted.is_empty() && !open_string {
+        formatted.push('\n');
+    }
+    // Make sure that nothing but whitespace changed
+    let (before, before_end) = lex(file, source);
+    let (after, after_end) = lex(file, &formatted);
+    let summary = |toks: Vec<Tok>, end: Vec<Comment>| {
+        let mut tokens = Vec::new();
+        let mut comments = Vec::new();
+        for tok in toks {
+            comments.extend(tok.comments.into_iter().map(|comment| comment.text));
+            tokens.push(tok.token);
+        }
+        comments.extend(end.into_iter().map(|comment| comment.text));
+        (tokens, comments)
+    };
+    if summary(before, before_end) == summary(after, after_end) {
+        Ok(formatted)
+    } else {
+        Err(Error::new(FormatChangedCode(file.to_string())))
+    }
+}
+
+// Split source code into tokens with their comments. The comments after
+// the last token are also returned.
+fn lex(file: &str, source: &str) -> (Vec<Tok>, Vec<Comment>) {
+    let mut lexer = Lexer::from_source(file, source);
+    let mut toks = Vec::new();
+    let mut last_end = 0;
+    loop {
+        let token = lexer.lex();
+        let span = lexer.span();
+        // Strings that are never closed end with the newline after them
+        let after_token = toks
+            .last()
+            .is_some_and(|tok: &Tok| !tok.text.ends_with('\n'));
+        let (comments, blank_before) = trivia(&source[last_end..span.start], after_token);
+        if token.0 == Done {
+            return (toks, comments);
+        }
+        let keyword = token.0 == Keyword;
+        toks.push(Tok {
+            token,
+            text: source[span.start..span.end].to_string(),
+            comments,
+            blank_before,
+            kind: Kind::Atom,
+            keyword,
+            depth: 0,
+            space: false,
+        });
+        last_end = span.end;
+    }
+}
+
+// Whether a token is a string that is closed by neither a quote nor a
+// newline, so it runs to the end of the file
+fn is_open_string(tok: &Tok) -> bool {
+    if tok.token.0 != StringLiteral {
+        return false;
+    }
+    let mut chars = tok.text.chars().skip(1);
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' | '\n' => return false,
+            _ => (),
+        }
+    }
+    true
+}
+
+// Find the comments in the whitespace and comments between two tokens, and
+// whether there is a blank line after the last one
+fn trivia(mut text: &str, after_token: bool) -> (Vec<Comment>, bool) {
+    let mut comments: Vec<Comment> = Vec::new();
+    let mut newlines = 0;
+    while let Some(c) = text.chars().next() {
+        if c == '#' {
+            // Block comments end with "/#" and line comments end with a newline
+            let len = if let Some(rest) = text.strip_prefix("#/") {
+                rest.find("/#").map(|i| i + 4).unwrap_or(text.len())
+            } else {
+                text.find('\n').unwrap_or(text.len())
+            };
+            comments.push(Comment {
+                text: text[..len].trim_end().to_string(),
+                trailing: newlines == 0 && (after_token || !comments.is_empty()),
+                blank_before: newlines > 1,
+            });
+            newlines = 0;
+            text = &text[len..];
+        } else {
+            if c == '\n' {
+                newlines += 1;
+            }
+            text = &text[c.len_utf8()..];
+        }
+    }
+    (comments, newlines > 1)
+}
+
+// Work out the kind, depth, and spacing of every token, and split the tokens
+// into top-level items
+fn analyze(toks: &mut [Tok]) -> Vec<Range<usize>> {
+    mark_contextual_keywords(toks);
+    let mut items = Vec::new();
+    let mut item_start = 0;
+    let mut delimeters: Vec<String> = Vec::new();
+    let mut ternaries = 0;
+    let mut prev: Option<usize> = None;
+    for i in 0..toks.len() {
+        // A new item starts with a keyword like "std" or a name and a ':',
+        // as long as the item before it is finished
+        let starts_item = (toks[i].keyword
+            && keyword_kind(&toks[i].text) == Some(KeywordKind::Item))
+            || (toks[i].token.0 == Id && toks.get(i + 1).is_some_and(|tok| tok.text == ":"));
+        let continues = prev.is_some_and(|p| {
+            !matches!(
+                toks[p].kind,
+                Kind::Atom | Kind::Close | Kind::Dot | Kind::Tilde
+            )
+        });
+        if i > 0 && delimeters.is_empty() && starts_item && !continues {
+            items.push(item_start..i);
+            item_start = i;
+            prev = None;
+            ternaries = 0;
+        }
+        let in_notes = delimeters.last().is_some_and(|d| d == "{");
+        let in_durations = in_notes
+            || delimeters
+                .last()
+                .is_some_and(|d| keyword_kind(d) == Some(KeywordKind::DurationFunction));
+        let kind = classify(
+            &toks[i],
+            prev.map(|p| toks[p].kind),
+            in_notes,
+            &mut ternaries,
+        );
+        toks[i].kind = kind;
+        if kind == Kind::Close {
+            delimeters.pop();
+        }
+        toks[i].depth = delimeters.len();
+        if kind == Kind::Open {
+            // The arguments of functions that take durations are marked
+            // with the function's name
+            let function = prev
+                .map(|p| &toks[p].text)
+                .filter(|text| keyword_kind(text) == Some(KeywordKind::DurationFunction));
+            delimeters.push(function.unwrap_or(&toks[i].text).clone());
+        }
+        toks[i].space = prev.is_some_and(|p| spaced(&toks[p], &toks[i], in_notes, in_durations));
+        prev = Some(i);
+    }
+    if item_start < toks.len() {
+        items.push(item_start..toks.len());
+    }
+    items
+}
+
+// Mark the contextual keywords that are used as keywords. This follows the
+// parser, except that only the chains declared in the file are known.
+fn mark_contextual_keywords(toks: &mut [Tok]) {
+    let chains: HashSet<String> = toks
+        .windows(2)
+        .filter(|pair| pair[0].token.0 == Id && pair[1].text == ":")
+        .map(|pair| pair[0].text.clone())
+        .collect();
+    for i in 0..toks.len() {
+        let text = toks[i].text.as_str();
+        if toks[i].token.0 != Id || !CONTEXTUAL_KEYWORDS.contains(&text) {
+            continue;
+        }
+        let next = match toks.get(i + 1) {
+            Some(next) => &next.token,
+            None => continue,
+        };
+        let chain = chains.contains(text);
+        toks[i].keyword = match keyword_kind(text) {
+            Some(KeywordKind::Function) | Some(KeywordKind::DurationFunction) => next.1 == "(",
+            Some(KeywordKind::Item) => next.0 == StringLiteral,
+            Some(KeywordKind::Value) => !chain,
+            _ => match next.0 {
+                Num | Id | StringLiteral | NoteString | BackLink | TokenType::Dot => true,
+                Keyword => keyword_kind(&next.1) != Some(KeywordKind::Binary),
+                Delimeter => next.1 == "(" || next.1 == "[",
+                Operator => next.1 == "-" && !chain,
+                _ => false,
+            },
+        };
+    }
+}
+
+// Determine the kind of a token from the kind of the one before it
+fn classify(tok: &Tok, prev: Option<Kind>, in_notes: bool, ternaries: &mut usize) -> Kind {
+    use self::Kind::*;
+    // Whether the token is at the start of a term
+    let starts_term = prev.is_none_or(|prev| {
+        matches!(
+            prev,
+            Open | Comma | Colon | TernaryColon | Arrow | Binary | Prefix
+        )
+    });
+    let text = tok.text.as_str();
+    match tok.token.0 {
+        Delimeter => match text {
+            "(" | "[" | "{" => Open,
+            ")" | "]" | "}" => Close,
+            "|" if starts_term => Open,
+            "|" => Close,
+            "," => Comma,
+            "::" => Path,
+            ":" if !in_notes && *ternaries > 0 => {
+                *ternaries -= 1;
+                TernaryColon
+            }
+            ":" => Colon,
+            "->" => Arrow,
+            _ => Atom,
+        },
+        TokenType::Dot => Dot,
+        TokenType::BackLink => BackLink,
+        Operator => match text {
+            "~" => Tilde,
+            "-" if starts_term => Prefix,
+            // The '*' in "use gen::*"
+            "*" if prev == Some(Path) => Atom,
+            "?" => {
+                *ternaries += 1;
+                Binary
+            }
+            _ => Binary,
+        },
+        _ if tok.keyword => match keyword_kind(text) {
+            Some(KeywordKind::Prefix) => Prefix,
+            Some(KeywordKind::Binary) => Binary,
+            Some(KeywordKind::Function) | Some(KeywordKind::DurationFunction) => Function,
+            _ => Atom,
+        },
+        _ => Atom,
+    }
+}
+
+// Whether there should be a space between two tokens on the same line
+fn spaced(prev: &Tok, next: &Tok, in_notes: bool, in_durations: bool) -> bool {
+    use self::Kind::*;
+    let space = match (prev.kind, next.kind) {
+        (Open, _) | (_, Close) | (_, Comma) | (_, Tilde) | (BackLink, _) => false,
+        (Path, _) | (_, Path) => false,
+        (Dot, Dot) => true,
+        (Atom, Dot) | (Close, Dot) | (Dot, Atom) => false,
+        (_, Colon) => false,
+        (Colon, _) => !in_notes,
+        (Prefix, _) if prev.text == "-" => false,
+        (Prefix, Open) | (Function, Open) => next.text != "(",
+        // Indexing, and tuplets in notes
+        (Atom, Open) | (Close, Open) | (Tilde, Open) => {
+            next.text != "[" && !(in_notes && next.text == "{")
+        }
+        (Binary, _) | (_, Binary) if in_durations && (prev.text == "/" || next.text == "/") => {
+            false
+        }
+        _ => true,
+    };
+    // Never join tokens that would be lexed differently without a space
+    space || {
+        let mut lexer = Lexer::from_source("", &format!("{}{}", prev.text, next.text));
+        lexer.lex() != prev.token || lexer.lex() != next.token || lexer.lex().0 != Done
+    }
+}
+
+// Lays out the items of a file into lines
+struct Formatter {
+    toks: Vec<Tok>,
+    // The lines that are finished
+    lines: Vec<String>,
+    // The line that is being written
+    line: String,
+    // The tempo that durations in seconds are measured with
+    tempo: f64,
+}
+
+impl Formatter {
+    // Format a top-level item
+    fn item(&mut self, range: Range<usize>) {
+        self.start_line(range.start, 0, true);
+        // Chains and tests have a header like "name:" before their links
+        let first = &self.toks[range.start];
+        let header_end = if first.token.0 == Id {
+            Some(range.start + 2)
+                .filter(|_| range.len() > 2 && self.toks[range.start + 1].text == ":")
+        } else if first.keyword && first.text == "test" {
+            (range.start..range.end)
+                .find(|&i| self.toks[i].kind == Kind::Colon && self.toks[i].depth == 0)
+                .map(|i| i + 1)
+                .filter(|&end| end < range.end)
+        } else {
+            None
+        };
+        let header_end = if let Some(header_end) = header_end {
+            header_end
+        } else {
+            // Keep track of the tempo for measuring notes
+            if self.toks[range.start].text == "tempo" {
+                let tempo: String = self.toks[range.clone()]
+                    .iter()
+                    .skip(2)
+                    .map(|tok| tok.text.as_str())
+                    .collect();
+                self.tempo = tempo.parse().unwrap_or(self.tempo);
+            }
+            self.flow(range, INDENT);
+            return;
+        };
+        // Split the chain into links
+        let mut links = Vec::new();
+        let mut arrow = None;
+        let mut start = header_end;
+        for i in header_end..range.end {
+            if self.toks[i].kind == Kind::Arrow && self.toks[i].depth == 0 {
+                links.push(Link {
+                    arrow,
+                    tokens: start..i,
+                });
+                arrow = Some(i);
+                start = i + 1;
+            }
+        }
+        links.push(Link {
+            arrow,
+            tokens: start..range.end,
+        });
+        if links.iter().any(|link| link.tokens.is_empty()) {
+            self.flow(range, INDENT);
+            return;
+        }
+        self.flow(range.start..header_end, INDENT);
+        self.chain(&links);
+    }
+    // Format the links of a chain after its name
+    fn chain(&mut self, links: &[Link]) {
+        // Comments before an arrow are kept with the link after it
+        for link in links {
+            if let Some(arrow) = link.arrow {
+                let mut comments = mem::take(&mut self.toks[arrow].comments);
+                comments.append(&mut self.toks[link.tokens.start].comments);
+                self.toks[link.tokens.start].comments = comments;
+            }
+        }
+        let flat: Option<Vec<String>> = links
+            .iter()
+            .map(|link| self.flat(link.tokens.clone()))
+            .collect();
+        // Put the whole chain on one line if it fits
+        if let Some(flat) = flat {
+            let flat = flat.join(" -> ");
+            if self.line.len() + 1 + flat.len() <= MAX_WIDTH {
+                self.line.push(' ');
+                self.line.push_str(&flat);
+                return;
+            }
+        }
+        // Otherwise, a long note list at the start of the chain can be
+        // wrapped with the rest of the chain after it
+        let first = links[0].tokens.clone();
+        let rest: Option<Vec<String>> = links[1..]
+            .iter()
+            .map(|link| self.flat(link.tokens.clone()))
+            .collect();
+        let list_fits = self
+            .flat(first.clone())
+            .is_some_and(|flat| self.line.len() + 1 + flat.len() <= MAX_WIDTH);
+        if let (true, false, Some(rest)) = (self.is_note_list(&first), list_fits, rest) {
+            let rest: String = rest.iter().map(|link| format!(" -> {}", link)).collect();
+            if self.toks[first.start].comments.is_empty() && rest.len() < MAX_WIDTH {
+                self.note_list(first, 0);
+                self.line.push_str(&rest);
+                return;
+            }
+        }
+        // Otherwise, put each link on its own line
+        for link in links {
+            if let Some(arrow) = link.arrow {
+                self.push(arrow, INDENT);
+            }
+            self.start_line(link.tokens.start, INDENT, true);
+            if self.is_note_list(&link.tokens) {
+                self.note_list(link.tokens.clone(), INDENT);
+            } else {
+                self.flow(link.tokens.clone(), INDENT * 2);
+            }
+        }
+    }
+    // Format a note list, wrapping it by bar if it does not fit on the line.
+    // Bars are one whole note long.
+    fn note_list(&mut self, range: Range<usize>, indent: usize) {
+        let (open, close) = (range.start, range.end - 1);
+        if let Some(flat) = self.flat(range.clone()) {
+            if self.line.len() + 1 + flat.len() <= MAX_WIDTH {
+                self.flow(range, indent);
+                return;
+            }
+        }
+        let items = self.note_items(open, close);
+        let durations: Option<Vec<f64>> = items
+            .iter()
+            .map(|item| self.duration(item.clone()))
+            .collect();
+        self.push(open, indent);
+        let mut position = 0.0;
+        let mut bar_end = 1.0;
+        let mut new_line = true;
+        for (i, item) in items.into_iter().enumerate() {
+            let fits = self
+                .flat(item.clone())
+                .is_some_and(|flat| self.line.len() + 1 + flat.len() <= MAX_WIDTH);
+            // A note that goes past the end of the bar starts a new line
+            let spans_bar = durations
+                .as_ref()
+                .is_some_and(|durations| position + durations[i] > bar_end + 1e-9);
+            if new_line || !fits || spans_bar {
+                self.start_line(item.start, indent + INDENT, false);
+            }
+            self.flow(item, indent + INDENT * 2);
+            new_line = false;
+            if let Some(ref durations) = durations {
+                position += durations[i];
+                if position >= bar_end - 1e-9 {
+                    bar_end = (position + 1e-9).floor() + 1.0;
+                    // The line of a note that spans bars goes on to the
+                    // end of the bar that the note ends in
+                    new_line = (position - position.round()).abs() < 1e-9;
+                }
+            }
+        }
+        self.start_line(close, indent, false);
+        self.push(close, indent);
+    }
+    // Whether a range of tokens is a single note list
+    fn is_note_list(&self, range: &Range<usize>) -> bool {
+        let depth = self.toks[range.start].depth;
+        self.toks[range.start].text == "{"
+            && self.toks[range.end - 1].text == "}"
+            && self.toks[range.start + 1..range.end - 1]
+                .iter()
+                .all(|tok| tok.depth > depth)
+    }
+    // Split the contents of a note list into notes and tuplets, each with
+    // the ',' after it
+    fn note_items(&self, open: usize, close: usize) -> Vec<Range<usize>> {
+        let depth = self.toks[open].depth + 1;
+        let mut items = Vec::new();
+        let mut start = open + 1;
+        for i in open + 1..close {
+            if self.toks[i].kind == Kind::Comma && self.toks[i].depth == depth {
+                items.push(start..i + 1);
+                start = i + 1;
+            }
+        }
+        if start < close {
+            items.push(start..close);
+        }
+        items
+    }
+    // The duration of a note or tuplet in whole notes, if it can be
+    // known without parsing the rest of the file
+    fn duration(&self, range: Range<usize>) -> Option<f64> {
+        let mut end = range.end;
+        while end > range.start
+            && (self.toks[end - 1].text == "," || self.toks[end - 1].text == "~")
+        {
+            end -= 1;
+        }
+        let toks = &self.toks[range.start..end];
+        // Tuplets fit the notes of one number of beats into another
+        if toks.len() > 4
+            && toks[0].token.0 == Num
+            && toks[1].text == ":"
+            && toks[2].token.0 == Num
+            && toks[3].text == "{"
+        {
+            let count: f64 = toks[0].text.parse().ok()?;
+            let span: f64 = toks[2].text.parse().ok()?;
+            let mut total = 0.0;
+            for item in self.note_items(range.start + 3, end - 1) {
+                total += self.duration(item)?;
+            }
+            return Some(total * span / count);
+        }
+        let depth = toks.first()?.depth;
+        let colon = toks
+            .iter()
+            .position(|tok| tok.text == ":" && tok.depth == depth)?;
+        let duration = &toks[colon + 1..];
+        let first = duration.first()?;
+        if first.token.0 == Keyword {
+            let mut frac = match first.text.as_str() {
+                "w" => 1.0,
+                "h" => 0.5,
+                "q" => 0.25,
+                "e" => 0.125,
+                "s" => 0.0625,
+                "ts" => 0.03125,
+                _ => return None,
+            };
+            for (i, dot) in duration[1..].iter().enumerate() {
+                if dot.text != "." {
+                    return None;
+                }
+                frac += frac / 2usize.pow(i as u32 + 1) as f64;
+            }
+            Some(frac)
+        } else if duration.len() == 3 && duration[1].text == "/" {
+            let top: f64 = duration[0].text.parse().ok()?;
+            let bottom: f64 = duration[2].text.parse().ok()?;
+            Some(top / bottom)
+        } else if first.token.0 == Num {
+            // Other numbers are in seconds
+            let seconds: String = duration.iter().map(|tok| tok.text.as_str()).collect();
+            seconds
+                .parse::<f64>()
+                .ok()
+                .map(|seconds| seconds * self.tempo / 240.0)
+        } else {
+            None
+        }
+    }
+    // The tokens on a single line, or None if there are comments between them
+    fn flat(&self, range: Range<usize>) -> Option<String> {
+        let mut text = String::new();
+        for i in range.clone() {
+            if !self.toks[i].comments.is_empty() {
+                return None;
+            }
+            if i > range.start && self.toks[i].space {
+                text.push(' ');
+            }
+            text.push_str(&self.toks[i].text);
+        }
+        Some(text)
+    }
+    // Write tokens one after another, only starting new lines for comments
+    fn flow(&mut self, range: Range<usize>, indent: usize) {
+        for i in range {
+            self.push(i, indent);
+        }
+    }
+    // Write a token on the current line. If there are comments before it,
+    // it is put on a new line with the given indentation.
+    fn push(&mut self, i: usize, indent: usize) {
+        if !self.toks[i].comments.is_empty() {
+            self.start_line(i, indent, false);
+        } else if self.toks[i].space && !self.line_is_empty() {
+            self.line.push(' ');
+        }
+        self.line.push_str(&self.toks[i].text);
+    }
+    // Write the comments before a token and start a new line for it
+    fn start_line(&mut self, i: usize, indent: usize, allow_blank: bool) {
+        let comments = mem::take(&mut self.toks[i].comments);
+        self.comments(comments, indent, allow_blank);
+        if !self.line_is_empty() {
+            self.end_line();
+        }
+        if allow_blank && self.toks[i].blank_before {
+            self.blank_line();
+        }
+        self.line = " ".repeat(indent);
+    }
+    // Write comments. Comments that were on the same line as the code
+    // before them stay there, and the rest go on their own lines.
+    fn comments(&mut self, comments: Vec<Comment>, indent: usize, allow_blank: bool) {
+        for comment in comments {
+            if comment.trailing && !self.line_is_empty() {
+                self.line.push(' ');
+            } else {
+                if !self.line_is_empty() {
+                    self.end_line();
+                }
+                if allow_blank && comment.blank_before {
+                    self.blank_line();
+                }
+                self.line = " ".repeat(indent);
+            }
+            self.line.push_str(&comment.text);
+            self.end_line();
+        }
+    }
+    // Whether there is nothing but indentation on the current line
+    fn line_is_empty(&self) -> bool {
+        self.line.trim().is_empty()
+    }
+    fn end_line(&mut self) {
+        let line = mem::take(&mut self.line);
+        self.lines.push(if line.trim().is_empty() {
+            String::new()
+        } else {
+            line
+        });
+    }
+    // Add a blank line, unless it would be at the start of the file or
+    // right after another blank line
+    fn blank_line(&mut self) {
+        if self.lines.last().is_some_and(|line| !line.is_empty()) {
+            self.lines.push(String::new());
+        }
+    }
+}
@@ -0,0 +1,299 @@
+// This module contains golden tests, which render chains and compare
+// them with reference WAV files that were rendered before.
+//
+// Tests are listed in a manifest file, one per line, like this:
+//
+//     # file       chain    start  end  reference
+//     song.son     song     0      4    golden/song.wav    sample_rate=8000
+//
+// Paths are relative to the manifest. The chain "out" is the chain sent
+// to out. Each test can end with settings: "sample_rate", "window", and
+// "buffer" are like the command line options, "tolerance" is the most
+// that any sample may differ by, and "spectral_tolerance" is the most
+// that the spectra of the audio may differ by, relative to their size.
+
+use std::{fs, path::Path};
+
+use hound;
+
+use builder::ChainName;
+use error::{ErrorSpec::*, *};
+use render::{quantize, write_wav, RenderSettings};
+use spectrum::Stft;
+
+// The number of samples in each frame that the spectrum of a render is
+// averaged over
+static FRAME_SIZE: usize = 1024;
+
+// A chain to render and the reference to compare it with
+#[derive(Debug, Clone)]
+pub struct GoldenTest {
+    // The manifest the test is in and the line it is on
+    pub manifest: String,
+    pub line: usize,
+    pub file: String,
+    pub chain: String,
+    pub start: f64,
+    pub end: f64,
+    pub reference: String,
+    pub settings: RenderSettings,
+    pub tolerance: f64,
+    pub spectral_tolerance: f64,
+}
+
+// How different a render is from its reference
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    // The largest difference between two samples and the time it is at
+    pub max_difference: f64,
+    pub max_difference_time: f64,
+    // The root mean square of the differences between samples
+    pub rms_difference: f64,
+    // The difference between the average spectra of the audio, relative
+    // to the size of the larger one
+    pub spectral_difference: f64,
+}
+
+// The result of running a test
+#[derive(Debug, Clone)]
+pub enum TestResult {
+    Passed(Comparison),
+    // The render is too different from the reference, and why
+    Failed(Vec<String>, Option<Comparison>, Vec<f64>),
+    // The test could not be run
+    Errors(Vec<Error>),
+}
+
+// Read the tests in a manifest file
+pub fn read_manifest(manifest: &str) -> SonnyResult<Vec<GoldenTest>> {
+    let text =
+        fs::read_to_string(manifest).map_err(|_| Error::new(FileNotFound(manifest.to_string())))?;
+    let folder = Path::new(manifest)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let relative = |path: &str| folder.join(path).to_string_lossy().into_owned();
+    let mut tests = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let invalid =
+            |reason: String| Error::new(InvalidTestLine(manifest.to_string(), i + 1, reason));
+        let line = line.split('#').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words.len() < 5 {
+            return Err(invalid(format!(
+                "expected 5 columns, found {}",
+                words.len()
+            )));
+        }
+        let number = |word: &str| {
+            word.parse::<f64>()
+                .map_err(|_| invalid(format!("'{}' is not a number", word)))
+        };
+        let mut test = GoldenTest {
+            manifest: manifest.to_string(),
+            line: i + 1,
+            file: relative(words[0]),
+            chain: words[1].to_string(),
+            start: number(words[2])?,
+            end: number(words[3])?,
+            reference: relative(words[4]),
+            settings: RenderSettings::default(),
+            tolerance: 0.001,
+            spectral_tolerance: 0.01,
+        };
+        for setting in &words[5..] {
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().ok_or_else(|| {
+                invalid(format!(
+                    "expected a setting like 'key=value', found '{}'",
+                    setting
+                ))
+            })?;
+            match key {
+                "sample_rate" => test.settings.sample_rate = number(value)?,
+                "window" => test.settings.window_size = number(value)? as usize,
+                "buffer" => test.settings.buffer_size = number(value)? as usize,
+                "tolerance" => test.tolerance = number(value)?,
+                "spectral_tolerance" => test.spectral_tolerance = number(value)?,
+                _ => return Err(invalid(format!("unknown setting '{}'", key))),
+            }
+        }
+        if test.end <= test.start {
+            return Err(invalid(
+                "the end time must be after the start time".to_string(),
+            ));
+        }
+        tests.push(test);
+    }
+    Ok(tests)
+}
+
+impl GoldenTest {
+    // A short description of the test for reports
+    pub fn description(&self) -> String {
+        format!(
+            "{} {} from {} to {} s",
+            self.file, self.chain, self.start, self.end
+        )
+    }
+    // Render the test's chain
+    pub fn render(&self) -> Result<Vec<f64>, Vec<Error>> {
+        let builder = ::parse_file(&self.file)?;
+        let name = if self.chain == "out" {
+            builder.out_chain().cloned()
+        } else {
+            let name = ChainName::Scoped(self.chain.clone());
+            builder.find_chain(&name).map(|chain| chain.name.clone())
+        };
+        let name = name.ok_or_else(|| {
+            vec![Error::new(CantFindChain(ChainName::Scoped(
+                self.chain.clone(),
+            )))]
+        })?;
+        let mut errors = builder.check_backlinks(&name);
+        errors.extend(builder.check_output_shape(&name));
+        errors.retain(Error::is_fatal);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        builder
+            .render(&name, self.start, self.end, self.settings)
+            .map_err(|error| vec![error])
+    }
+    // Render the test's chain and compare it with the reference
+    pub fn run(&self) -> TestResult {
+        let actual = match self.render() {
+            Ok(actual) => actual,
+            Err(errors) => return TestResult::Errors(errors),
+        };
+        if !Path::new(&self.reference).exists() {
+            let reason = format!(
+                "there is no reference at '{}', so save one with --bless",
+                self.reference
+            );
+            return TestResult::Failed(vec![reason], None, actual);
+        }
+        let (expected, sample_rate) = match read_wav(&self.reference) {
+            Ok(reference) => reference,
+            Err(error) => return TestResult::Errors(vec![error]),
+        };
+        let mut reasons = Vec::new();
+        if sample_rate != self.settings.sample_rate {
+            reasons.push(format!(
+                "the reference has a sample rate of {}, but the test uses {}",
+                sample_rate, self.settings.sample_rate
+            ));
+        }
+        if expected.len() != actual.len() {
+            reasons.push(format!(
+                "the reference has {} samples, but the render has {}",
+                expected.len(),
+                actual.len()
+            ));
+        }
+        if !reasons.is_empty() {
+            return TestResult::Failed(reasons, None, actual);
+        }
+        let comparison = compare(&actual, &expected, sample_rate);
+        if comparison.max_difference > self.tolerance {
+            reasons.push(format!(
+                "samples differ by up to {:.6} at {:.4} s, but the tolerance is {}",
+                comparison.max_difference,
+                self.start + comparison.max_difference_time,
+                self.tolerance
+            ));
+        }
+        if comparison.spectral_difference > self.spectral_tolerance {
+            reasons.push(format!(
+                "the spectra differ by {:.6}, but the tolerance is {}",
+                comparison.spectral_difference, self.spectral_tolerance
+            ));
+        }
+        if reasons.is_empty() {
+            TestResult::Passed(comparison)
+        } else {
+            TestResult::Failed(reasons, Some(comparison), actual)
+        }
+    }
+    // Render the test's chain and save it as the reference
+    pub fn bless(&self) -> Result<(), Vec<Error>> {
+        let actual = self.render()?;
+        if let Some(folder) = Path::new(&self.reference).parent() {
+            let _ = fs::create_dir_all(folder);
+        }
+        write_wav(&self.reference, &actual, self.settings.sample_rate).map_err(|error| vec![error])
+    }
+}
+
+// Read the samples and sample rate of a 16-bit mono WAV file
+pub fn read_wav(file: &str) -> SonnyResult<(Vec<f64>, f64)> {
+    let mut reader =
+        hound::WavReader::open(file).map_err(|_| Error::new(InvalidWavFile(file.to_string())))?;
+    let spec = reader.spec();
+    if spec.channels != 1 || spec.bits_per_sample != 16 {
+        return Err(Error::new(InvalidWavFile(file.to_string())));
+    }
+    let amplitude = f64::from(i16::MAX);
+    let samples = reader
+        .samples::<i16>()
+        .map(|sample| sample.map(|sample| f64::from(sample) / amplitude))
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| Error::new(InvalidWavFile(file.to_string())))?;
+    Ok((samples, f64::from(spec.sample_rate)))
+}
+
+// Compare rendered samples with the samples of a reference. The render
+// is compared as it would be written to a WAV file.
+pub fn compare(actual: &[f64], expected: &[f64], sample_rate: f64) -> Comparison {
+    let amplitude = f64::from(i16::MAX);
+    let actual: Vec<f64> = actual
+        .iter()
+        .map(|sample| f64::from(quantize(*sample)) / amplitude)
+        .collect();
+    let mut max_difference = 0.0;
+    let mut max_difference_index = 0;
+    let mut square_sum = 0.0;
+    for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+        let difference = (a - e).abs();
+        if difference > max_difference {
+            max_difference = difference;
+            max_difference_index = i;
+        }
+        square_sum += difference * difference;
+    }
+    let len = actual.len().min(expected.len()).max(1);
+    let actual_spectrum = spectrum(&actual);
+    let expected_spectrum = spectrum(expected);
+    let norm = |spectrum: &[f64]| spectrum.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let difference: Vec<f64> = actual_spectrum
+        .iter()
+        .zip(&expected_spectrum)
+        .map(|(a, e)| a - e)
+        .collect();
+    let size = norm(&actual_spectrum).max(norm(&expected_spectrum));
+    Comparison {
+        max_difference,
+        max_difference_time: max_difference_index as f64 / sample_rate,
+        rms_difference: (square_sum / len as f64).sqrt(),
+        spectral_difference: if size > 0.0 {
+            norm(&difference) / size
+        } else {
+            0.0
+        },
+    }
+}
+
+// The average magnitude spectrum of frames of samples
+fn spectrum(samples: &[f64]) -> Vec<f64> {
+    let stft = Stft::new(FRAME_SIZE);
+    let mut total = vec![0.0; FRAME_SIZE / 2];
+    for start in (0..samples.len()).step_by(FRAME_SIZE) {
+        for (total, magnitude) in total.iter_mut().zip(stft.frame(samples, start as isize)) {
+            *total += magnitude;
+        }
+    }
+    total
+}
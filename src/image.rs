@@ -0,0 +1,365 @@
+// This module contains functions for drawing rendered audio as waveform
+// and spectrogram images, which are written as PNG or SVG files
+
+use std::{fs, path::Path};
+
+use png;
+use rayon::prelude::*;
+
+use error::*;
+use spectrum::Stft;
+
+// An RGB color
+pub type Color = [u8; 3];
+
+static BACKGROUND: Color = [16, 16, 24];
+static AXIS: Color = [64, 64, 88];
+static WAVEFORM: Color = [80, 200, 255];
+static REFERENCE: Color = [140, 140, 140];
+static CLIPPING: Color = [255, 64, 64];
+
+// The colors that spectrogram levels fade between, from quiet to loud
+static HEAT: [Color; 5] = [
+    [0, 0, 0],
+    [40, 0, 100],
+    [180, 20, 90],
+    [255, 140, 0],
+    [255, 255, 200],
+];
+
+// The number of samples in the frame behind each column of a spectrogram.
+// Longer frames tell closer frequencies apart, so low notes can be read.
+static FRAME_SIZE: usize = 2048;
+
+// How frequencies are spaced along the height of a spectrogram
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrequencyScale {
+    Linear,
+    Logarithmic,
+}
+
+// Settings used when drawing images
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSettings {
+    // The size of the image in pixels
+    pub width: usize,
+    pub height: usize,
+    pub frequency_scale: FrequencyScale,
+    // The lowest and highest frequencies in a spectrogram. By default
+    // they are 0 Hz, or 20 Hz with a logarithmic scale, and half of
+    // the sample rate.
+    pub min_frequency: Option<f64>,
+    pub max_frequency: Option<f64>,
+    // How many decibels below full scale are drawn in a spectrogram
+    pub dynamic_range: f64,
+}
+
+impl Default for ImageSettings {
+    fn default() -> ImageSettings {
+        ImageSettings {
+            width: 1200,
+            height: 400,
+            frequency_scale: FrequencyScale::Logarithmic,
+            min_frequency: None,
+            max_frequency: None,
+            dynamic_range: 90.0,
+        }
+    }
+}
+
+// An RGB image
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Color>,
+}
+
+impl Image {
+    // Create an image filled with a color
+    pub fn new(width: usize, height: usize, color: Color) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![color; width * height],
+        }
+    }
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+    pub fn set(&mut self, x: usize, y: usize, color: Color) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = color;
+        }
+    }
+    // Encode the image as a PNG file
+    pub fn to_png(&self) -> SonnyResult<Vec<u8>> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, self.width as u32, self.height as u32);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            let data: Vec<u8> = self
+                .pixels
+                .iter()
+                .flat_map(|pixel| pixel.to_vec())
+                .collect();
+            encoder
+                .write_header()
+                .and_then(|mut writer| writer.write_image_data(&data))
+                .map_err(|error| Error::new(ErrorSpec::CantEncodeImage(error.to_string())))?;
+        }
+        Ok(bytes)
+    }
+    // Encode the image as an SVG file with the image embedded in it
+    pub fn to_svg(&self) -> SonnyResult<String> {
+        Ok(format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n<image width=\"{w}\" height=\"{h}\" \
+             href=\"data:image/png;base64,{}\"/>\n</svg>\n",
+            base64(&self.to_png()?),
+            w = self.width,
+            h = self.height
+        ))
+    }
+    // Write the image to a file as an SVG if the file name ends with
+    // ".svg", or as a PNG otherwise
+    pub fn write(&self, file: &str) -> SonnyResult<()> {
+        let bytes = if is_svg(file) {
+            self.to_svg()?.into_bytes()
+        } else {
+            self.to_png()?
+        };
+        fs::write(file, bytes)
+            .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(file.to_string())))
+    }
+}
+
+fn is_svg(file: &str) -> bool {
+    Path::new(file)
+        .extension()
+        .is_some_and(|extension| extension == "svg")
+}
+
+// The smallest and largest samples drawn in each column of an image
+pub fn column_ranges(samples: &[f64], width: usize) -> Vec<Option<(f64, f64)>> {
+    (0..width)
+        .map(|x| {
+            let start = x * samples.len() / width;
+            let end = ((x + 1) * samples.len() / width).max(start + 1);
+            samples
+                .get(start..end.min(samples.len()))
+                .and_then(|column| {
+                    if column.is_empty() {
+                        return None;
+                    }
+                    let min = column.iter().cloned().fold(f64::INFINITY, f64::min);
+                    let max = column.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                    Some((min, max))
+                })
+        })
+        .collect()
+}
+
+// The row of an image that a sample is drawn at
+fn sample_row(sample: f64, height: usize) -> usize {
+    let half = height.saturating_sub(1) as f64 / 2.0;
+    (half - sample.clamp(-1.0, 1.0) * half).round() as usize
+}
+
+// Draw the waveforms of layers of samples on top of each other. Samples
+// that would clip are drawn in red.
+pub fn waveform(layers: &[(&[f64], Color)], settings: &ImageSettings) -> Image {
+    let mut image = Image::new(settings.width, settings.height, BACKGROUND);
+    let middle = sample_row(0.0, settings.height);
+    for x in 0..settings.width {
+        image.set(x, middle, AXIS);
+    }
+    for &(samples, color) in layers {
+        for (x, range) in column_ranges(samples, settings.width)
+            .into_iter()
+            .enumerate()
+        {
+            if let Some((min, max)) = range {
+                let color = if min < -1.0 || max > 1.0 {
+                    CLIPPING
+                } else {
+                    color
+                };
+                for y in sample_row(max, settings.height)..=sample_row(min, settings.height) {
+                    image.set(x, y, color);
+                }
+            }
+        }
+    }
+    image
+}
+
+// Draw the waveform of samples as a filled SVG path
+pub fn waveform_svg(samples: &[f64], settings: &ImageSettings) -> String {
+    let ranges = column_ranges(samples, settings.width);
+    let row = |sample: f64| sample_row(sample, settings.height);
+    let mut tops = Vec::new();
+    let mut bottoms = Vec::new();
+    for (x, range) in ranges.into_iter().enumerate() {
+        if let Some((min, max)) = range {
+            tops.push(format!("{},{}", x, row(max)));
+            bottoms.push(format!("{},{}", x, row(min) + 1));
+        }
+    }
+    bottoms.reverse();
+    let hex = |color: Color| format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2]);
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\">\n<rect width=\"{w}\" height=\"{h}\" fill=\"{}\"/>\n\
+         <line x1=\"0\" y1=\"{m}\" x2=\"{w}\" y2=\"{m}\" stroke=\"{}\"/>\n\
+         <path d=\"M{}Z\" fill=\"{}\"/>\n</svg>\n",
+        hex(BACKGROUND),
+        hex(AXIS),
+        tops.into_iter()
+            .chain(bottoms)
+            .collect::<Vec<_>>()
+            .join(" L"),
+        hex(WAVEFORM),
+        w = settings.width,
+        h = settings.height,
+        m = row(0.0)
+    )
+}
+
+// Draw the waveform of a render over the waveform of its reference
+pub fn comparison(actual: &[f64], expected: &[f64], settings: &ImageSettings) -> Image {
+    waveform(&[(expected, REFERENCE), (actual, WAVEFORM)], settings)
+}
+
+// The magnitude spectra of frames of samples centered on each column of
+// an image, in decibels relative to a full scale sine wave
+fn stft(samples: &[f64], width: usize) -> Vec<Vec<f64>> {
+    let stft = Stft::new(FRAME_SIZE);
+    let full_scale = FRAME_SIZE as f64 / 4.0;
+    (0..width)
+        .into_par_iter()
+        .map(|x| {
+            let center = ((x as f64 + 0.5) / width as f64 * samples.len() as f64) as isize;
+            stft.frame(samples, center - FRAME_SIZE as isize / 2)
+                .into_iter()
+                .map(|magnitude| 20.0 * (magnitude / full_scale).max(1e-12).log10())
+                .collect()
+        })
+        .collect()
+}
+
+// The color of a level between 0 and 1
+fn heat(level: f64) -> Color {
+    let position = level.clamp(0.0, 1.0) * (HEAT.len() - 1) as f64;
+    let i = (position as usize).min(HEAT.len() - 2);
+    let fraction = position - i as f64;
+    let mut color = [0; 3];
+    for (c, channel) in color.iter_mut().enumerate() {
+        let (a, b) = (f64::from(HEAT[i][c]), f64::from(HEAT[i + 1][c]));
+        *channel = (a + (b - a) * fraction).round() as u8;
+    }
+    color
+}
+
+// Draw a spectrogram of samples, with time going right and frequency
+// going up
+pub fn spectrogram(samples: &[f64], sample_rate: f64, settings: &ImageSettings) -> Image {
+    let nyquist = sample_rate / 2.0;
+    let max = settings.max_frequency.unwrap_or(nyquist).min(nyquist);
+    let min = settings
+        .min_frequency
+        .unwrap_or(match settings.frequency_scale {
+            FrequencyScale::Linear => 0.0,
+            FrequencyScale::Logarithmic => 20.0,
+        })
+        .max(match settings.frequency_scale {
+            FrequencyScale::Linear => 0.0,
+            FrequencyScale::Logarithmic => 1.0,
+        })
+        .min(max);
+    // The frequency at a fraction of the height from the bottom
+    let frequency = |fraction: f64| match settings.frequency_scale {
+        FrequencyScale::Linear => min + (max - min) * fraction,
+        FrequencyScale::Logarithmic => min * (max / min).powf(fraction),
+    };
+    // The bins that each row covers
+    let height = settings.height;
+    let bin = |frequency: f64| frequency / sample_rate * FRAME_SIZE as f64;
+    let rows: Vec<(f64, f64)> = (0..height)
+        .map(|y| {
+            let from_bottom = (height - 1 - y) as f64;
+            (
+                bin(frequency(from_bottom / height as f64)),
+                bin(frequency((from_bottom + 1.0) / height as f64)),
+            )
+        })
+        .collect();
+    let mut image = Image::new(settings.width, height, BACKGROUND);
+    for (x, spectrum) in stft(samples, settings.width).into_iter().enumerate() {
+        let last = spectrum.len() - 1;
+        for (y, &(low, high)) in rows.iter().enumerate() {
+            // Rows that cover whole bins show the loudest of them. Rows
+            // within a bin are interpolated between bins.
+            let (first, end) = (low.ceil() as usize, (high.floor() as usize).min(last));
+            let level = if first <= end {
+                spectrum[first..=end]
+                    .iter()
+                    .cloned()
+                    .fold(f64::NEG_INFINITY, f64::max)
+            } else {
+                let center = ((low + high) / 2.0).min(last as f64);
+                let i = center.floor() as usize;
+                let j = (i + 1).min(last);
+                let fraction = center - i as f64;
+                spectrum[i] + (spectrum[j] - spectrum[i]) * fraction
+            };
+            image.set(
+                x,
+                y,
+                heat(1.0 + level / settings.dynamic_range.max(f64::EPSILON)),
+            );
+        }
+    }
+    image
+}
+
+// Encode bytes as base64
+fn base64(bytes: &[u8]) -> String {
+    static ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(chunk.get(1).cloned().unwrap_or(0)) << 8)
+            | u32::from(chunk.get(2).cloned().unwrap_or(0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 63]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Write the waveform of samples to a file. SVG waveforms are drawn as
+// paths instead of being embedded as images.
+pub fn write_waveform(file: &str, samples: &[f64], settings: &ImageSettings) -> SonnyResult<()> {
+    if is_svg(file) {
+        fs::write(file, waveform_svg(samples, settings))
+            .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(file.to_string())))
+    } else {
+        waveform(&[(samples, WAVEFORM)], settings).write(file)
+    }
+}
+
+// Write a spectrogram of samples to a file
+pub fn write_spectrogram(
+    file: &str,
+    samples: &[f64],
+    sample_rate: f64,
+    settings: &ImageSettings,
+) -> SonnyResult<()> {
+    spectrogram(samples, sample_rate, settings).write(file)
+}
@@ -1,42 +1,105 @@
-use std::{fmt, fs::File, io::Read, path::PathBuf};
+use std::{fmt, fs, path::PathBuf, sync::Arc};
 
 use error::*;
 
-static KEYWORDS: &[&str] = &[
-    "time",
-    "sin",
-    "cos",
-    "ceil",
-    "floor",
-    "abs",
-    "min",
-    "max",
-    "log",
-    "avg",
-    "out",
-    "w",
-    "h",
-    "q",
-    "e",
-    "s",
-    "ts",
-    "tempo",
-    "include",
-    "std",
-    "use",
-    "fft",
-    "window_size",
-    "buffer_size",
-    "sample_rate",
-    "wi",
-    "window",
-    "debug",
-    "print",
-    "cat",
-    "len",
-    "find",
+// How a keyword is used, which decides where it is a keyword and how
+// the formatter lays it out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeywordKind {
+    // A value on its own, like "time"
+    Value,
+    // A note duration, like "q"
+    Duration,
+    // The start of a top-level item that is not a chain, like "std"
+    Item,
+    // A function of the term after it, like "sin"
+    Prefix,
+    // An operator between two terms, like "min"
+    Binary,
+    // A function with a parenthesized list of arguments, like "clamp"
+    Function,
+    // A function whose arguments can be durations, like "swing"
+    DurationFunction,
+}
+
+use self::KeywordKind::*;
+
+pub static KEYWORDS: &[(&str, KeywordKind)] = &[
+    ("time", Value),
+    ("sin", Prefix),
+    ("cos", Prefix),
+    ("tan", Prefix),
+    ("asin", Prefix),
+    ("acos", Prefix),
+    ("atan", Prefix),
+    ("atan2", Function),
+    ("exp", Prefix),
+    ("sqrt", Prefix),
+    ("sign", Prefix),
+    ("round", Prefix),
+    ("fract", Prefix),
+    ("clamp", Function),
+    ("lerp", Function),
+    ("dbtoa", Prefix),
+    ("atodb", Prefix),
+    ("mtof", Prefix),
+    ("ftom", Prefix),
+    ("ceil", Prefix),
+    ("floor", Prefix),
+    ("abs", Prefix),
+    ("min", Binary),
+    ("max", Binary),
+    ("log", Prefix),
+    ("avg", Prefix),
+    ("out", Value),
+    ("w", Duration),
+    ("h", Duration),
+    ("q", Duration),
+    ("e", Duration),
+    ("s", Duration),
+    ("ts", Duration),
+    ("tempo", Item),
+    ("include", Item),
+    ("std", Item),
+    ("use", Item),
+    ("fft", Prefix),
+    ("window_size", Value),
+    ("buffer_size", Value),
+    ("sample_rate", Value),
+    ("wi", Value),
+    ("window", Prefix),
+    ("debug", Prefix),
+    ("print", Prefix),
+    ("assert", Prefix),
+    ("test", Item),
+    ("cat", Binary),
+    ("len", Prefix),
+    ("find", Binary),
+    ("swing", DurationFunction),
+    ("humanize", DurationFunction),
+    ("euclid", DurationFunction),
+    ("steps", DurationFunction),
+    ("true", Value),
+    ("false", Value),
+];
+
+// Keywords that were added after files could already have chains with
+// their names. They are lexed as ids, and the parser decides whether
+// they are used as keywords, so that those chains keep working.
+pub static CONTEXTUAL_KEYWORDS: &[&str] = &[
+    "tan", "asin", "acos", "atan", "atan2", "exp", "sqrt", "sign", "round", "fract", "clamp",
+    "lerp", "dbtoa", "atodb", "mtof", "ftom", "assert", "test", "swing", "humanize", "euclid",
+    "steps", "true", "false",
 ];
 
+// The kind of a keyword, or None if the word is not a keyword
+pub fn keyword_kind(word: &str) -> Option<KeywordKind> {
+    KEYWORDS
+        .iter()
+        .find(|&&(keyword, _)| keyword == word)
+        .map(|&(_, kind)| kind)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenType {
     Operator,
@@ -98,11 +161,59 @@ impl fmt::Display for Token {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct CodeLocation {
     pub line: usize,
     pub column: usize,
     pub file: String,
+    // The path of the file as it was given to the lexer. Files in different
+    // folders can have the same name, so this tells them apart.
+    pub path: Arc<str>,
+    // The bytes of the source that the location covers
+    pub span: Span,
+    // The source code of the file, so that errors can show the code around the location
+    pub source: Arc<str>,
+}
+
+impl CodeLocation {
+    // Extend the location so that it covers everything up to the end of another one
+    pub fn to(&self, end: &CodeLocation) -> CodeLocation {
+        let mut loc = self.clone();
+        loc.span.end = loc.span.end.max(end.span.end);
+        loc
+    }
+    // The text of the line the location starts on
+    pub fn line_text(&self) -> &str {
+        self.source.lines().nth(self.line - 1).unwrap_or("")
+    }
+    // The line and column just past the end of the location
+    pub fn end_line_column(&self) -> (usize, usize) {
+        let before = self.source.get(..self.span.end).unwrap_or("");
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        (line, before[line_start..].chars().count() + 1)
+    }
+    // The number of characters of the location's first line that it covers,
+    // which is always at least 1
+    pub fn width(&self) -> usize {
+        self.source
+            .get(self.span.start..self.span.end)
+            .map(|text| text.chars().take_while(|&c| c != '\n').count())
+            .unwrap_or(0)
+            .max(1)
+    }
+}
+
+// Locations are debug printed without their source
+impl fmt::Debug for CodeLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CodeLocation")
+            .field("line", &self.line)
+            .field("column", &self.column)
+            .field("file", &self.file)
+            .field("span", &self.span)
+            .finish()
+    }
 }
 
 impl fmt::Display for CodeLocation {
@@ -111,285 +222,309 @@ impl fmt::Display for CodeLocation {
     }
 }
 
+// A range of bytes in a source file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug)]
 pub struct Lexer {
+    // The location of the last character read
     loc: CodeLocation,
-    was_put_back: bool,
-    c: [u8; 1],
-    file: File,
+    // The source code being lexed
+    source: Arc<str>,
+    // The byte position of the next character to be read
+    pos: usize,
+    // The location of the last token lexed
+    token_loc: CodeLocation,
+}
+
+// The name that code locations in a file have, which is the file's name
+// without the folders it is in
+pub fn location_file(file: &str) -> String {
+    PathBuf::from(file)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(file)
+        .to_string()
 }
 
 impl Lexer {
+    // Creates a new Lexer which reads from the given file
     pub fn new(file: &str) -> SonnyResult<Lexer> {
-        Ok(Lexer {
-            loc: CodeLocation {
-                line: 1,
-                column: 0,
-                file: PathBuf::from(file)
-                    .file_name()
-                    .expect("unable to get file name")
-                    .to_str()
-                    .expect("unable to convert file name to str")
-                    .to_string(),
-            },
-            was_put_back: false,
-            c: [0],
-            file: if let Ok(f) = File::open(file) {
-                f
-            } else {
-                return Err(Error::new(ErrorSpec::FileNotFound(file.to_string())));
+        match fs::read(file) {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(source) => Ok(Lexer::from_source(file, &source)),
+                Err(_) => Err(Error::new(ErrorSpec::InvalidEncoding(file.to_string()))),
             },
-        })
+            Err(_) => Err(Error::new(ErrorSpec::FileNotFound(file.to_string()))),
+        }
+    }
+    // Creates a new Lexer which reads from the given source code. The
+    // file name is used for error messages.
+    pub fn from_source(file: &str, source: &str) -> Lexer {
+        let loc = CodeLocation {
+            line: 1,
+            column: 0,
+            file: location_file(file),
+            path: Arc::from(file),
+            span: Span::default(),
+            source: Arc::from(source),
+        };
+        Lexer {
+            source: loc.source.clone(),
+            token_loc: loc.clone(),
+            loc,
+            pos: 0,
+        }
     }
+    // The location of the last character read
     pub fn loc(&self) -> CodeLocation {
-        self.loc.clone()
+        let mut loc = self.loc.clone();
+        loc.span = Span {
+            start: self.pos,
+            end: self.pos,
+        };
+        loc
+    }
+    // The source code being lexed
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    // The span of the last token lexed
+    pub fn span(&self) -> Span {
+        self.token_loc.span
+    }
+    // The location of the last token lexed
+    pub fn token_loc(&self) -> CodeLocation {
+        self.token_loc.clone()
+    }
+    // Look at the next character without consuming it
+    fn peek_char(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
     }
     fn get_char(&mut self) -> Option<char> {
-        if self.was_put_back {
-            self.was_put_back = false;
-            Some(self.c[0] as char)
-        } else if self.file.read_exact(&mut self.c).is_ok() {
-            self.loc.column += 1;
-            Some(self.c[0] as char)
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.loc.line += 1;
+            self.loc.column = 0;
         } else {
-            None
+            self.loc.column += 1;
         }
+        Some(c)
     }
-    fn put_back(&mut self) {
-        self.was_put_back = true;
+    // Consume the next character if it is the given one
+    fn get_if(&mut self, c: char) -> bool {
+        if self.peek_char() == Some(c) {
+            self.get_char();
+            true
+        } else {
+            false
+        }
     }
-    #[allow(unknown_lints)]
-    #[allow(cyclomatic_complexity)]
+    // Lex the next token and keep track of its location
     pub fn lex(&mut self) -> Token {
-        // Begin reading a token
-        while let Some(c) = self.get_char() {
-            let mut token = String::new();
-
-            // Check for tokens that start with alpha or us
-            if c.is_alphabetic() || c == '_' {
-                token.push(c);
-                // Check for ids, keywords, built_ins and notes
-                while let Some(c) = self.get_char() {
-                    if c.is_alphanumeric() || c == '_' || c == '#' {
-                        token.push(c);
-                    } else {
-                        self.put_back();
-                        // Check for pi
-                        if token == "pi" {
-                            return Token(Num, token);
-                        }
-                        // Check for keywords
-                        if KEYWORDS.iter().any(|&k| k == token) {
-                            return Token(Keyword, token);
+        self.skip_whitespace_and_comments();
+        let mut token_loc = self.loc();
+        token_loc.column += 1;
+        let token = self.lex_token();
+        token_loc.span.end = self.pos;
+        self.token_loc = token_loc;
+        token
+    }
+    // Skip past any whitespace and comments
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(c) = self.peek_char() {
+            if c.is_whitespace() {
+                self.get_char();
+            } else if c == '#' {
+                self.get_char();
+                if self.get_if('/') {
+                    // Block comments end with "/#"
+                    while let Some(c) = self.get_char() {
+                        if c == '/' && self.get_if('#') {
+                            break;
                         }
-                        // Check for notes
-                        let bytes: Vec<u8> = token.chars().map(|cc| cc as u8).collect();
-                        let mut i = 0;
-                        if bytes[i] >= b'A' && bytes[i] <= b'G' {
-                            if bytes.len() == 1 {
-                                return Token(NoteString, token);
-                            } else {
-                                i += 1;
-                                if bytes[i] == b'b' || bytes[i] == b'#' {
-                                    if bytes.len() == 2 {
-                                        return Token(NoteString, token);
-                                    }
-                                    i += 1;
-                                }
-                                while i < bytes.len() {
-                                    if !(bytes[i] as char).is_digit(10) {
-                                        return Token(Id, token);
-                                    }
-                                    i += 1;
-                                }
-                                return Token(NoteString, token);
-                            }
+                    }
+                } else {
+                    // Line comments end with a newline
+                    while let Some(c) = self.get_char() {
+                        if c == '\n' {
+                            break;
                         }
-
-                        return Token(Id, token);
                     }
                 }
+            } else {
+                break;
             }
-            // Check for valid num tokens
-            else if c.is_digit(10) {
-                token.push(c);
-                while let Some(c) = self.get_char() {
-                    if c.is_digit(10) {
-                        token.push(c);
-                    } else {
-                        self.put_back();
-                        return Token(Num, token);
-                    }
+        }
+    }
+    // Determine whether a contextual keyword that was just lexed is used
+    // as a keyword, which depends on the code around it
+    // Determine whether an id-like token is a pitch, like "C#4" or "Bb"
+    fn is_note(token: &str) -> bool {
+        let mut chars = token.chars().peekable();
+        match chars.next() {
+            Some('A'..='G') => (),
+            _ => return false,
+        }
+        if let Some(&c) = chars.peek() {
+            if c == 'b' || c == '#' {
+                chars.next();
+            }
+        }
+        chars.all(|c| c.is_ascii_digit())
+    }
+    fn lex_token(&mut self) -> Token {
+        let c = if let Some(c) = self.get_char() {
+            c
+        } else {
+            return Token(Done, String::new());
+        };
+        let mut token = c.to_string();
+
+        // Check for tokens that start with alpha or us
+        if c.is_alphabetic() || c == '_' {
+            // Check for ids, keywords, built_ins and notes
+            while let Some(c) = self.peek_char() {
+                if c.is_alphanumeric() || c == '_' || c == '#' {
+                    token.push(c);
+                    self.get_char();
+                } else {
+                    break;
                 }
             }
-            // Check for newlines
-            else if c.is_whitespace() {
-                if c == '\n' {
-                    self.loc.line += 1;
-                    self.loc.column = 0;
+            // Check for pi
+            if token == "pi" {
+                Token(Num, token)
+            }
+            // Check for keywords
+            else if keyword_kind(&token).is_some()
+                && !CONTEXTUAL_KEYWORDS.contains(&token.as_str())
+            {
+                Token(Keyword, token)
+            }
+            // Check for notes
+            else if Lexer::is_note(&token) {
+                Token(NoteString, token)
+            } else {
+                Token(Id, token)
+            }
+        }
+        // Check for valid num tokens
+        else if c.is_ascii_digit() {
+            while let Some(c) = self.peek_char() {
+                if c.is_ascii_digit() {
+                    token.push(c);
+                    self.get_char();
+                } else {
+                    break;
                 }
             }
-            // Check for operators
-            else {
-                token.push(c);
-                match c {
-                    '(' | ')' | '{' | '}' | '[' | ']' | ',' => return Token(Delimeter, token),
-                    '|' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '|' {
-                                token.push(c);
-                                return Token(Operator, token);
-                            } else {
-                                self.put_back();
-                                return Token(Delimeter, token);
-                            }
-                        }
-                    }
-                    '&' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '&' {
-                                token.push(c);
-                                return Token(Operator, token);
-                            } else {
-                                self.put_back();
-                                return Token(Unknown, token);
-                            }
-                        }
+            Token(Num, token)
+        }
+        // Check for operators and delimeters
+        else {
+            match c {
+                '(' | ')' | '{' | '}' | '[' | ']' | ',' => Token(Delimeter, token),
+                '|' => {
+                    if self.get_if('|') {
+                        token.push('|');
+                        Token(Operator, token)
+                    } else {
+                        Token(Delimeter, token)
                     }
-                    ':' => {
-                        if let Some(c) = self.get_char() {
-                            if c == ':' {
-                                token.push(c);
-                            } else {
-                                self.put_back();
-                            }
-                            return Token(Delimeter, token);
-                        }
+                }
+                '&' => {
+                    if self.get_if('&') {
+                        token.push('&');
+                        Token(Operator, token)
+                    } else {
+                        Token(Unknown, token)
                     }
-                    '=' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '=' {
-                                token.push(c);
-                                return Token(Operator, token);
-                            } else {
-                                self.put_back();
-                                return Token(Unknown, token);
-                            }
-                        }
+                }
+                ':' => {
+                    if self.get_if(':') {
+                        token.push(':');
                     }
-                    '!' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '=' {
-                                token.push(c);
-                                return Token(Operator, token);
-                            } else {
-                                self.put_back();
-                                return Token(BackLink, token);
-                            }
-                        }
+                    Token(Delimeter, token)
+                }
+                '=' => {
+                    if self.get_if('=') {
+                        token.push('=');
+                        Token(Operator, token)
+                    } else {
+                        Token(Unknown, token)
                     }
-                    '<' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '=' {
-                                token.push(c);
-                                return Token(Operator, token);
-                            } else {
-                                self.put_back();
-                                return Token(Operator, token);
-                            }
-                        }
+                }
+                '!' => {
+                    if self.get_if('=') {
+                        token.push('=');
+                        Token(Operator, token)
+                    } else {
+                        Token(BackLink, token)
                     }
-                    '>' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '=' {
-                                token.push(c);
-                                return Token(Operator, token);
-                            } else {
-                                self.put_back();
-                                return Token(Operator, token);
-                            }
-                        }
+                }
+                '<' | '>' => {
+                    if self.get_if('=') {
+                        token.push('=');
                     }
-                    '.' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '.' {
-                                token.push(c);
-                                return Token(Dot, token);
-                            } else {
-                                self.put_back();
-                                return Token(Dot, token);
-                            }
-                        }
+                    Token(Operator, token)
+                }
+                '.' => {
+                    if self.get_if('.') {
+                        token.push('.');
                     }
-                    '_' => return Token(Rest, token),
-                    '+' | '*' | '%' | '^' | '?' | '/' | '~' => return Token(Operator, token),
-                    '-' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '>' {
-                                token.push(c);
-                                return Token(Delimeter, token);
-                            } else {
-                                self.put_back();
-                                return Token(Operator, token);
-                            }
-                        }
+                    Token(Dot, token)
+                }
+                '+' | '*' | '%' | '^' | '?' | '/' | '~' => Token(Operator, token),
+                '-' => {
+                    if self.get_if('>') {
+                        token.push('>');
+                        Token(Delimeter, token)
+                    } else {
+                        Token(Operator, token)
                     }
-                    '#' => {
-                        if let Some(c) = self.get_char() {
-                            if c == '/' {
-                                while let Some(c) = self.get_char() {
-                                    if c == '\n' {
-                                        self.loc.line += 1;
-                                        self.loc.column = 0;
-                                    } else if c == '/' {
-                                        if let Some(c) = self.get_char() {
-                                            if c == '#' {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }
-                            } else {
-                                while let Some(c) = self.get_char() {
-                                    if c == '\n' {
-                                        self.loc.line += 1;
-                                        self.loc.column = 0;
-                                        break;
-                                    }
+                }
+                '"' => {
+                    token = String::new();
+                    while let Some(c) = self.get_char() {
+                        match c {
+                            '"' => break,
+                            // Escaped characters are taken literally
+                            '\\' => {
+                                if let Some(c) = self.get_char() {
+                                    token.push(c);
                                 }
                             }
-                        }
-                    }
-                    '"' => {
-                        token = String::new();
-                        let mut escape = false;
-                        while let Some(c) = self.get_char() {
-                            if c == '"' && !escape {
-                                break;
-                            }
-
-                            if c == '\\' && !escape {
-                                escape = true;
-                            } else if escape {
-                                escape = false;
-                            } else {
+                            // Strings cannot span multiple lines
+                            '\n' => {
                                 token.push(c);
-                                if c == '\n' {
-                                    self.loc.line += 1;
-                                    self.loc.column = 0;
-                                    break;
-                                }
+                                break;
                             }
+                            _ => token.push(c),
                         }
-                        return Token(StringLiteral, token);
-                    }
-                    _ => {
-                        token.push(c);
-                        return Token(Unknown, token);
                     }
+                    Token(StringLiteral, token)
                 }
+                _ => Token(Unknown, token),
             }
         }
-        Token(Done, String::new())
+    }
+}
+
+// Iterating over a Lexer yields every token and its span until the end of the source
+impl Iterator for Lexer {
+    type Item = (Token, Span);
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let token = self.lex();
+        if token.0 == Done {
+            None
+        } else {
+            Some((token, self.span()))
+        }
     }
 }
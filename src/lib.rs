@@ -0,0 +1,51 @@
+// Sonny is a functional programming language for creating music and
+// other sounds. This library parses Sonny source code into chains and
+// renders them into audio.
+
+extern crate colored;
+extern crate either;
+extern crate find_folder;
+extern crate hound;
+extern crate png;
+extern crate rayon;
+extern crate rustfft;
+#[macro_use]
+extern crate serde_json;
+
+pub mod builder;
+pub mod error;
+pub mod format;
+pub mod golden;
+pub mod image;
+pub mod lexer;
+pub mod loudness;
+pub mod meter;
+pub mod parser;
+pub mod render;
+pub mod repl;
+pub mod spectrum;
+
+pub use builder::{
+    trace::{samples_to_csv, TraceMode},
+    variable::Variable,
+    Builder, Chain, ChainLinks, ChainName,
+};
+pub use error::{Error, ErrorSpec, SonnyResult};
+pub use format::format_source;
+pub use parser::Parser;
+pub use render::{write_wav, RenderSettings};
+pub use repl::Repl;
+
+// Parse a file and all of the files it includes. Warnings are kept in the
+// builder's diagnostics. If there are any errors, all of them are returned.
+pub fn parse_file(file: &str) -> Result<Builder, Vec<Error>> {
+    Parser::new(file, Builder::new())
+        .map_err(|error| vec![error])?
+        .parse(false)
+}
+
+// Parse source code. The file name is used to name the source's top-level
+// chain, in error messages, and to find files included by the source.
+pub fn parse_source(file: &str, source: &str) -> Result<Builder, Vec<Error>> {
+    Parser::from_source(file, source, Builder::new()).parse(false)
+}
@@ -0,0 +1,203 @@
+// This module contains loudness analysis of rendered audio, which follows
+// ITU-R BS.1770 and EBU R128, and normalization to a target loudness
+
+use std::f64::consts::PI;
+
+use rayon::prelude::*;
+
+use meter::{decibels, Levels};
+
+// The number of samples that each true peak sample is interpolated from on
+// each side
+static TRUE_PEAK_TAPS: isize = 8;
+// How many times the audio is oversampled to find the true peak
+static OVERSAMPLING: usize = 4;
+
+// The length of blocks of audio that loudness is measured in, in seconds,
+// and how far apart they start
+static BLOCK_LENGTH: f64 = 0.4;
+static BLOCK_STEP: f64 = 0.1;
+// Blocks quieter than this are not counted in integrated loudness
+static ABSOLUTE_GATE: f64 = -70.0;
+// Blocks quieter than this relative to the other blocks are not counted
+static RELATIVE_GATE: f64 = -10.0;
+
+// How loud some audio is
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness {
+    // The peak and RMS levels in dBFS
+    pub peak: f64,
+    pub rms: f64,
+    // The peak of the audio between samples, as it would be after being
+    // converted to analog, in dBTP
+    pub true_peak: f64,
+    // The integrated loudness in LUFS. This is negative infinity if the
+    // audio is too short or too quiet to measure.
+    pub integrated: f64,
+}
+
+// Analyze the loudness of samples
+pub fn analyze(samples: &[f64], sample_rate: f64) -> Loudness {
+    let levels = Levels::of(samples);
+    Loudness {
+        peak: decibels(levels.peak),
+        rms: decibels(levels.rms),
+        true_peak: decibels(true_peak(samples).max(levels.peak)),
+        integrated: integrated_loudness(samples, sample_rate),
+    }
+}
+
+// The gain in decibels that brings audio to a target peak in dBTP, a
+// target integrated loudness in LUFS, or the loudness without going over
+// the peak if both are given. There is no gain if the audio is silent.
+pub fn normalization_gain(
+    loudness: &Loudness,
+    target_peak: Option<f64>,
+    target_loudness: Option<f64>,
+) -> Option<f64> {
+    let peak_gain = target_peak
+        .map(|target| target - loudness.true_peak)
+        .filter(|gain| gain.is_finite());
+    let loudness_gain = target_loudness
+        .map(|target| target - loudness.integrated)
+        .filter(|gain| gain.is_finite());
+    match (peak_gain, loudness_gain) {
+        (Some(peak), Some(loudness)) => Some(peak.min(loudness)),
+        (peak, loudness) => peak.or(loudness),
+    }
+}
+
+// Apply a gain in decibels to samples
+pub fn apply_gain(samples: &mut [f64], gain: f64) {
+    let factor = 10f64.powf(gain / 20.0);
+    samples.par_iter_mut().for_each(|sample| *sample *= factor);
+}
+
+// The largest absolute value of samples interpolated between samples
+fn true_peak(samples: &[f64]) -> f64 {
+    // The interpolation filter for each position between samples, which
+    // is a sinc function faded out with a Hann window
+    let filters: Vec<Vec<f64>> = (1..OVERSAMPLING)
+        .map(|phase| {
+            let offset = phase as f64 / OVERSAMPLING as f64;
+            (-TRUE_PEAK_TAPS + 1..=TRUE_PEAK_TAPS)
+                .map(|k| {
+                    let x = offset - k as f64;
+                    let sinc = (PI * x).sin() / (PI * x);
+                    let window = 0.5 + 0.5 * (PI * x / TRUE_PEAK_TAPS as f64).cos();
+                    sinc * window
+                })
+                .collect()
+        })
+        .collect();
+    (0..samples.len())
+        .into_par_iter()
+        .map(|i| {
+            filters
+                .iter()
+                .map(|filter| {
+                    let value: f64 = (-TRUE_PEAK_TAPS + 1..=TRUE_PEAK_TAPS)
+                        .zip(filter)
+                        .map(|(k, weight)| {
+                            let j = i as isize + k;
+                            if j >= 0 {
+                                samples.get(j as usize).cloned().unwrap_or(0.0) * weight
+                            } else {
+                                0.0
+                            }
+                        })
+                        .sum();
+                    value.abs()
+                })
+                .fold(0.0, f64::max)
+        })
+        .reduce(|| 0.0, f64::max)
+}
+
+// A second order filter
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+}
+
+impl Biquad {
+    fn filter(&self, samples: &[f64]) -> Vec<f64> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        samples
+            .iter()
+            .map(|&x| {
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[1] * y1
+                    - self.a[2] * y2;
+                x2 = x1;
+                x1 = x;
+                y2 = y1;
+                y1 = y;
+                y
+            })
+            .collect()
+    }
+}
+
+// The filters that weight frequencies like hearing does, which are a high
+// shelf followed by a high pass. The coefficients are found for any
+// sample rate from the analog filters that BS.1770 is based on.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let k = (PI * 1681.974450955533 / sample_rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+    let k = (PI * 38.13547087602444 / sample_rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+    [shelf, high_pass]
+}
+
+// The loudness of a mean square of K-weighted samples
+fn block_loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+// The gated loudness of samples over their whole length in LUFS
+fn integrated_loudness(samples: &[f64], sample_rate: f64) -> f64 {
+    let [shelf, high_pass] = k_weighting(sample_rate);
+    let weighted = high_pass.filter(&shelf.filter(samples));
+    let block_length = (BLOCK_LENGTH * sample_rate).round() as usize;
+    let step = ((BLOCK_STEP * sample_rate).round() as usize).max(1);
+    if block_length == 0 || weighted.len() < block_length {
+        return f64::NEG_INFINITY;
+    }
+    let blocks: Vec<f64> = (0..=(weighted.len() - block_length) / step)
+        .map(|i| {
+            let block = &weighted[i * step..i * step + block_length];
+            block.iter().map(|x| x * x).sum::<f64>() / block_length as f64
+        })
+        .filter(|&mean_square| block_loudness(mean_square) > ABSOLUTE_GATE)
+        .collect();
+    if blocks.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let gate = block_loudness(mean(&blocks)) + RELATIVE_GATE;
+    let gated: Vec<f64> = blocks
+        .into_iter()
+        .filter(|&mean_square| block_loudness(mean_square) > gate)
+        .collect();
+    if gated.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    block_loudness(mean(&gated))
+}
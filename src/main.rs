@@ -1,28 +1,62 @@
 extern crate colored;
-extern crate either;
-extern crate find_folder;
-extern crate hound;
 extern crate open;
-extern crate rayon;
-extern crate rustfft;
-
-mod builder;
-mod error;
-mod lexer;
-mod parser;
+extern crate sonny;
 
 use std::{
     collections::VecDeque,
-    env, f64, i16,
-    io::{stdout, Write},
-    time::Instant,
+    env, f64, fs,
+    io::{stdin, stdout, BufRead, Write},
+    mem, process, thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use colored::*;
 
-use builder::*;
-use error::*;
-use parser::*;
+use sonny::{
+    error::ErrorSeverity,
+    image::{FrequencyScale, ImageSettings},
+    meter::{clips, decibels, Levels, Meter},
+    *,
+};
+
+// How values are traced and time is measured while a file is rendered
+struct RenderOptions {
+    mode: TraceMode,
+    // The chain whose values are written to a CSV file
+    chain: Option<String>,
+    profile: Profile,
+    // Whether levels and a waveform are drawn below the progress bar
+    meter: bool,
+    // Whether the loudness of the audio is reported
+    loudness: bool,
+    // The peak in dBTP and the loudness in LUFS to normalize the audio to
+    normalize_peak: Option<f64>,
+    normalize_loudness: Option<f64>,
+    json: bool,
+}
+
+// The size of the waveform drawn by the level meter
+static METER_WIDTH: usize = 64;
+static METER_HEIGHT: usize = 8;
+
+// Whether the time spent in each chain is measured and how it is shown
+#[derive(Clone, Copy, PartialEq)]
+enum Profile {
+    Off,
+    // Print a table of the slowest chains and operations
+    Report,
+    // Write the stacks of chains to a file for flamegraph tools
+    Folded,
+}
+
+// Which images of a chain are drawn and how
+struct ImageOptions {
+    settings: ImageSettings,
+    waveform: bool,
+    spectrogram: bool,
+    // The extension of the image files, which sets their format
+    extension: &'static str,
+}
 
 fn main() {
     let mut args = env::args();
@@ -30,10 +64,32 @@ fn main() {
     let mut sample_rate = 32000.0;
     let mut window_size = 4000;
     let mut buffer_size = 10;
-    let mut file_name = None;
+    let mut file_names = Vec::new();
     let mut start_time = 0f64;
     let mut end_time = None;
     let mut play = false;
+    let mut json_errors = false;
+    let mut repl = false;
+    let mut watch = false;
+    let mut format = false;
+    let mut fmt_check = false;
+    let mut lint_check = false;
+    let mut test = false;
+    let mut bless = false;
+    let mut trace_mode = TraceMode::default();
+    let mut trace_chain = None;
+    let mut profile = Profile::Off;
+    let mut meter = false;
+    let mut loudness = false;
+    let mut normalize_peak = None;
+    let mut normalize_loudness = None;
+    let mut image = false;
+    let mut image_options = ImageOptions {
+        settings: ImageSettings::default(),
+        waveform: false,
+        spectrogram: false,
+        extension: "png",
+    };
     // Parse command args for input and flags
     while let Some(ref arg) = args.next() {
         match arg.to_string().as_ref() {
@@ -87,12 +143,103 @@ fn main() {
                     }
                 }
             }
+            "--debug-every" => {
+                if let Some(ref d_str) = args.next() {
+                    if let Ok(d) = d_str.parse() {
+                        trace_mode = TraceMode::Every(d);
+                    } else {
+                        println!("Invalid debug period.");
+                        return;
+                    }
+                }
+            }
+            "--debug-on-change" => trace_mode = TraceMode::OnChange,
+            "--trace" => {
+                if let Some(chain) = args.next() {
+                    trace_chain = Some(chain);
+                } else {
+                    println!("Invalid trace chain.");
+                    return;
+                }
+            }
+            "--width" | "--height" => {
+                // Images must be at least one pixel in each direction
+                if let Some(size) = args
+                    .next()
+                    .and_then(|s_str| s_str.parse().ok())
+                    .filter(|&size| size >= 1)
+                {
+                    if arg == "--width" {
+                        image_options.settings.width = size;
+                    } else {
+                        image_options.settings.height = size;
+                    }
+                } else {
+                    println!("Invalid image size.");
+                    return;
+                }
+            }
+            "--frequency-scale" => match args.next().as_deref() {
+                Some("linear") => image_options.settings.frequency_scale = FrequencyScale::Linear,
+                Some("log") => image_options.settings.frequency_scale = FrequencyScale::Logarithmic,
+                _ => {
+                    println!("Invalid frequency scale. Use \"linear\" or \"log\".");
+                    return;
+                }
+            },
+            "--min-frequency" | "--max-frequency" => {
+                if let Some(Ok(frequency)) = args.next().map(|f_str| f_str.parse()) {
+                    if arg == "--min-frequency" {
+                        image_options.settings.min_frequency = Some(frequency);
+                    } else {
+                        image_options.settings.max_frequency = Some(frequency);
+                    }
+                } else {
+                    println!("Invalid frequency.");
+                    return;
+                }
+            }
+            "--waveform" => image_options.waveform = true,
+            "--spectrogram" => image_options.spectrogram = true,
+            "--svg" => image_options.extension = "svg",
+            "image" => image = true,
+            "--meter" => meter = true,
+            "--loudness" => loudness = true,
+            "--normalize-peak" | "--normalize-lufs" => {
+                if let Some(Ok(target)) = args.next().map(|t_str| t_str.parse()) {
+                    if arg == "--normalize-peak" {
+                        normalize_peak = Some(target);
+                    } else {
+                        normalize_loudness = Some(target);
+                    }
+                } else {
+                    println!("Invalid normalization target.");
+                    return;
+                }
+            }
+            "--profile" => profile = Profile::Report,
+            "--profile=folded" => profile = Profile::Folded,
             "-p" | "--play" => play = true,
+            "--watch" => watch = true,
+            "--error-format=human" => json_errors = false,
+            "--error-format=json" => json_errors = true,
+            "repl" => repl = true,
+            "fmt" => format = true,
+            "check" => lint_check = true,
+            "test" => test = true,
+            "--bless" => bless = true,
+            "--check" => fmt_check = true,
             "-h" | "--help" => {
                 println!(
                     "\n\
 Usage:
     sonny <filename> [options]
+    sonny repl [options]
+    sonny fmt [--check] <filenames>
+    sonny check <filenames>
+    sonny test <filenames>
+    sonny test [--bless] [manifest]
+    sonny image <filename> [chain] [options]
 
 Options:
     -h | --help             Display this message
@@ -106,120 +253,702 @@ Options:
     -e | --end              Set the end time of the output file
     -p | --play             Plays the output file after it is
                             finished generating.
+    --debug-every           Set how often in seconds the values of
+                            debug and print expressions are
+                            printed while rendering (default is 0.25)
+    --debug-on-change       Print the values of debug and print
+                            expressions whenever they change instead
+    --trace <chain>         Write every value of a chain while
+                            rendering to <chain>.csv
+    --meter                 Show the levels and waveform of the audio
+                            and how many samples clip while rendering
+    --loudness              Report the peak, true peak, RMS, and
+                            integrated loudness of the audio
+    --normalize-peak <dB>   Change the volume of the audio so that its
+                            true peak is at some dBTP before writing
+    --normalize-lufs <LUFS> Change the volume of the audio so that its
+                            integrated loudness is at some LUFS before
+                            writing, without going over the peak given
+                            with --normalize-peak
+    --profile               Measure the time spent in each chain and
+                            operation and print the slowest ones
+    --profile=folded        Measure the time spent in each chain and
+                            write it to <filename>.folded for
+                            flamegraph tools
+    --watch                 Render the file again whenever it or a
+                            file it includes changes
+    --check                 With fmt, list the files that are not
+                            formatted instead of formatting them
+    --bless                 With test, render the tests' chains and
+                            save them as their references
+    --waveform              With image, only draw the waveform
+    --spectrogram           With image, only draw the spectrogram
+    --width, --height       With image, set the size of the images in
+                            pixels (default is 1200 by 400)
+    --frequency-scale       With image, space the spectrogram's
+                            frequencies \"linear\" or \"log\" (default)
+    --min-frequency,        With image, set the range of frequencies
+    --max-frequency         in the spectrogram in Hz
+    --svg                   With image, write SVG files instead of PNG
+    --error-format=json     Print errors and warnings to stderr as
+                            lines of JSON instead of as text
 "
                 );
                 return;
             }
-            _ => file_name = Some(arg.to_string()),
+            _ => file_names.push(arg.to_string()),
         }
     }
-    if let Some(ref file_name) = file_name {
-        // Initialize the parser
-        match Parser::new(file_name, Builder::new()) {
-            // start parsing the file
-            Ok(parser) => match parser.parse(false) {
-                // make functions
-                Ok(builder) => {
-                    // output sound
-                    if let Err(error) = write(
-                        &builder,
-                        sample_rate,
-                        window_size,
-                        buffer_size,
-                        start_time,
-                        end_time,
-                        play,
-                    ) {
-                        error.report();
-                    }
+    let settings = RenderSettings {
+        sample_rate,
+        window_size,
+        buffer_size,
+    };
+    let trace = RenderOptions {
+        mode: trace_mode,
+        chain: trace_chain,
+        profile,
+        meter,
+        loudness,
+        normalize_peak,
+        normalize_loudness,
+        json: json_errors,
+    };
+    if image && !image_options.waveform && !image_options.spectrogram {
+        image_options.waveform = true;
+        image_options.spectrogram = true;
+    }
+    if repl {
+        run_repl(settings, json_errors);
+    } else if format {
+        if !format_files(&file_names, fmt_check, json_errors) {
+            process::exit(1);
+        }
+    } else if lint_check {
+        if !check_files(&file_names, json_errors) {
+            process::exit(1);
+        }
+    } else if test && file_names.iter().any(|file| file.ends_with(".son")) {
+        if !run_son_tests(&file_names, sample_rate, json_errors) {
+            process::exit(1);
+        }
+    } else if test {
+        let manifest = file_names.first().map_or("golden.txt", String::as_str);
+        if !run_tests(manifest, bless, json_errors) {
+            process::exit(1);
+        }
+    } else if image {
+        if !draw_images(
+            &file_names,
+            settings,
+            start_time,
+            end_time,
+            &image_options,
+            json_errors,
+        ) {
+            process::exit(1);
+        }
+    } else if let Some(file_name) = file_names.first() {
+        if watch {
+            watch_file(file_name, settings, start_time, end_time, play, &trace);
+            return;
+        }
+        // Parse the file and output sound
+        match parse_file(file_name) {
+            Ok(builder) => {
+                report_all(builder.diagnostics.clone(), json_errors);
+                if let Err(error) = write(&builder, settings, start_time, end_time, play, &trace) {
+                    report_all(vec![error], json_errors);
                 }
-                Err(error) => error.report(),
-            },
-            Err(error) => error.report(),
+            }
+            Err(errors) => report_all(errors, json_errors),
         }
     } else {
         println!("Usage: \n    sonny <filname> [options]\n    Type \"sonny -h\" or \"sonny --help\" for usage details.");
     }
 }
 
+// Report a list of errors with a blank line between each one, or
+// as one line of JSON each on stderr
+fn report_all(errors: Vec<Error>, json: bool) {
+    if json {
+        for error in errors {
+            eprintln!("{}", error.to_json());
+        }
+        return;
+    }
+    for (i, error) in errors.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        error.report();
+    }
+}
+
+// Report the values of "debug" and "print" expressions on one line each,
+// or as one line of JSON each on stderr
+fn report_trace(messages: Vec<Error>, json: bool) {
+    for message in messages {
+        if json {
+            eprintln!("{}", message.to_json());
+            continue;
+        }
+        let severity = match message.severity {
+            ErrorSeverity::Print => "Print",
+            _ => "Debug",
+        };
+        let location = message
+            .location
+            .as_ref()
+            .map(|loc| format!(" {}", loc))
+            .unwrap_or_default();
+        println!("{}{} {}", severity.yellow().bold(), location, message.spec);
+    }
+}
+
+// Format files in place, or with check, list the ones that are not
+// formatted. Returns whether every file was formatted without errors.
+fn format_files(files: &[String], check: bool, json: bool) -> bool {
+    let mut success = true;
+    for file in files {
+        let result = lexer::Lexer::new(file).and_then(|lexer| {
+            let source = lexer.source().to_string();
+            format_source(file, &source).map(|formatted| (source, formatted))
+        });
+        match result {
+            Ok((ref source, ref formatted)) if source == formatted => (),
+            Ok((_, formatted)) => {
+                if check {
+                    println!("{} is not formatted", file);
+                    success = false;
+                } else if fs::write(file, formatted).is_err() {
+                    let error = Error::new(ErrorSpec::CantWriteOutputFile(file.clone()));
+                    report_all(vec![error], json);
+                    success = false;
+                }
+            }
+            Err(error) => {
+                report_all(vec![error], json);
+                success = false;
+            }
+        }
+    }
+    success
+}
+
+// Parse files and report their errors and warnings, including the ones
+// found by linting. Returns whether there were no errors or warnings.
+fn check_files(files: &[String], json: bool) -> bool {
+    let mut success = true;
+    for file in files {
+        let diagnostics = match parse_file(file) {
+            Ok(mut builder) => {
+                let mut diagnostics = mem::take(&mut builder.diagnostics);
+                diagnostics.extend(builder.lint());
+                diagnostics
+            }
+            Err(errors) => errors,
+        };
+        if diagnostics.is_empty() {
+            if !json {
+                println!("{} has no problems", file);
+            }
+        } else {
+            success = false;
+            report_all(diagnostics, json);
+        }
+    }
+    success
+}
+
+// Run the tests declared in files with "test". Returns whether every
+// file compiled and every test passed.
+fn run_son_tests(files: &[String], sample_rate: f64, json: bool) -> bool {
+    let mut passed = 0;
+    let mut failed = 0;
+    for file in files {
+        let builder = match parse_file(file) {
+            Ok(builder) => builder,
+            Err(errors) => {
+                report_all(errors, json);
+                failed += 1;
+                continue;
+            }
+        };
+        report_all(builder.diagnostics.clone(), json);
+        for test in builder.tests_in(&builder.files[0]) {
+            print!("test {} \"{}\" ... ", file, test.name);
+            stdout().flush().expect("Unable to flush stdout");
+            let errors = builder.run_test(test, sample_rate);
+            if errors.is_empty() {
+                println!("{}", "ok".green());
+                passed += 1;
+            } else {
+                println!("{}", "FAILED".bright_red());
+                report_all(errors, json);
+                failed += 1;
+            }
+            report_trace(builder.tracer.take_messages(), json);
+        }
+    }
+    let summary = format!("{} passed, {} failed", passed, failed);
+    if failed == 0 {
+        println!("\ntest result: {}. {}", "ok".green(), summary);
+    } else {
+        println!("\ntest result: {}. {}", "FAILED".bright_red(), summary);
+    }
+    failed == 0
+}
+
+// Run the golden tests in a manifest, or with bless, save their renders
+// as their references. Returns whether every test passed.
+fn run_tests(manifest: &str, bless: bool, json: bool) -> bool {
+    let tests = match golden::read_manifest(manifest) {
+        Ok(tests) => tests,
+        Err(error) => {
+            report_all(vec![error], json);
+            return false;
+        }
+    };
+    let mut failed = 0;
+    for test in &tests {
+        print!("test {} ... ", test.description());
+        stdout().flush().expect("Unable to flush stdout");
+        if bless {
+            match test.bless() {
+                Ok(()) => println!("{}", "blessed".green()),
+                Err(errors) => {
+                    println!("{}", "error".bright_red());
+                    report_all(errors, json);
+                    failed += 1;
+                }
+            }
+            continue;
+        }
+        match test.run() {
+            golden::TestResult::Passed(..) => println!("{}", "ok".green()),
+            golden::TestResult::Failed(reasons, comparison, actual) => {
+                println!("{}", "FAILED".bright_red());
+                failed += 1;
+                for reason in reasons {
+                    println!("    {}", reason);
+                }
+                if let Some(comparison) = comparison {
+                    println!(
+                        "    max difference: {:.6} at {:.4} s, rms difference: {:.6}, \
+                         spectral difference: {:.6}",
+                        comparison.max_difference,
+                        test.start + comparison.max_difference_time,
+                        comparison.rms_difference,
+                        comparison.spectral_difference
+                    );
+                }
+                // The render is saved next to the reference to listen to,
+                // along with its waveform drawn over the reference's
+                let filename = format!("{}.actual.wav", test.reference.trim_end_matches(".wav"));
+                match write_wav(&filename, &actual, test.settings.sample_rate) {
+                    Ok(()) => println!("    the render was written to {}", filename.cyan()),
+                    Err(error) => report_all(vec![error], json),
+                }
+                let expected = golden::read_wav(&test.reference)
+                    .map(|reference| reference.0)
+                    .unwrap_or_default();
+                let filename = format!("{}.actual.png", test.reference.trim_end_matches(".wav"));
+                match image::comparison(&actual, &expected, &ImageSettings::default())
+                    .write(&filename)
+                {
+                    Ok(()) => println!(
+                        "    its waveform was drawn over the reference's in {}",
+                        filename.cyan()
+                    ),
+                    Err(error) => report_all(vec![error], json),
+                }
+            }
+            golden::TestResult::Errors(errors) => {
+                println!("{}", "error".bright_red());
+                failed += 1;
+                report_all(errors, json);
+            }
+        }
+    }
+    let summary = format!("{} passed, {} failed", tests.len() - failed, failed);
+    if bless {
+        println!(
+            "\n{} of {} references saved",
+            tests.len() - failed,
+            tests.len()
+        );
+    } else if failed == 0 {
+        println!("\ntest result: {}. {}", "ok".green(), summary);
+    } else {
+        println!("\ntest result: {}. {}", "FAILED".bright_red(), summary);
+    }
+    failed == 0
+}
+
+// Render a chain, or the out chain if none is given, and draw images of
+// it. Returns whether every image was written without errors.
+fn draw_images(
+    files: &[String],
+    settings: RenderSettings,
+    start_time: f64,
+    end_time: Option<f64>,
+    options: &ImageOptions,
+    json: bool,
+) -> bool {
+    let file = if let Some(file) = files.first() {
+        file
+    } else {
+        println!("Usage: \n    sonny image <filename> [chain] [options]");
+        return false;
+    };
+    let builder = match parse_file(file) {
+        Ok(builder) => builder,
+        Err(errors) => {
+            report_all(errors, json);
+            return false;
+        }
+    };
+    report_all(builder.diagnostics.clone(), json);
+    let name = match files.get(1) {
+        Some(chain) => {
+            let name = ChainName::Scoped(chain.clone());
+            builder.find_chain(&name).map(|chain| chain.name.clone())
+        }
+        None => builder.out_chain().cloned(),
+    };
+    let name = if let Some(name) = name {
+        name
+    } else {
+        let chain = files.get(1).map_or("out", String::as_str).to_string();
+        report_all(
+            vec![Error::new(ErrorSpec::CantFindChain(ChainName::Scoped(
+                chain,
+            )))],
+            json,
+        );
+        return false;
+    };
+    // Warnings about shapes were already found by the parser
+    let mut errors = builder.check_backlinks(&name);
+    errors.extend(builder.check_output_shape(&name));
+    errors.retain(Error::is_fatal);
+    if !errors.is_empty() {
+        report_all(errors, json);
+        return false;
+    }
+    let end = end_time.unwrap_or_else(|| builder.audio_end());
+    let samples = match builder.render(&name, start_time, end, settings) {
+        Ok(samples) => samples,
+        Err(error) => {
+            report_all(vec![error], json);
+            return false;
+        }
+    };
+    let mut success = true;
+    let mut write = |kind: &str, result: &dyn Fn(&str) -> SonnyResult<()>| {
+        let filename = format!("{}_{}.{}", short_name(&name), kind, options.extension);
+        match result(&filename) {
+            Ok(()) => println!("Wrote {}", filename.cyan()),
+            Err(error) => {
+                report_all(vec![error], json);
+                success = false;
+            }
+        }
+    };
+    if options.waveform {
+        write("waveform", &|filename| {
+            image::write_waveform(filename, &samples, &options.settings)
+        });
+    }
+    if options.spectrogram {
+        write("spectrogram", &|filename| {
+            image::write_spectrogram(filename, &samples, settings.sample_rate, &options.settings)
+        });
+    }
+    success
+}
+
+// Parse and render a file, then do it again every time that it or any of
+// the files that it includes change. Errors are reported without exiting.
+fn watch_file(
+    file_name: &str,
+    settings: RenderSettings,
+    start_time: f64,
+    end_time: Option<f64>,
+    play: bool,
+    trace: &RenderOptions,
+) {
+    let json = trace.json;
+    // The signature and end time of the last output that was written
+    let mut last_output = None;
+    loop {
+        let mut files = vec![file_name.to_string()];
+        let mut times = modified_times(&files);
+        match Parser::new(file_name, Builder::new()) {
+            Ok(parser) => {
+                let mut builder = parser.parse_items(false);
+                files = builder.files.clone();
+                times = modified_times(&files);
+                let diagnostics = mem::take(&mut builder.diagnostics);
+                let failed = diagnostics.iter().any(Error::is_fatal);
+                report_all(diagnostics, json);
+                if !failed {
+                    // The output is only rendered again if the out chain or
+                    // anything it uses changed, or if its length changed
+                    let output = builder.out_chain().map(|name| {
+                        (
+                            builder.signature(name),
+                            end_time.unwrap_or_else(|| builder.audio_end()),
+                        )
+                    });
+                    if output.is_some() && output == last_output {
+                        println!("The output is unchanged.");
+                    } else {
+                        last_output = None;
+                        match write(&builder, settings, start_time, end_time, play, trace) {
+                            Ok(()) => last_output = output,
+                            Err(error) => report_all(vec![error], json),
+                        }
+                    }
+                }
+            }
+            Err(error) => report_all(vec![error], json),
+        }
+        println!("{}", "Watching for changes...".cyan());
+        // Wait until a file is modified, created, or deleted
+        while modified_times(&files) == times {
+            thread::sleep(Duration::from_millis(200));
+        }
+        println!();
+    }
+}
+
+// The times at which files were last modified. Missing files have no time.
+fn modified_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|data| data.modified()).ok())
+        .collect()
+}
+
+static REPL_HELP: &str = "\
+Enter chain declarations and std, include, use, and tempo lines to add
+them to the session. Redeclaring a chain replaces it. Anything else is
+evaluated as an expression and its value is printed. Input continues on
+the next line while delimeters are unclosed or it ends with -> or ,
+
+Commands:
+    :help                       Display this message
+    :time <seconds>             Set the time at which expressions are
+                                evaluated and rendered (default is 0)
+    :render <seconds> <expr>    Render an expression for some seconds
+                                into the output file
+    :play <seconds> <expr>      Render an expression and play it
+    :output <filename>          Set the output file (default is repl.wav)
+    :chains                     List the chains declared in the session
+    :quit                       Exit the session";
+
+// Run an interactive session that reads declarations, expressions,
+// and commands from stdin
+fn run_repl(settings: RenderSettings, json: bool) {
+    let mut repl = Repl::new();
+    let mut time = 0.0;
+    let mut output = "repl.wav".to_string();
+    let stdin = stdin();
+    let mut lines = stdin.lock().lines();
+    println!("Type \":help\" for help or \":quit\" to exit.");
+    loop {
+        // Read lines until the input is complete
+        let mut input = String::new();
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { "| " });
+            stdout().flush().expect("Unable to flush stdout");
+            match lines.next() {
+                Some(Ok(line)) => {
+                    input.push_str(&line);
+                    input.push('\n');
+                }
+                _ => {
+                    println!();
+                    return;
+                }
+            }
+            if Repl::is_complete(&input) {
+                break;
+            }
+        }
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        if let Some(command) = input.strip_prefix(':') {
+            let mut parts = command.splitn(2, char::is_whitespace);
+            let command = parts.next().unwrap_or_default();
+            let rest = parts.next().unwrap_or_default().trim();
+            match command {
+                "h" | "help" => println!("{}", REPL_HELP),
+                "q" | "quit" => return,
+                "time" => {
+                    if let Ok(t) = rest.parse() {
+                        time = t;
+                    } else {
+                        println!("Invalid time.");
+                    }
+                }
+                "output" => {
+                    if rest.is_empty() {
+                        println!("Invalid output file.");
+                    } else {
+                        output = rest.to_string();
+                    }
+                }
+                "chains" => {
+                    for name in repl.chain_names() {
+                        println!("{}", name);
+                    }
+                }
+                "render" | "play" => {
+                    let mut parts = rest.splitn(2, char::is_whitespace);
+                    let seconds: f64 = if let Ok(seconds) = parts.next().unwrap_or_default().parse()
+                    {
+                        seconds
+                    } else {
+                        println!("Invalid duration.");
+                        continue;
+                    };
+                    let expression = parts.next().unwrap_or_default();
+                    let result = repl
+                        .render(expression, time, time + seconds, settings)
+                        .and_then(|samples| {
+                            write_wav(&output, &samples, settings.sample_rate)
+                                .map_err(|error| vec![error])
+                        });
+                    match result {
+                        Ok(()) => {
+                            println!("Wrote {}", output.cyan());
+                            if command == "play" && open::that(&output).is_err() {
+                                report_all(vec![Error::new(ErrorSpec::CantOpenOutputFile)], json);
+                            }
+                        }
+                        Err(errors) => report_all(errors, json),
+                    }
+                    report_trace(repl.take_messages(), json);
+                }
+                _ => println!(
+                    "Unknown command: \":{}\". Type \":help\" for a list of commands.",
+                    command
+                ),
+            }
+        } else if Repl::is_declaration(input) {
+            match repl.declare(input) {
+                Ok(warnings) => report_all(warnings, json),
+                Err(errors) => report_all(errors, json),
+            }
+        } else {
+            match repl.evaluate(input, time, settings.sample_rate) {
+                Ok(value) => {
+                    report_trace(repl.take_messages(), json);
+                    println!("{}", value);
+                }
+                Err(errors) => report_all(errors, json),
+            }
+        }
+    }
+}
+
 fn write(
     builder: &Builder,
-    sample_rate: f64,
-    window_size: usize,
-    buffer_size: usize,
+    settings: RenderSettings,
     start_time: f64,
     end_time: Option<f64>,
     play: bool,
+    trace: &RenderOptions,
 ) -> SonnyResult<()> {
     // Find the audio end time
-    // TODO: make this only take into account chains that are actually used
-    let mut end: f64 = 1.0;
-    for chain in builder.chains.values() {
-        if let ChainLinks::OnlyNotes(ref _notes_or_ids, period) = chain.links {
-            end = end.max(period.end);
+    let end = end_time.unwrap_or_else(|| builder.audio_end());
+
+    // Find the traced chain
+    let traced = match trace.chain {
+        Some(ref chain) => {
+            let name = ChainName::Scoped(chain.clone());
+            let chain = builder
+                .find_chain(&name)
+                .ok_or_else(|| Error::new(ErrorSpec::CantFindChain(name)))?;
+            Some(chain.name.clone())
         }
-    }
-    end = end.max(builder.end_time);
-    if let Some(end_time) = end_time {
-        end = end_time;
+        None => None,
+    };
+    builder.tracer.configure(trace.mode, traced.clone());
+    if trace.profile != Profile::Off {
+        builder.profiler.enable();
     }
 
     // output the main chain
-    if let Some(name) = builder.chains.iter().find(|f| f.1.play).map(|f| f.0) {
-        // populate the sample array with its own indicies because par_iter doesn't have enumerate()
-        let mut song = vec![0f64; (sample_rate * (end - start_time)) as usize];
-        // run each sample window as a batch
+    if let Some(name) = builder.out_chain() {
         let mut then = Instant::now(); // Keeps track of the time when the last window iteration started
         let mut last_elapsed = VecDeque::new(); // Keeps a moving list of elapsed time values for a running average
         let start_instant = Instant::now(); // The time the evaluation started
+        let mut meter = Meter::default();
+        // The number of lines drawn below the progress bar by the meter
+        let meter_lines = if trace.meter { METER_HEIGHT + 1 } else { 0 };
+        let mut meter_drawn = false;
 
         // Main generation loop
-        let window_count = (song.len() as f64 / window_size as f64).ceil() as usize;
-        for window_start in (0..window_count).map(|x| x * window_size) {
-            // Determine the buffer size and adjusted window start
-            let this_buffer_size = if window_start == 0 { 0 } else { buffer_size };
-            let window_start = window_start - this_buffer_size;
-            // Determine the time
-            let time = window_start as f64 / sample_rate + start_time;
-
-            // Print a progress bar
-            let progress = (time / end * 41.0) as usize;
-            print!(
-                "\r{} [{}>{}] ",
-                format!("{:.2} / {:.2} s", time, end - start_time).cyan(),
-                (0..progress).map(|_| '=').collect::<String>(),
-                (0..(40 - progress)).map(|_| ' ').collect::<String>()
-            );
-            // Print an eta
-            let now = Instant::now();
-            let elapsed = now.duration_since(then);
-            then = now;
-            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
-            last_elapsed.push_back(elapsed);
-            if last_elapsed.len() > 30 {
-                last_elapsed.pop_front();
-            }
-            let rate = ((window_size + this_buffer_size) as f64 / sample_rate)
-                / (last_elapsed.iter().sum::<f64>() / last_elapsed.len() as f64);
-            let eta = (end - time) / rate;
-            print!("eta: {}", format!("{:.2}s", eta).cyan());
-            stdout().flush().expect("Unable to flush stdout");
-
-            // Evaluate
-            if time >= end {
-                break;
-            }
-            let window_result = builder.evaluate_chain(
-                &name,
-                &[],
-                time,
-                window_size.min(song.len() - window_start),
-                this_buffer_size,
-                sample_rate,
-            )?;
-            for (i, r) in window_result.into_iter().skip(this_buffer_size).enumerate() {
-                song[i + window_start] = f64::from(r);
-            }
+        let mut song =
+            builder.render_with_progress(name, start_time, end, settings, |time, window| {
+                // Move back up to the progress bar and clear the meter
+                if trace.meter {
+                    meter.update(window);
+                    if meter_drawn {
+                        print!("\x1b[{}A\r\x1b[J", meter_lines);
+                    }
+                }
+                // Print the values that were traced above the progress bar
+                let messages = builder.tracer.take_messages();
+                if !messages.is_empty() {
+                    print!("\r{}\r", " ".repeat(95));
+                    report_trace(messages, trace.json);
+                }
+                // Print a progress bar
+                let progress = (time / end * 41.0) as usize;
+                print!(
+                    "\r{} [{}>{}] ",
+                    format!("{:.2} / {:.2} s", time, end - start_time).cyan(),
+                    (0..progress).map(|_| '=').collect::<String>(),
+                    (0..(40 - progress)).map(|_| ' ').collect::<String>()
+                );
+                // Print an eta
+                let now = Instant::now();
+                let elapsed = now.duration_since(then);
+                then = now;
+                let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+                last_elapsed.push_back(elapsed);
+                if last_elapsed.len() > 30 {
+                    last_elapsed.pop_front();
+                }
+                // Every window after the first also evaluates the buffer before it
+                let this_buffer_size = if window.is_empty() {
+                    0
+                } else {
+                    settings.buffer_size
+                };
+                let rate = ((settings.window_size + this_buffer_size) as f64
+                    / settings.sample_rate)
+                    / (last_elapsed.iter().sum::<f64>() / last_elapsed.len() as f64);
+                let eta = (end - time) / rate;
+                print!("eta: {}", format!("{:.2}s", eta).cyan());
+                // Draw the meter below the progress bar
+                if trace.meter {
+                    for line in meter.lines(METER_WIDTH, METER_HEIGHT) {
+                        print!("\n{}", line);
+                    }
+                    meter_drawn = true;
+                }
+                stdout().flush().expect("Unable to flush stdout");
+            })?;
+        if meter_drawn {
+            print!("\x1b[{}A\r\x1b[J", meter_lines);
         }
         // Print the final progress bar
         let total_elapsed = Instant::now().duration_since(start_instant);
@@ -234,30 +963,79 @@ fn write(
             )
             .cyan()
         );
+        report_trace(builder.tracer.take_messages(), trace.json);
 
-        // Write the audio file
-        let filename;
-        {
-            let spec = hound::WavSpec {
-                channels: 1,
-                sample_rate: sample_rate as u32,
-                bits_per_sample: 16,
-                sample_format: hound::SampleFormat::Int,
-            };
-            filename = format!(
-                "{}.wav",
-                if let ChainName::Scoped(chain_name) = name {
-                    chain_name.split("::").last().unwrap().to_string()
-                } else {
-                    name.to_string()
+        // Analyze the loudness of the audio and normalize it
+        if trace.loudness || trace.normalize_peak.is_some() || trace.normalize_loudness.is_some() {
+            let analysis = loudness::analyze(&song, settings.sample_rate);
+            if trace.loudness {
+                report_loudness(&analysis);
+            }
+            match loudness::normalization_gain(
+                &analysis,
+                trace.normalize_peak,
+                trace.normalize_loudness,
+            ) {
+                Some(gain) => {
+                    loudness::apply_gain(&mut song, gain);
+                    println!(
+                        "Normalized with a gain of {}",
+                        format!("{:+.2} dB", gain).cyan()
+                    );
+                    if trace.loudness {
+                        report_loudness(&loudness::analyze(&song, settings.sample_rate));
+                    }
                 }
+                None if trace.normalize_peak.is_some() || trace.normalize_loudness.is_some() => {
+                    println!(
+                        "{}: the audio is too quiet or too short to measure, so it was not \
+                         normalized",
+                        "Warning".yellow().bold()
+                    )
+                }
+                None => (),
+            }
+        }
+
+        // Report the levels of the audio and warn about clipping
+        let levels = Levels::of(&song);
+        if trace.meter {
+            println!(
+                "peak: {}, rms: {}",
+                format!("{:.1} dB", decibels(levels.peak)).cyan(),
+                format!("{:.1} dB", decibels(levels.rms)).cyan()
+            );
+        }
+        if let Some(first) = song.iter().position(|sample| clips(*sample)) {
+            println!(
+                "{}: {} samples clip, the first at {:.3} s",
+                "Warning".yellow().bold(),
+                levels.clipped,
+                start_time + first as f64 / settings.sample_rate
             );
-            let mut writer = hound::WavWriter::create(&filename, spec).unwrap();
-            let amplitude = f64::from(i16::MAX);
-            for s in song {
-                writer
-                    .write_sample((s * amplitude).min(amplitude) as i16)
-                    .unwrap();
+        }
+
+        // Write the audio file
+        let filename = format!("{}.wav", short_name(name));
+        write_wav(&filename, &song, settings.sample_rate)?;
+
+        // Write the traced chain's values
+        if let Some(traced) = traced {
+            let csv_name = format!("{}.csv", short_name(&traced));
+            fs::write(&csv_name, samples_to_csv(&builder.tracer.take_samples()))
+                .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(csv_name.clone())))?;
+            println!("Wrote {}", csv_name.cyan());
+        }
+
+        // Show the time spent in each chain
+        match trace.profile {
+            Profile::Off => (),
+            Profile::Report => print!("\n{}", builder.profiler.report()),
+            Profile::Folded => {
+                let folded_name = format!("{}.folded", short_name(name));
+                fs::write(&folded_name, builder.profiler.folded())
+                    .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(folded_name.clone())))?;
+                println!("Wrote {}", folded_name.cyan());
             }
         }
 
@@ -267,3 +1045,24 @@ fn write(
     }
     Ok(())
 }
+
+// Report the loudness of audio on one line
+fn report_loudness(analysis: &loudness::Loudness) {
+    let level = |level: f64, unit: &str| format!("{:.2} {}", level, unit).cyan();
+    println!(
+        "peak: {}, true peak: {}, rms: {}, loudness: {}",
+        level(analysis.peak, "dBFS"),
+        level(analysis.true_peak, "dBTP"),
+        level(analysis.rms, "dBFS"),
+        level(analysis.integrated, "LUFS")
+    );
+}
+
+// The name of a chain without the scope it is in
+fn short_name(name: &ChainName) -> String {
+    if let ChainName::Scoped(chain_name) = name {
+        chain_name.split("::").last().unwrap().to_string()
+    } else {
+        name.to_string()
+    }
+}
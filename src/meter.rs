@@ -0,0 +1,127 @@
+// This module contains the level meter, which measures audio as it is
+// rendered and draws its levels and waveform in the terminal
+
+use colored::*;
+
+use image::column_ranges;
+
+// The quietest level that level bars show, in decibels
+static FLOOR: f64 = -60.0;
+
+// Convert an amplitude to decibels relative to full scale
+pub fn decibels(amplitude: f64) -> f64 {
+    20.0 * amplitude.abs().log10()
+}
+
+// Whether a sample is too loud to be written without clipping. Samples
+// that are not numbers are written at full scale, so they clip too.
+pub fn clips(sample: f64) -> bool {
+    sample.is_nan() || sample.abs() > 1.0
+}
+
+// The levels of some samples
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Levels {
+    pub peak: f64,
+    pub rms: f64,
+    // The number of samples that clip
+    pub clipped: usize,
+}
+
+impl Levels {
+    pub fn of(samples: &[f64]) -> Levels {
+        let square_sum: f64 = samples.iter().filter(|x| !x.is_nan()).map(|x| x * x).sum();
+        Levels {
+            peak: samples.iter().map(|x| x.abs()).fold(0.0, f64::max),
+            rms: (square_sum / samples.len().max(1) as f64).sqrt(),
+            clipped: samples.iter().filter(|x| clips(**x)).count(),
+        }
+    }
+}
+
+// Measures the windows of a render as they are rendered
+#[derive(Debug, Clone, Default)]
+pub struct Meter {
+    // The levels of the last window
+    pub window: Levels,
+    // The levels of every window so far
+    pub total: Levels,
+    // The samples of the last window
+    samples: Vec<f64>,
+    square_sum: f64,
+    count: usize,
+}
+
+impl Meter {
+    // Measure the next window of samples
+    pub fn update(&mut self, window: &[f64]) {
+        self.window = Levels::of(window);
+        self.square_sum += window
+            .iter()
+            .filter(|x| !x.is_nan())
+            .map(|x| x * x)
+            .sum::<f64>();
+        self.count += window.len();
+        self.total = Levels {
+            peak: self.total.peak.max(self.window.peak),
+            rms: (self.square_sum / self.count.max(1) as f64).sqrt(),
+            clipped: self.total.clipped + self.window.clipped,
+        };
+        self.samples = window.to_vec();
+    }
+    // Draw the levels of the last window and a waveform of it with some
+    // number of rows and columns. Columns that clip are drawn in red.
+    pub fn lines(&self, width: usize, height: usize) -> Vec<String> {
+        let bar = |level: f64| {
+            let db = decibels(level);
+            let fraction = ((db - FLOOR) / -FLOOR).clamp(0.0, 1.0);
+            let filled = (fraction * 20.0).round() as usize;
+            let bar = format!(
+                "[{}{}]",
+                "=".repeat(filled),
+                " ".repeat(20 - filled.min(20))
+            );
+            let bar = if level > 1.0 {
+                bar.red()
+            } else if db > -6.0 {
+                bar.yellow()
+            } else {
+                bar.green()
+            };
+            format!("{} {:>6.1} dB", bar, db.max(-99.9))
+        };
+        let clipped = format!("clipped: {}", self.total.clipped);
+        let mut lines = vec![format!(
+            "peak {}  rms {}  {}",
+            bar(self.window.peak),
+            bar(self.window.rms),
+            if self.total.clipped > 0 {
+                clipped.red().bold()
+            } else {
+                clipped.normal()
+            }
+        )];
+        let ranges = column_ranges(&self.samples, width);
+        for row in 0..height {
+            // The amplitudes that the row covers
+            let top = 1.0 - 2.0 * row as f64 / height as f64;
+            let bottom = 1.0 - 2.0 * (row + 1) as f64 / height as f64;
+            let line: Vec<String> = ranges
+                .iter()
+                .map(|range| match *range {
+                    Some((min, max)) if max >= bottom && min <= top => {
+                        if min < -1.0 || max > 1.0 {
+                            "#".red().to_string()
+                        } else {
+                            "#".cyan().to_string()
+                        }
+                    }
+                    _ if bottom <= 0.0 && top >= 0.0 => "-".dimmed().to_string(),
+                    _ => " ".to_string(),
+                })
+                .collect();
+            lines.push(line.concat());
+        }
+        lines
+    }
+}
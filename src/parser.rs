@@ -1,9 +1,12 @@
-use std::{env, f64, path::PathBuf};
+use std::{
+    env, f64, mem,
+    path::{Path, PathBuf},
+};
 
 use either::*;
 use find_folder::{Search, SearchFolder};
 
-use builder::{variable::*, *};
+use builder::{rhythm::*, transform::*, variable::*, *};
 use error::{ErrorSpec::*, *};
 use lexer::{TokenType::*, *};
 
@@ -20,12 +23,16 @@ pub struct Parser {
     builder: Builder,
     // The next token to be parsed
     look: Token,
+    // The location of the next token to be parsed
+    look_loc: CodeLocation,
     // The next next token to be parsed
     next: Token,
+    // The location of the next next token to be parsed
+    next_loc: CodeLocation,
+    // The location of the last token that was parsed
+    prev_loc: CodeLocation,
     // Wheter or not peek() has recently been called
     peeked: bool,
-    // The sample rate of the output
-    sample_rate: f64,
     // The current time. Used for correctly assigned periods to notes
     curr_time: f64,
     // How many levels deep of parenthesis the parser is
@@ -38,30 +45,52 @@ impl Parser {
     // Creates a new Parser which will parse the given file and
     // invoke the given builder
     pub fn new(file: &str, builder: Builder) -> SonnyResult<Parser> {
-        let mut lexer = Lexer::new(file)?;
+        Ok(Parser::with_lexer(file, Lexer::new(file)?, builder))
+    }
+    // Creates a new Parser which will parse the given source code and
+    // invoke the given builder. The file name is used to name the
+    // top-level chain and to find included files.
+    pub fn from_source(file: &str, source: &str, builder: Builder) -> Parser {
+        Parser::with_lexer(file, Lexer::from_source(file, source), builder)
+    }
+    fn with_lexer(file: &str, mut lexer: Lexer, builder: Builder) -> Parser {
         let look = lexer.lex();
-        Ok(Parser {
+        let look_loc = lexer.token_loc();
+        Parser {
             main_file_name: file.to_string(),
             lexer,
             builder,
             look,
             next: Token(Empty, String::new()),
+            next_loc: look_loc.clone(),
+            prev_loc: look_loc.clone(),
+            look_loc,
             peeked: false,
-            sample_rate: 44100.0,
             curr_time: 0.0,
             paren_level: 0,
             last_note_octave: 3,
-        })
+        }
     }
     // Parse the whole file and return the builder so that it can be
     // used by higher-level parsers or the write function itself.
-    pub fn parse(mut self, finalize: bool) -> SonnyResult<Builder> {
+    // If any errors are found, all of them are returned instead.
+    pub fn parse(self, finalize: bool) -> Result<Builder, Vec<Error>> {
+        let mut builder = self.parse_items(finalize);
+        if builder.diagnostics.iter().any(Error::is_fatal) {
+            Err(mem::take(&mut builder.diagnostics))
+        } else {
+            Ok(builder)
+        }
+    }
+    // Parse the whole file and return the builder with any errors
+    // that were found added to its diagnostics. Unlike parse(), this
+    // always returns the builder so that tools can use whatever parsed.
+    pub fn parse_items(mut self, finalize: bool) -> Builder {
         // Determine the name of this file's top-level chain
         let top_chain_name = PathBuf::from(self.lexer.loc().file)
             .file_stem()
-            .expect("Unable to get file stem from file path")
-            .to_str()
-            .expect("Unable to convert file steam to &str")
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
             .to_string();
         // If this chain name already exists, then this is a file that
         // has already been included. Return the builder.
@@ -70,145 +99,301 @@ impl Parser {
             .find_chain(&ChainName::Scoped(top_chain_name.clone()))
             .is_some()
         {
-            return Ok(self.builder);
+            return self.builder;
         }
+        self.builder.files.push(self.main_file_name.clone());
         // Create this file's top-level chain
+        let loc = self.loc();
+        if let Err(error) = self.builder.new_chain(Some(top_chain_name), loc) {
+            self.builder.diagnostics.push(error);
+            return self.builder;
+        }
+        self.items();
+        // If this is not the top-level parser, finalize the top-level file chain
+        if finalize {
+            if let Err(error) = self.builder.finalize_chain() {
+                self.builder.diagnostics.push(error);
+            }
+        } else if !self.builder.diagnostics.iter().any(Error::is_fatal) {
+            // The out chain and tests are evaluated with no arguments
+            match self.builder.out_chain().cloned() {
+                Some(out) => {
+                    let errors = self.builder.check_backlinks(&out);
+                    self.report_new(errors);
+                    let errors = self.builder.check_output_shape(&out);
+                    self.report_new(errors);
+                }
+                // Files of tests do not need to output anything
+                None if !self
+                    .builder
+                    .tests
+                    .iter()
+                    .any(|test| test.location.path == self.look_loc.path) =>
+                {
+                    self.builder.diagnostics.push(Error::new(NoOutChain))
+                }
+                None => (),
+            }
+            let errors: Vec<Error> = self
+                .builder
+                .tests
+                .iter()
+                .flat_map(|test| self.builder.check_backlinks(&test.chain))
+                .collect();
+            self.builder.diagnostics.extend(errors);
+            let errors = self.builder.check_used_backlinks();
+            self.report_new(errors);
+            // Every chain that takes no arguments is checked for values
+            // that are mixed wrongly, like the out chain is
+            let mut chains: Vec<&Chain> = self
+                .builder
+                .chains
+                .values()
+                .filter(|chain| self.builder.arity(&chain.name) == 0)
+                .collect();
+            chains.sort_by(|a, b| {
+                (&a.location.path, a.location.span.start)
+                    .cmp(&(&b.location.path, b.location.span.start))
+            });
+            let errors: Vec<Error> = chains
+                .into_iter()
+                .filter_map(|chain| self.builder.shape(&chain.name).err())
+                .flatten()
+                .collect();
+            self.report_new(errors);
+        }
         self.builder
-            .new_chain(Some(top_chain_name), self.lexer.loc())?;
-        // While in this file
+    }
+    // Add errors to the diagnostics, except the ones that were already
+    // found from another chain that uses the same one
+    fn report_new(&mut self, errors: Vec<Error>) {
+        for error in errors {
+            let reported = self.builder.diagnostics.iter().any(|reported| {
+                match (&reported.location, &error.location) {
+                    (Some(a), Some(b)) => {
+                        a.path == b.path
+                            && a.span == b.span
+                            && reported.spec.to_string() == error.spec.to_string()
+                    }
+                    _ => false,
+                }
+            });
+            if !reported {
+                self.builder.diagnostics.push(error);
+            }
+        }
+    }
+    // Parse more items into a builder whose top-level chain has already
+    // been created, like the one kept by the REPL. Any errors that are
+    // found are added to the builder's diagnostics.
+    pub fn parse_more(mut self) -> Builder {
+        self.items();
+        self.builder
+    }
+    // Parse the source as the body of a chain with the given name, as if
+    // it were declared with "name: ..." in the current scope
+    pub fn parse_chain(mut self, name: &str) -> Result<Builder, Vec<Error>> {
+        let loc = self.loc();
+        self.builder
+            .new_chain(Some(name.to_string()), loc)
+            .map_err(|error| vec![error])?;
+        self.chain()
+            .and_then(|_| self.mat(Done))
+            .and_then(|_| self.builder.finalize_chain())
+            .map_err(|error| vec![error])?;
+        Ok(self.builder)
+    }
+    // Parse top-level items until the end of the file, recovering from
+    // errors by skipping to the next item
+    fn items(&mut self) {
+        let depth = self.builder.chain_depth();
         while self.look.0 != Done {
-            // Check for tempo setting
-            if self.look.1 == "tempo" {
-                self.mas("tempo")?;
-                self.mas(":")?;
-                self.builder.tempo = self.real()?;
-            }
-            // check for "include" keyword
-            else if self.look.1 == "std" || self.look.1 == "include" {
-                let standard = self.look.1 == "std";
-                if standard {
-                    self.mas("std")?;
-                } else {
-                    self.mas("include")?;
+            let start = self.look_loc.span.start;
+            if let Err(error) = self.item() {
+                self.builder.diagnostics.push(error);
+                // Make sure at least one token is skipped so that
+                // the same error is not found again
+                if self.look_loc.span.start == start {
+                    self.advance();
                 }
-                let mut filename = self.look.1.clone();
-                self.mat(Id)?;
-                while self.look.1 == "::" {
-                    self.mas("::")?;
-                    filename.push_str("/");
-                    filename.push_str(&self.look.1);
-                    self.mat(Id)?;
+                self.synchronize();
+                self.builder.discard_chains(depth);
+            }
+        }
+    }
+    // Skip tokens until the start of the next top-level item, which is
+    // a chain declaration or a keyword like "tempo" at the start of a line
+    fn synchronize(&mut self) {
+        while self.look.0 != Done {
+            if self.look_loc.line > self.prev_loc.line {
+                let item_keyword = match self.look.1.as_str() {
+                    "tempo" | "std" | "include" | "use" => self.look.0 == Keyword,
+                    "test" => self.keyword_here(),
+                    _ => false,
+                };
+                if item_keyword || self.look.0 == Id && self.peek().1 == ":" {
+                    break;
                 }
-                filename.push_str(".son");
-                // Temporarily pop off this file's scope
-                let temp_scope = self
-                    .builder
-                    .names_in_scope
-                    .pop()
-                    .expect("no chains in scope");
+            }
+            self.advance();
+        }
+        self.paren_level = 0;
+        self.curr_time = 0.0;
+        self.last_note_octave = 3;
+    }
+    // Match a top-level item, which is a setting, an included file,
+    // a use of names, or a chain declaration
+    fn item(&mut self) -> SonnyResult<()> {
+        // Check for tempo setting
+        if self.look.1 == "tempo" {
+            self.mas("tempo")?;
+            self.mas(":")?;
+            self.builder.tempo = self.real()?;
+        }
+        // check for "include" keyword
+        else if self.look.1 == "std" || self.look.1 == "include" {
+            let loc = self.loc();
+            let standard = self.look.1 == "std";
+            if standard {
+                self.mas("std")?;
+            } else {
+                self.mas("include")?;
+            }
+            let mut filename = self.look.1.clone();
+            self.mat(Id)?;
+            while self.look.1 == "::" {
+                self.mas("::")?;
+                filename.push('/');
+                filename.push_str(&self.look.1);
+                self.mat(Id)?;
+            }
+            filename.push_str(".son");
 
-                // Create the new file path
-                let path = if standard {
-                    SearchFolder {
-                        start: PathBuf::from(
-                            env::current_exe()
-                                .expect("Unable to determine sonny executable path")
-                                .parent()
-                                .expect("Unable to get sonny executable parent"),
-                        ),
-                        direction: Search::ParentsThenKids(3, 3),
-                    }
-                    .for_folder("std")
-                    .or_else(|_| {
-                        SearchFolder {
-                            start: env::current_dir()
-                                .expect("Unable to determine current directory"),
-                            direction: Search::ParentsThenKids(3, 3),
-                        }
-                        .for_folder("std")
-                    })
-                    .expect("Unable to find standard library folder")
+            // Create the new file path
+            let path = if standard {
+                std_folder()
+                    .ok_or_else(|| Error::new(StdNotFound).on_line(loc.to(&self.prev_loc)))?
                     .join(filename)
-                } else {
-                    PathBuf::from(&self.main_file_name)
-                        .parent()
-                        .expect("Unable to get main file parent")
-                        .join(filename.clone())
-                };
+            } else {
+                Path::new(&self.main_file_name)
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(filename)
+            };
+            let path = path.to_string_lossy().into_owned();
+            let lexer = Lexer::new(&path).map_err(|error| error.on_line(loc.to(&self.prev_loc)))?;
 
-                // Create a new parser for the file. Give it this parser's builder.
-                // It's okay. It will get the builder back when the other parser
-                // is done.
-                self.builder = Parser::new(
-                    &path.to_str().expect("unable to convert path to string"),
-                    self.builder,
-                )?
-                .parse(true)?;
+            // Temporarily pop off this file's scope
+            let temp_scope = self
+                .builder
+                .names_in_scope
+                .pop()
+                .expect("no chains in scope");
 
-                // Put back the popped file scope.
-                self.builder.names_in_scope.push(temp_scope);
-            }
-            // check for "use" keyword
-            else if self.look.1 == "use" {
-                self.mas("use")?;
-                let mut name = self.look.1.clone();
-                self.mat(Id)?;
-                let mut broke = false;
-                while self.look.1 == "::" {
-                    self.mas("::")?;
-                    if self.look.0 == Id {
-                        name.push_str(&format!("::{}", self.look.1));
-                        self.mat(Id)?;
-                    } else if self.look.1 == "*" {
-                        self.mas("*")?;
-                        self.builder.names_in_scope.insert(
-                            0,
-                            NameInScope {
-                                name: name.clone(),
-                                contents: true,
-                            },
-                        );
-                        broke = true;
-                        break;
-                    }
-                }
-                if !broke {
-                    self.builder.names_in_scope.insert(
-                        0,
-                        NameInScope {
-                            name,
-                            contents: false,
-                        },
-                    );
+            // Create a new parser for the file. Give it this parser's builder.
+            // It's okay. It will get the builder back when the other parser
+            // is done.
+            let builder = mem::take(&mut self.builder);
+            self.builder = Parser::with_lexer(&path, lexer, builder).parse_items(true);
+
+            // Put back the popped file scope.
+            self.builder.names_in_scope.push(temp_scope);
+        }
+        // check for "use" keyword
+        else if self.look.1 == "use" {
+            self.mas("use")?;
+            let loc = self.loc();
+            let mut name = self.look.1.clone();
+            self.mat(Id)?;
+            let mut broke = false;
+            while self.look.1 == "::" {
+                self.mas("::")?;
+                if self.look.0 == Id {
+                    name.push_str(&format!("::{}", self.look.1));
+                    self.mat(Id)?;
+                } else if self.look.1 == "*" {
+                    self.mas("*")?;
+                    broke = true;
+                    break;
                 }
-            } else {
-                // Declare a chain
-                self.chain_declaration(false)?;
             }
+            let loc = loc.to(&self.prev_loc);
+            let chain_name = ChainName::Scoped(name.clone());
+            if self.builder.chains.contains_key(&chain_name) {
+                self.builder.references.push((loc.clone(), chain_name));
+            }
+            let name_in_scope = NameInScope {
+                name,
+                contents: broke,
+            };
+            self.builder.uses.push((name_in_scope.clone(), loc));
+            self.builder.names_in_scope.insert(0, name_in_scope);
         }
-        // If this is not the top-level parser, finalize the top-level file chain
-        if finalize {
-            self.builder.finalize_chain();
+        // check for "test" keyword
+        else if self.look.1 == "test" && self.keyword_here() {
+            self.test()?;
+        } else {
+            // Declare a chain
+            self.chain_declaration(false)?;
         }
-        Ok(self.builder)
+        Ok(())
+    }
+    // Match a test, which is a name, optional times to evaluate it at
+    // like "at 0, 0.5", and a chain, e.g. 'test "add": 1 -> 2 -> assert(!1 + !2 == 3)'
+    fn test(&mut self) -> SonnyResult<()> {
+        let loc = self.loc();
+        self.mas("test")?;
+        let name = self.look.1.clone();
+        self.mat(StringLiteral)?;
+        let mut times = Vec::new();
+        if self.look.1 == "at" {
+            self.mat(Id)?;
+            times.push(self.real()?);
+            while self.look.1 == "," {
+                self.mas(",")?;
+                times.push(self.real()?);
+            }
+        } else {
+            times.push(0.0);
+        }
+        self.mas(":")?;
+        let chain = self.builder.new_chain(None, loc.clone())?;
+        self.chain()?;
+        self.builder.finalize_chain()?;
+        self.builder.tests.push(Test {
+            name,
+            chain,
+            times,
+            location: loc.to(&self.prev_loc),
+        });
+        Ok(())
+    }
+    // The location of the next token to be parsed
+    fn loc(&self) -> CodeLocation {
+        self.look_loc.clone()
+    }
+    // Move on to the next token
+    fn advance(&mut self) {
+        let (look, look_loc) = if self.peeked {
+            self.peeked = false;
+            (self.next.clone(), self.next_loc.clone())
+        } else {
+            (self.lexer.lex(), self.lexer.token_loc())
+        };
+        self.look = look;
+        self.prev_loc = mem::replace(&mut self.look_loc, look_loc);
     }
     // Match the next token against a token type.
     // Used when the desired match is something generic,
     // link an Id or a Num
     fn mat(&mut self, t: TokenType) -> SonnyResult<()> {
         if self.look.0 == t {
-            // println!("Expected {:?}, found {:?}", t, self.look.1.clone());
-            if self.peeked {
-                self.peeked = false;
-                self.look = self.next.clone();
-            } else {
-                self.look = self.lexer.lex();
-            }
+            self.advance();
             Ok(())
         } else {
             Err(
                 Error::new(ErrorSpec::ExpectedFound(Left(t), self.look.clone()))
-                    .on_line(self.lexer.loc()),
+                    .on_line(self.loc()),
             )
         }
     }
@@ -217,13 +402,7 @@ impl Parser {
     // This and mat() should probably be combined into a single macro.
     fn mas(&mut self, s: &str) -> SonnyResult<()> {
         if self.look.1 == s {
-            // println!("Expected {:?}, found {:?}", s, self.look.1.clone());
-            if self.peeked {
-                self.peeked = false;
-                self.look = self.next.clone();
-            } else {
-                self.look = self.lexer.lex();
-            }
+            self.advance();
             // This is probably not where this paren check should go.
             if s == "(" {
                 self.paren_level += 1;
@@ -232,7 +411,7 @@ impl Parser {
                     self.paren_level -= 1;
                 } else {
                     return Err(Error::new(ErrorSpec::CloseDelimeter(")".to_string()))
-                        .on_line(self.lexer.loc()));
+                        .on_line(self.prev_loc.clone()));
                 }
             }
             Ok(())
@@ -241,20 +420,53 @@ impl Parser {
                 Right(s.to_string()),
                 self.look.clone(),
             ))
-            .on_line(self.lexer.loc()))
+            .on_line(self.loc()))
         }
     }
     // Look at the token after the next one without consuming
     // the next one.
+    // Check whether the current token is used as a keyword. Contextual
+    // keywords are lexed as ids, so they are only keywords where they
+    // cannot be the name of a chain.
+    fn keyword_here(&mut self) -> bool {
+        if self.look.0 == Keyword {
+            return true;
+        }
+        if self.look.0 != Id || !CONTEXTUAL_KEYWORDS.contains(&self.look.1.as_str()) {
+            return false;
+        }
+        let next = self.peek();
+        let chain = self
+            .builder
+            .find_chain(&ChainName::Scoped(self.look.1.clone()))
+            .is_some();
+        match keyword_kind(&self.look.1) {
+            Some(KeywordKind::Function) | Some(KeywordKind::DurationFunction) => next.1 == "(",
+            Some(KeywordKind::Item) => next.0 == StringLiteral,
+            Some(KeywordKind::Value) => !chain,
+            // Everything else is a function of the term after it
+            _ => match next.0 {
+                Num | Id | StringLiteral | NoteString | BackLink | Dot => true,
+                Keyword => keyword_kind(&next.1) != Some(KeywordKind::Binary),
+                Delimeter => next.1 == "(" || next.1 == "[",
+                // A chain with the name can be subtracted from
+                Operator => next.1 == "-" && !chain,
+                _ => false,
+            },
+        }
+    }
     fn peek(&mut self) -> Token {
         if !self.peeked {
             self.peeked = true;
             self.next = self.lexer.lex();
+            self.next_loc = self.lexer.token_loc();
         }
         self.next.clone()
     }
     // Match a real number
     fn real(&mut self) -> SonnyResult<f64> {
+        let loc = self.loc();
+        let found = self.look.clone();
         let mut num_str = String::new();
         if self.look.1 == "pi" {
             num_str.push_str("3.14159265358979323846");
@@ -278,35 +490,46 @@ impl Parser {
                 self.mat(Num)?;
             }
         }
-        Ok(num_str
+        num_str
             .parse::<f64>()
-            .unwrap_or_else(|_| panic!("Unable to parse real num string: {}", num_str)))
+            .map_err(|_| Error::new(InvalidNumber(found)).on_line(loc))
+    }
+    // Match a whole number, like the counts of tuplets and rhythms
+    fn whole_number(&mut self) -> SonnyResult<usize> {
+        let loc = self.loc();
+        let mut found = self.look.clone();
+        let number = found.1.parse::<usize>();
+        self.mat(Num)?;
+        // A decimal is reported as a whole
+        if self.look.1 == "." && self.peek().0 == Num {
+            self.mas(".")?;
+            found.1.push('.');
+            found.1.push_str(&self.look.1);
+            self.mat(Num)?;
+        } else if let Ok(number) = number {
+            return Ok(number);
+        }
+        Err(Error::new(InvalidWholeNumber(found)).on_line(loc.to(&self.prev_loc)))
     }
-    // Convert a string representing a pitch into a number
-    fn string_to_pitch(&mut self, s: &str) -> f64 {
+    // Convert a string representing a pitch into a number, if it is a valid pitch
+    fn string_to_pitch(&mut self, s: &str) -> Option<f64> {
         let bytes = s.as_bytes();
         let letter = bytes[0] as char;
         let mut octave = self.last_note_octave as u32;
         let accidental: i32 = if bytes.len() > 1 {
             if bytes[1] as char == '#' {
                 if s.len() == 3 {
-                    octave = (bytes[2] as char)
-                        .to_digit(10)
-                        .expect("unable to convert char to digit");
+                    octave = (bytes[2] as char).to_digit(10)?;
                 }
                 1
             } else if bytes[1] as char == 'b' {
                 if s.len() == 3 {
-                    octave = (bytes[2] as char)
-                        .to_digit(10)
-                        .expect("unable to convert char to digit");
+                    octave = (bytes[2] as char).to_digit(10)?;
                 }
                 -1
             } else {
                 if s.len() == 2 {
-                    octave = (bytes[1] as char)
-                        .to_digit(10)
-                        .expect("unable to convert char to digit");
+                    octave = (bytes[1] as char).to_digit(10)?;
                 }
                 0
             }
@@ -322,27 +545,32 @@ impl Parser {
             'G' => 7,
             'A' => 9,
             'B' => 11,
-            _ => panic!("Invalid note letter"),
+            _ => return None,
         };
         self.last_note_octave = octave as usize;
         local_offset += accidental;
         let offset = local_offset + (octave * 12) as i32;
-        16.3516f64 * 1.059_463_094_359_f64.powf(f64::from(offset))
+        Some(16.3516f64 * 1.059_463_094_359_f64.powf(f64::from(offset)))
+    }
+    // Match a note name like "C#4" and convert it into a pitch
+    fn note_string(&mut self) -> SonnyResult<f64> {
+        let found = self.look.clone();
+        let loc = self.loc();
+        self.mat(NoteString)?;
+        self.string_to_pitch(&found.1)
+            .ok_or_else(|| Error::new(InvalidPitch(found)).on_line(loc))
     }
     // Match a pitch element
     fn pitch_element(&mut self) -> SonnyResult<f64> {
         Ok(if self.look.0 == NoteString {
-            let note_string = self.look.1.clone();
-            let pitch = self.string_to_pitch(&note_string);
-            self.mat(NoteString)?;
-            pitch
+            self.note_string()?
         } else if self.look.0 == Num {
             self.real()?
         } else if self.look.1 == "_" {
             self.mas("_")?;
             0.0
         } else {
-            return Err(Error::new(InvalidPitch(self.look.clone())).on_line(self.lexer.loc()));
+            return Err(Error::new(InvalidPitch(self.look.clone())).on_line(self.loc()));
         })
     }
     // Match a list of pitches
@@ -384,22 +612,16 @@ impl Parser {
     fn duration(&mut self) -> SonnyResult<f64> {
         Ok(if self.look.0 == Num {
             if self.peek().1 == "/" {
-                let num1 = self.look.1.parse::<f64>().unwrap_or_else(|_| {
-                    panic!(
-                        "Unable to parse duration num {:?} on line {:?}",
-                        self.look.1,
-                        self.lexer.loc()
-                    )
-                });
-                self.mat(Num)?;
+                let num1 = self.real()?;
                 self.mas("/")?;
-                let num2 = self.look.1.parse::<f64>().unwrap_or_else(|_| {
-                    panic!(
-                        "Unable to parse duration num {:?} on line {:?}",
-                        self.look.1,
-                        self.lexer.loc()
-                    )
-                });
+                let num2 = match self.look.1.parse::<f64>() {
+                    Ok(num2) if self.look.0 == Num && num2 > 0.0 => num2,
+                    _ => {
+                        return Err(
+                            Error::new(InvalidFraction(self.look.clone())).on_line(self.loc())
+                        )
+                    }
+                };
                 self.mat(Num)?;
                 (num1 / num2) / (self.builder.tempo / 60.0) * 4.0
             } else {
@@ -415,7 +637,7 @@ impl Parser {
                 "ts" => 0.03125,
                 _ => {
                     return Err(
-                        Error::new(DurationQuantifier(self.look.clone())).on_line(self.lexer.loc())
+                        Error::new(DurationQuantifier(self.look.clone())).on_line(self.loc())
                     )
                 }
             } / (self.builder.tempo / 60.0)
@@ -427,23 +649,23 @@ impl Parser {
             frac
         })
     }
-    // Match a note which has both pitch and duration
-    fn note(&mut self) -> SonnyResult<Note> {
-        let pitch = self.pitch()?;
-        self.mas(":")?;
+    // Match the duration of a note with the given pitch
+    fn note(&mut self, pitch: Vec<f64>) -> SonnyResult<Note> {
         let duration = if self.look.0 == Id {
             let possible_chain_name = ChainName::Scoped(self.look.1.clone());
             self.mat(Id)?;
-            if let Some(ref chain) = self.builder.find_chain(&possible_chain_name) {
+            if let Some(chain) = self.builder.find_chain(&possible_chain_name) {
+                let reference = (self.prev_loc.clone(), chain.name.clone());
                 if let ChainLinks::OnlyNotes(ref _notes_or_ids, period) = chain.links {
+                    self.builder.references.push(reference);
                     period.duration()
                 } else {
                     return Err(Error::new(DurationOfGenericChain(possible_chain_name))
-                        .on_line(self.lexer.loc()));
+                        .on_line(self.prev_loc.clone()));
                 }
             } else {
                 return Err(
-                    Error::new(CantFindChain(possible_chain_name)).on_line(self.lexer.loc())
+                    Error::new(CantFindChain(possible_chain_name)).on_line(self.prev_loc.clone())
                 );
             }
         } else {
@@ -456,15 +678,103 @@ impl Parser {
                 start: self.curr_time - duration,
                 end: self.curr_time,
             },
+            velocity: 1.0,
+        })
+    }
+    // Match the rest of a tuplet group, e.g. the "2{C:e, D:e, E:e}" in
+    // "3:2{C:e, D:e, E:e}", which fits the notes of `count` beats into
+    // the time of `span` beats.
+    fn tuplet(&mut self, count: usize, loc: CodeLocation) -> SonnyResult<(Vec<Note>, bool)> {
+        let span = self.whole_number()?;
+        if count == 0 || span == 0 {
+            return Err(Error::new(InvalidTuplet(count, span)).on_line(loc.to(&self.prev_loc)));
+        }
+        let group_start = self.curr_time;
+        self.mas("{")?;
+        let (mut notes, tied) = self.note_list()?;
+        self.mas("}")?;
+        // Scale every note in the group toward the group's start
+        let ratio = span as f64 / count as f64;
+        for note in &mut notes {
+            note.period.start = group_start + (note.period.start - group_start) * ratio;
+            note.period.end = group_start + (note.period.end - group_start) * ratio;
+        }
+        self.curr_time = group_start + (self.curr_time - group_start) * ratio;
+        Ok((notes, tied))
+    }
+    // Match a single note or a tuplet group, and whether it ends in a tie
+    fn note_item(&mut self) -> SonnyResult<(Vec<Note>, bool)> {
+        // A whole number followed by a ':' is either a pitch in hertz or
+        // the start of a tuplet group. It is a tuplet if the number after
+        // the ':' is followed by a '{'.
+        if self.look.0 == Num && self.peek().1 == ":" {
+            let loc = self.loc();
+            let found = self.look.clone();
+            let num_str = self.look.1.clone();
+            self.mat(Num)?;
+            self.mas(":")?;
+            if self.look.0 == Num && self.peek().1 == "{" {
+                let count = num_str
+                    .parse()
+                    .map_err(|_| Error::new(InvalidWholeNumber(found)).on_line(loc.clone()))?;
+                return self.tuplet(count, loc);
+            }
+            // Pitches with too many digits to be a number are errors too
+            let pitch = num_str
+                .parse::<f64>()
+                .ok()
+                .filter(|pitch| pitch.is_finite())
+                .ok_or_else(|| Error::new(InvalidNumber(found)).on_line(loc))?;
+            let note = self.note(vec![pitch])?;
+            Ok((vec![note], self.tie()?))
+        } else {
+            let pitch = self.pitch()?;
+            self.mas(":")?;
+            let note = self.note(pitch)?;
+            Ok((vec![note], self.tie()?))
+        }
+    }
+    // Match an optional '~' after a note's duration
+    fn tie(&mut self) -> SonnyResult<bool> {
+        Ok(if self.look.1 == "~" {
+            self.mas("~")?;
+            true
+        } else {
+            false
         })
     }
-    // Match a series of ,-separated notes
+    // Match a series of ,-separated notes and tuplet groups. A '~' after
+    // a note ties it to the next one, merging them into a single note.
+    // Also returns whether the last note is tied to one after the list.
+    fn note_list(&mut self) -> SonnyResult<(Vec<Note>, bool)> {
+        let mut note_list: Vec<Note> = Vec::new();
+        let mut tied = false;
+        loop {
+            let loc = self.loc();
+            let (mut item, item_tied) = self.note_item()?;
+            if tied {
+                if let Some(prev) = note_list.pop() {
+                    if prev.pitches != item[0].pitches {
+                        return Err(Error::new(InvalidTie).on_line(loc.to(&self.prev_loc)));
+                    }
+                    item[0].period.start = prev.period.start;
+                }
+            }
+            note_list.extend(item);
+            tied = item_tied;
+            if self.look.1 == "," {
+                self.mas(",")?;
+            } else {
+                break;
+            }
+        }
+        Ok((note_list, tied))
+    }
+    // Match the contents of a note list
     fn notes(&mut self) -> SonnyResult<Vec<Note>> {
-        let mut note_list = Vec::new();
-        note_list.push(self.note()?);
-        while self.look.1 == "," {
-            self.mas(",")?;
-            note_list.push(self.note()?);
+        let (note_list, tied) = self.note_list()?;
+        if tied {
+            return Err(Error::new(DanglingTie).on_line(self.prev_loc.clone()));
         }
         self.last_note_octave = 3;
         self.curr_time = 0.0;
@@ -473,19 +783,18 @@ impl Parser {
     // Match a backlink
     fn backlink(&mut self) -> SonnyResult<Operand> {
         self.mas("!")?;
+        let loc = self.prev_loc.to(&self.look_loc);
         let op = Operand::BackLink(
             if let Ok(x) = self.look.1.parse() {
                 if x == 0 {
-                    return Err(Error::new(ZeroBacklink).on_line(self.lexer.loc()));
+                    return Err(Error::new(ZeroBacklink).on_line(loc));
                 } else {
                     x
                 }
             } else {
-                return Err(
-                    Error::new(InvalidBackLink(self.look.clone())).on_line(self.lexer.loc())
-                );
+                return Err(Error::new(InvalidBackLink(self.look.clone())).on_line(loc));
             },
-            self.lexer.loc(),
+            loc,
         );
         self.mat(Num)?;
         Ok(op)
@@ -532,8 +841,23 @@ impl Parser {
         }
         Ok(Operand::Array(result))
     }
+    // Match a parenthesized list of the given number of ,-separated
+    // expressions, which are the arguments to a built-in function
+    fn arguments(&mut self, count: usize) -> SonnyResult<Vec<Operand>> {
+        let mut result = Vec::new();
+        self.mas("(")?;
+        for i in 0..count {
+            if i > 0 {
+                self.mas(",")?;
+            }
+            result.push(Operand::Expression(Box::new(self.expression()?)));
+        }
+        self.mas(")")?;
+        Ok(result)
+    }
     // Match an expression term identifier
     fn term_identifier(&mut self) -> SonnyResult<Operand> {
+        let keyword = self.keyword_here();
         match self.look.0 {
             Num => Ok(Operand::Var(Variable::Number(self.real()?))),
             StringLiteral => {
@@ -541,22 +865,54 @@ impl Parser {
                 self.mat(StringLiteral)?;
                 op
             }
-            Keyword => {
+            _ if keyword && self.look.1 == "atan2" => {
+                let loc = self.loc();
+                self.mas("atan2")?;
+                let mut args = self.arguments(2)?.into_iter();
+                Ok(Operand::Expression(Box::new(Expression(
+                    Operation::ArcTangent2(args.next().unwrap(), args.next().unwrap()),
+                    loc.to(&self.prev_loc),
+                ))))
+            }
+            _ if keyword && (self.look.1 == "clamp" || self.look.1 == "lerp") => {
+                let operation = if self.look.1 == "clamp" {
+                    Operation::Clamp
+                } else {
+                    Operation::Lerp
+                };
+                let loc = self.loc();
+                let op = self.look.1.clone();
+                self.mas(&op)?;
+                let mut args = self.arguments(3)?.into_iter();
+                Ok(Operand::Expression(Box::new(Expression(
+                    operation(
+                        args.next().unwrap(),
+                        args.next().unwrap(),
+                        args.next().unwrap(),
+                    ),
+                    loc.to(&self.prev_loc),
+                ))))
+            }
+            _ if keyword => {
                 let op = match self.look.1.as_str() {
                     "time" => Operand::Time,
                     "window_size" => Operand::WindowSize,
                     "buffer_size" => Operand::BufferSize,
                     "sample_rate" => Operand::SampleRate,
                     "wi" => Operand::WindowIndex,
+                    "true" => Operand::Var(Variable::Bool(true)),
+                    "false" => Operand::Var(Variable::Bool(false)),
                     _ => {
-                        return Err(Error::new(InvalidKeyword(self.look.1.clone()))
-                            .on_line(self.lexer.loc()))
+                        return Err(
+                            Error::new(InvalidKeyword(self.look.1.clone())).on_line(self.loc())
+                        )
                     }
                 };
-                self.mat(Keyword)?;
+                self.advance();
                 Ok(op)
             }
             Id => {
+                let loc = self.loc();
                 let mut name = ChainName::Scoped(self.look.1.clone());
                 self.mat(Id)?;
                 while self.look.1 == "::" {
@@ -568,26 +924,25 @@ impl Parser {
                         name.push_str(&next_id);
                     }
                 }
-                match self.builder.find_chain(&name) {
-                    Some(ref chain) => name = chain.name.clone(),
-                    None => return Err(Error::new(CantFindChain(name)).on_line(self.lexer.loc())),
-                }
+                let loc = loc.to(&self.prev_loc);
+                let generic = match self.builder.find_chain(&name) {
+                    Some(chain) => {
+                        let generic = matches!(chain.links, ChainLinks::Generic(..));
+                        name = chain.name.clone();
+                        self.builder.references.push((loc.clone(), name.clone()));
+                        generic
+                    }
+                    None => return Err(Error::new(CantFindChain(name)).on_line(loc)),
+                };
                 if self.look.1 == "~" {
                     self.mas("~")?;
-                    let operand = Ok(Operand::Properties(name.clone()));
-                    if let ChainLinks::Generic(..) = self
-                        .builder
-                        .find_chain(&name)
-                        .expect("Unable to find chain")
-                        .links
-                    {
-                        return Err(
-                            Error::new(PropertiesOfGenericChain(name)).on_line(self.lexer.loc())
-                        );
+                    let loc = loc.to(&self.prev_loc);
+                    if generic {
+                        return Err(Error::new(PropertiesOfGenericChain(name)).on_line(loc));
                     }
-                    operand
+                    Ok(Operand::Properties(name, loc))
                 } else {
-                    Ok(Operand::Id(name))
+                    Ok(Operand::Id(name, loc))
                 }
             }
             BackLink => Ok(self.backlink()?),
@@ -599,136 +954,126 @@ impl Parser {
                     self.mas(")")?;
                     Ok(Operand::Expression(Box::new(expr)))
                 } else if self.look.1 == "|" {
+                    let loc = self.loc();
                     self.mas("|")?;
                     let name = self.chain_declaration(true)?;
                     self.mas("|")?;
-                    Ok(Operand::Id(name))
+                    Ok(Operand::Id(name, loc.to(&self.prev_loc)))
                 } else if self.look.1 == "[" {
                     self.mas("[")?;
                     let list = self.expression_list()?;
                     self.mas("]")?;
                     Ok(list)
                 } else {
-                    Err(Error::new(InvalidDelimeter(self.look.1.clone())).on_line(self.lexer.loc()))
+                    Err(Error::new(InvalidDelimeter(self.look.1.clone())).on_line(self.loc()))
                 }
             }
-            NoteString => {
-                let note_string = self.look.1.clone();
-                let note = Operand::Var(Variable::Number(self.string_to_pitch(&note_string)));
-                self.mat(NoteString)?;
-                Ok(note)
-            }
-            Done => Err(Error::new(UnexpectedEndOfFile).on_line(self.lexer.loc())),
-            _ => Err(Error::new(InvalidTerm(self.look.clone())).on_line(self.lexer.loc())),
+            NoteString => Ok(Operand::Var(Variable::Number(self.note_string()?))),
+            Done => Err(Error::new(UnexpectedEndOfFile).on_line(self.loc())),
+            _ => Err(Error::new(InvalidTerm(self.look.clone())).on_line(self.loc())),
         }
     }
     // Match an expression term, which consists of a term_identifier and an optional indexer
     fn term(&mut self) -> SonnyResult<Expression> {
-        let mut expression = Expression(Operation::Operand(self.term_identifier()?));
+        let start = self.loc();
+        let mut expression = Expression(
+            Operation::Operand(self.term_identifier()?),
+            start.to(&self.prev_loc),
+        );
         while let Some(indexer) = self.indexer()? {
             if let Some(index) = indexer.0 {
-                expression = Expression(Operation::Index(
-                    Operand::Expression(Box::new(expression)),
-                    Operand::Expression(Box::new(index)),
-                ));
+                expression = Expression(
+                    Operation::Index(
+                        Operand::Expression(Box::new(expression)),
+                        Operand::Expression(Box::new(index)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else {
-                expression = Expression(Operation::SubArray(
-                    Operand::Expression(Box::new(expression)),
-                    indexer
-                        .1
-                        .map(|x| Operand::Expression(Box::new(x)))
-                        .unwrap_or(Operand::Var(Variable::Number(0.0))),
-                    indexer
-                        .2
-                        .map(|x| Operand::Expression(Box::new(x)))
-                        .unwrap_or(Operand::Var(Variable::Number(40000.0))),
-                ));
+                expression = Expression(
+                    Operation::SubArray(
+                        Operand::Expression(Box::new(expression)),
+                        indexer
+                            .1
+                            .map(|x| Operand::Expression(Box::new(x)))
+                            .unwrap_or(Operand::Var(Variable::Number(0.0))),
+                        indexer
+                            .2
+                            .map(|x| Operand::Expression(Box::new(x)))
+                            .unwrap_or(Operand::Var(Variable::Number(40000.0))),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             }
         }
         Ok(expression)
     }
     // Match a unary expression
     fn exp_un(&mut self) -> SonnyResult<Expression> {
-        Ok(if &self.look.1 == "-" {
-            self.mas("-")?;
-            Expression(Operation::Negate(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "sin" {
-            self.mas("sin")?;
-            Expression(Operation::Sine(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "cos" {
-            self.mas("cos")?;
-            Expression(Operation::Cosine(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "ceil" {
-            self.mas("ceil")?;
-            Expression(Operation::Ceiling(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "floor" {
-            self.mas("floor")?;
-            Expression(Operation::Floor(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "abs" {
-            self.mas("abs")?;
-            Expression(Operation::AbsoluteValue(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "avg" {
-            self.mas("avg")?;
-            Expression(Operation::Average(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "fft" {
-            self.mas("fft")?;
-            Expression(Operation::FFT(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "window" {
-            self.mas("window")?;
-            Expression(Operation::Window(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "debug" {
-            self.mas("debug")?;
-            Expression(Operation::Debug(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "print" {
-            self.mas("print")?;
-            Expression(Operation::Print(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else if &self.look.1 == "len" {
-            self.mas("len")?;
-            Expression(Operation::Length(Operand::Expression(Box::new(
-                self.exp_un()?,
-            ))))
-        } else {
-            self.term()?
-        })
+        let start = self.loc();
+        // Chains can have the names of contextual keywords
+        if self.look.0 == Id && !self.keyword_here() {
+            return self.term();
+        }
+        let operation: fn(Operand) -> Operation = match self.look.1.as_str() {
+            "-" => Operation::Negate,
+            "sin" => Operation::Sine,
+            "cos" => Operation::Cosine,
+            "tan" => Operation::Tangent,
+            "asin" => Operation::ArcSine,
+            "acos" => Operation::ArcCosine,
+            "atan" => Operation::ArcTangent,
+            "ceil" => Operation::Ceiling,
+            "floor" => Operation::Floor,
+            "round" => Operation::Round,
+            "fract" => Operation::Fraction,
+            "abs" => Operation::AbsoluteValue,
+            "sign" => Operation::Sign,
+            "exp" => Operation::Exponential,
+            "sqrt" => Operation::SquareRoot,
+            "dbtoa" => Operation::DecibelsToGain,
+            "atodb" => Operation::GainToDecibels,
+            "mtof" => Operation::MidiToFrequency,
+            "ftom" => Operation::FrequencyToMidi,
+            "avg" => Operation::Average,
+            "fft" => Operation::FFT,
+            "window" => Operation::Window,
+            "debug" => Operation::Debug,
+            "print" => Operation::Print,
+            "assert" => Operation::Assert,
+            "len" => Operation::Length,
+            _ => return self.term(),
+        };
+        let op = self.look.1.clone();
+        self.mas(&op)?;
+        Ok(Expression(
+            operation(Operand::Expression(Box::new(self.exp_un()?))),
+            start.to(&self.prev_loc),
+        ))
     }
     // Match a min/max expression
     fn exp_min_max(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_un()?;
         loop {
             if self.look.1 == "min" {
                 self.mas("min")?;
-                expr = Expression(Operation::Min(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_un()?)),
-                ));
+                expr = Expression(
+                    Operation::Min(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_un()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "max" {
                 self.mas("max")?;
-                expr = Expression(Operation::Max(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_un()?)),
-                ));
+                expr = Expression(
+                    Operation::Max(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_un()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else {
                 break;
             }
@@ -737,21 +1082,26 @@ impl Parser {
     }
     // Match a power or logarithm expression
     fn exp_pow(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr;
         if self.look.1 == "log" {
             self.mas("log")?;
-            expr = Expression(Operation::Logarithm(Operand::Expression(Box::new(
-                self.exp_pow()?,
-            ))));
+            expr = Expression(
+                Operation::Logarithm(Operand::Expression(Box::new(self.exp_pow()?))),
+                start.to(&self.prev_loc),
+            );
         } else {
             expr = self.exp_min_max()?;
             loop {
                 if self.look.1 == "^" {
                     self.mas("^")?;
-                    expr = Expression(Operation::Power(
-                        Operand::Expression(Box::new(expr)),
-                        Operand::Expression(Box::new(self.exp_min_max()?)),
-                    ));
+                    expr = Expression(
+                        Operation::Power(
+                            Operand::Expression(Box::new(expr)),
+                            Operand::Expression(Box::new(self.exp_min_max()?)),
+                        ),
+                        start.to(&self.prev_loc),
+                    );
                 } else {
                     break;
                 }
@@ -761,26 +1111,36 @@ impl Parser {
     }
     // Match a multiplication, division, or remainder expression
     fn exp_mul(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_pow()?;
         loop {
             if self.look.1 == "*" {
                 self.mas("*")?;
-                expr = Expression(Operation::Multiply(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_pow()?)),
-                ));
+                expr = Expression(
+                    Operation::Multiply(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_pow()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "/" {
                 self.mas("/")?;
-                expr = Expression(Operation::Divide(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_pow()?)),
-                ));
+                expr = Expression(
+                    Operation::Divide(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_pow()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "%" {
                 self.mas("%")?;
-                expr = Expression(Operation::Remainder(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_pow()?)),
-                ));
+                expr = Expression(
+                    Operation::Remainder(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_pow()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else {
                 break;
             }
@@ -789,20 +1149,27 @@ impl Parser {
     }
     // Match an addition or subtraction expression
     fn exp_add(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_mul()?;
         loop {
             if self.look.1 == "+" {
                 self.mas("+")?;
-                expr = Expression(Operation::Add(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_mul()?)),
-                ));
+                expr = Expression(
+                    Operation::Add(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_mul()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "-" {
                 self.mas("-")?;
-                expr = Expression(Operation::Subtract(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_mul()?)),
-                ));
+                expr = Expression(
+                    Operation::Subtract(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_mul()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else {
                 break;
             }
@@ -811,20 +1178,27 @@ impl Parser {
     }
     // Match array operation expressions
     fn exp_array_op(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_add()?;
         loop {
             if self.look.1 == "cat" {
                 self.mas("cat")?;
-                expr = Expression(Operation::Concatenate(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_add()?)),
-                ));
+                expr = Expression(
+                    Operation::Concatenate(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_add()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "find" {
                 self.mas("find")?;
-                expr = Expression(Operation::Find(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_add()?)),
-                ));
+                expr = Expression(
+                    Operation::Find(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_add()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else {
                 break;
             }
@@ -833,44 +1207,63 @@ impl Parser {
     }
     // Match a comparison expression
     fn exp_cmp(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_array_op()?;
         loop {
             if self.look.1 == "==" {
                 self.mas("==")?;
-                expr = Expression(Operation::Equal(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_array_op()?)),
-                ));
+                expr = Expression(
+                    Operation::Equal(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_array_op()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "!=" {
                 self.mas("!=")?;
-                expr = Expression(Operation::NotEqual(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_array_op()?)),
-                ));
+                expr = Expression(
+                    Operation::NotEqual(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_array_op()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "<" {
                 self.mas("<")?;
-                expr = Expression(Operation::LessThan(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_array_op()?)),
-                ));
+                expr = Expression(
+                    Operation::LessThan(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_array_op()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == ">" {
                 self.mas(">")?;
-                expr = Expression(Operation::GreaterThan(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_array_op()?)),
-                ));
+                expr = Expression(
+                    Operation::GreaterThan(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_array_op()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == "<=" {
                 self.mas("<=")?;
-                expr = Expression(Operation::LessThanOrEqual(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_array_op()?)),
-                ));
+                expr = Expression(
+                    Operation::LessThanOrEqual(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_array_op()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else if self.look.1 == ">=" {
                 self.mas(">=")?;
-                expr = Expression(Operation::GreaterThanOrEqual(
-                    Operand::Expression(Box::new(expr)),
-                    Operand::Expression(Box::new(self.exp_array_op()?)),
-                ));
+                expr = Expression(
+                    Operation::GreaterThanOrEqual(
+                        Operand::Expression(Box::new(expr)),
+                        Operand::Expression(Box::new(self.exp_array_op()?)),
+                    ),
+                    start.to(&self.prev_loc),
+                );
             } else {
                 break;
             }
@@ -879,41 +1272,53 @@ impl Parser {
     }
     // Match an OR expression
     fn exp_or(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_cmp()?;
         if self.look.1 == "||" {
             self.mas("||")?;
-            expr = Expression(Operation::Or(
-                Operand::Expression(Box::new(expr)),
-                Operand::Expression(Box::new(self.exp_or()?)),
-            ));
+            expr = Expression(
+                Operation::Or(
+                    Operand::Expression(Box::new(expr)),
+                    Operand::Expression(Box::new(self.exp_or()?)),
+                ),
+                start.to(&self.prev_loc),
+            );
         }
         Ok(expr)
     }
     // Match an AND expression
     fn exp_and(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_or()?;
         if self.look.1 == "&&" {
             self.mas("&&")?;
-            expr = Expression(Operation::And(
-                Operand::Expression(Box::new(expr)),
-                Operand::Expression(Box::new(self.exp_and()?)),
-            ));
+            expr = Expression(
+                Operation::And(
+                    Operand::Expression(Box::new(expr)),
+                    Operand::Expression(Box::new(self.exp_and()?)),
+                ),
+                start.to(&self.prev_loc),
+            );
         }
         Ok(expr)
     }
     // Match a ternary expression
     fn exp_tern(&mut self) -> SonnyResult<Expression> {
+        let start = self.loc();
         let mut expr = self.exp_and()?;
         if self.look.1 == "?" {
             self.mas("?")?;
-            expr = Expression(Operation::Ternary(
-                Operand::Expression(Box::new(expr)),
-                Operand::Expression(Box::new(self.exp_tern()?)),
-                Operand::Expression({
-                    self.mas(":")?;
-                    Box::new(self.exp_tern()?)
-                }),
-            ));
+            expr = Expression(
+                Operation::Ternary(
+                    Operand::Expression(Box::new(expr)),
+                    Operand::Expression(Box::new(self.exp_tern()?)),
+                    Operand::Expression({
+                        self.mas(":")?;
+                        Box::new(self.exp_tern()?)
+                    }),
+                ),
+                start.to(&self.prev_loc),
+            );
         }
         Ok(expr)
     }
@@ -921,15 +1326,123 @@ impl Parser {
     fn expression(&mut self) -> SonnyResult<Expression> {
         self.exp_tern()
     }
+    // Match a ratio, which is a real or a fraction of two reals
+    fn ratio(&mut self) -> SonnyResult<f64> {
+        let num = self.real()?;
+        Ok(if self.look.1 == "/" {
+            self.mas("/")?;
+            let (found, loc) = (self.look.clone(), self.loc());
+            let bottom = self.real()?;
+            if bottom == 0.0 {
+                return Err(Error::new(InvalidRatio(found)).on_line(loc.to(&self.prev_loc)));
+            }
+            num / bottom
+        } else {
+            num
+        })
+    }
+    // Match a note transform
+    fn transform(&mut self) -> SonnyResult<NoteTransform> {
+        let loc = self.loc();
+        Ok(if self.look.1 == "swing" {
+            self.mas("swing")?;
+            self.mas("(")?;
+            let unit = self.duration()?;
+            self.mas(",")?;
+            let ratio = self.ratio()?;
+            self.mas(")")?;
+            if !(unit.is_finite() && unit > 0.0 && ratio > 0.0 && ratio < 1.0) {
+                return Err(Error::new(InvalidSwing(unit, ratio)).on_line(loc.to(&self.prev_loc)));
+            }
+            NoteTransform::Swing(unit, ratio)
+        } else {
+            self.mas("humanize")?;
+            self.mas("(")?;
+            let timing = self.duration()?;
+            self.mas(",")?;
+            let velocity = self.ratio()?;
+            if !(timing.is_finite() && timing >= 0.0 && velocity.is_finite() && velocity >= 0.0) {
+                return Err(
+                    Error::new(InvalidHumanize(timing, velocity)).on_line(loc.to(&self.prev_loc))
+                );
+            }
+            // The seed is optional
+            let seed = if self.look.1 == "," {
+                self.mas(",")?;
+                self.whole_number()? as u64
+            } else {
+                0
+            };
+            self.mas(")")?;
+            NoteTransform::Humanize(timing, velocity, seed)
+        })
+    }
+    // Match a rhythm generator, which is either a euclidean rhythm like
+    // "euclid(3, 8, e)" or a step pattern like "steps("x..x..x.", e)".
+    // Both can be given the pitch of their hits as a last argument.
+    fn rhythm(&mut self) -> SonnyResult<Vec<Note>> {
+        let loc = self.loc();
+        let steps = if self.look.1 == "euclid" {
+            self.mas("euclid")?;
+            self.mas("(")?;
+            let hits = self.whole_number()?;
+            self.mas(",")?;
+            let steps = self.whole_number()?;
+            if let Some(steps) = euclid(hits, steps) {
+                steps
+            } else {
+                return Err(Error::new(InvalidEuclid(hits, steps)).on_line(loc.to(&self.prev_loc)));
+            }
+        } else {
+            self.mas("steps")?;
+            self.mas("(")?;
+            let pattern = self.look.1.clone();
+            self.mat(StringLiteral)?;
+            if let Some(steps) = step_pattern(&pattern) {
+                steps
+            } else {
+                return Err(Error::new(InvalidStepPattern(pattern)).on_line(self.prev_loc.clone()));
+            }
+        };
+        self.mas(",")?;
+        let duration = self.duration()?;
+        let pitches = if self.look.1 == "," {
+            self.mas(",")?;
+            let pitches = self.pitch()?;
+            self.last_note_octave = 3;
+            pitches
+        } else {
+            vec![1.0]
+        };
+        self.mas(")")?;
+        Ok(steps_to_notes(&steps, duration, &pitches))
+    }
     // Match a chain link
     fn link(&mut self) -> SonnyResult<()> {
+        // Check for note transforms
+        if (self.look.1 == "swing" || self.look.1 == "humanize") && self.keyword_here() {
+            let loc = self.loc();
+            let transform = self.transform()?;
+            self.builder
+                .new_transform(transform, loc.to(&self.prev_loc));
+        // Check for rhythm generators
+        } else if (self.look.1 == "euclid" || self.look.1 == "steps") && self.keyword_here() {
+            let loc = self.loc();
+            let notes = self.rhythm()?;
+            self.builder.new_expression(Expression(
+                Operation::Operand(Operand::Notes(notes)),
+                loc.to(&self.prev_loc),
+            ))
         // Check for notes
-        if self.look.1 == "{" {
+        } else if self.look.1 == "{" {
+            let loc = self.loc();
             self.mas("{")?;
             let notes = self.notes()?;
             self.mas("}")?;
-            self.builder
-                .new_expression(Expression(Operation::Operand(Operand::Notes(notes))))
+            self.builder.new_expression(Expression(
+                Operation::Operand(Operand::Notes(notes)),
+                loc.to(&self.prev_loc),
+            ))
         // It's an expression otherwise
         } else {
             let expr = self.expression()?;
@@ -945,7 +1458,7 @@ impl Parser {
             if self.look.1 == "out" {
                 if self.builder.out_declared.is_none() {
                     self.builder.play_chain();
-                    self.builder.out_declared = Some(self.lexer.loc());
+                    self.builder.out_declared = Some(self.loc());
                     self.mas("out")?;
                     if self.look.1 == ":" {
                         self.mas(":")?;
@@ -953,10 +1466,10 @@ impl Parser {
                     }
                     break;
                 } else {
-                    return Err(Error::new(MultipleOutChains(
+                    return Err(Error::new(MultipleOutChains(Box::new(
                         self.builder.out_declared.clone().unwrap(),
-                    ))
-                    .on_line(self.lexer.loc()));
+                    )))
+                    .on_line(self.loc()));
                 }
             } else {
                 self.link()?;
@@ -967,17 +1480,36 @@ impl Parser {
     // Match an entire chain, including its name
     fn chain_declaration(&mut self, name_optional: bool) -> SonnyResult<ChainName> {
         let mut name = None;
+        let loc = self.loc();
         if self.look.0 == Id && self.peek().1 == ":" || !name_optional {
             if !name_optional && self.look.0 != Id {
-                return Err(Error::new(UnnamedTopChain).on_line(self.lexer.loc()));
+                return Err(Error::new(UnnamedTopChain).on_line(loc));
             }
             name = Some(self.look.1.clone());
             self.mat(Id)?;
             self.mas(":")?;
         }
-        let chain_name = self.builder.new_chain(name, self.lexer.loc())?;
+        let chain_name = self.builder.new_chain(name, loc)?;
         self.chain()?;
-        self.builder.finalize_chain();
+        self.builder.finalize_chain()?;
         Ok(chain_name)
     }
 }
+
+// Find the standard library folder, which is searched for near the
+// sonny executable and then near the current directory
+fn std_folder() -> Option<PathBuf> {
+    let search = |start: PathBuf| {
+        SearchFolder {
+            start,
+            direction: Search::ParentsThenKids(3, 3),
+        }
+        .for_folder("std")
+        .ok()
+    };
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(PathBuf::from))
+        .and_then(search)
+        .or_else(|| env::current_dir().ok().and_then(search))
+}
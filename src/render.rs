@@ -0,0 +1,135 @@
+// This module contains functions for rendering chains into audio
+
+use std::f64;
+
+use hound;
+
+use builder::*;
+use error::*;
+
+// Settings used when rendering a chain
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    // The sample rate in samples/second
+    pub sample_rate: f64,
+    // The number of samples processed in each window
+    pub window_size: usize,
+    // The number of samples before each window that are also processed
+    pub buffer_size: usize,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            sample_rate: 32000.0,
+            window_size: 4000,
+            buffer_size: 10,
+        }
+    }
+}
+
+impl Builder {
+    // Find the name of the chain marked with "out"
+    pub fn out_chain(&self) -> Option<&ChainName> {
+        self.chains.iter().find(|f| f.1.play).map(|f| f.0)
+    }
+    // Find the time at which the audio ends, which is the end of the
+    // longest notes chain or the time set with "out", whichever is later
+    pub fn audio_end(&self) -> f64 {
+        // TODO: make this only take into account chains that are actually used
+        let mut end: f64 = 1.0;
+        for chain in self.chains.values() {
+            if let ChainLinks::OnlyNotes(ref _notes_or_ids, period) = chain.links {
+                end = end.max(period.end);
+            }
+        }
+        end.max(self.end_time)
+    }
+    // Render a chain from the start time to the end time into a buffer of samples
+    pub fn render(
+        &self,
+        name: &ChainName,
+        start_time: f64,
+        end_time: f64,
+        settings: RenderSettings,
+    ) -> SonnyResult<Vec<f64>> {
+        self.render_with_progress(name, start_time, end_time, settings, |_, _| ())
+    }
+    // Render a chain from the start time to the end time into a buffer of samples.
+    // The progress function is called with the time of the start of each window
+    // before it is evaluated, and with the samples of the window before it.
+    pub fn render_with_progress<F>(
+        &self,
+        name: &ChainName,
+        start_time: f64,
+        end_time: f64,
+        settings: RenderSettings,
+        mut progress: F,
+    ) -> SonnyResult<Vec<f64>>
+    where
+        F: FnMut(f64, &[f64]),
+    {
+        let RenderSettings {
+            sample_rate,
+            window_size,
+            buffer_size,
+        } = settings;
+        let mut song = vec![0f64; (sample_rate * (end_time - start_time)).max(0.0) as usize];
+        // run each sample window as a batch
+        let window_count = (song.len() as f64 / window_size as f64).ceil() as usize;
+        let mut last_window = 0..0;
+        for window_start in (0..window_count).map(|x| x * window_size) {
+            // Determine the buffer size and adjusted window start
+            let this_buffer_size = if window_start == 0 { 0 } else { buffer_size };
+            let window_start = window_start - this_buffer_size;
+            // Determine the time
+            let time = window_start as f64 / sample_rate + start_time;
+            progress(time, &song[last_window.clone()]);
+            if time >= end_time {
+                break;
+            }
+            // Evaluate
+            let window_result = self.evaluate_chain(
+                name,
+                &[],
+                time,
+                window_size.min(song.len() - window_start),
+                this_buffer_size,
+                sample_rate,
+            )?;
+            let mut window_end = window_start;
+            for (i, r) in window_result.into_iter().skip(this_buffer_size).enumerate() {
+                song[i + window_start] = f64::from(r);
+                window_end = i + window_start + 1;
+            }
+            last_window = window_start..window_end;
+        }
+        Ok(song)
+    }
+}
+
+// Convert a sample to the 16-bit value that is written to WAV files
+pub fn quantize(sample: f64) -> i16 {
+    let amplitude = f64::from(i16::MAX);
+    (sample * amplitude).min(amplitude) as i16
+}
+
+// Write samples to a 16-bit mono WAV file
+pub fn write_wav(filename: &str, samples: &[f64], sample_rate: f64) -> SonnyResult<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(filename, spec)
+        .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(filename.to_string())))?;
+    for s in samples {
+        writer
+            .write_sample(quantize(*s))
+            .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(filename.to_string())))?;
+    }
+    writer
+        .finalize()
+        .map_err(|_| Error::new(ErrorSpec::CantWriteOutputFile(filename.to_string())))
+}
@@ -0,0 +1,229 @@
+// This module contains the state of an interactive Sonny session.
+// Declarations are added to a builder that is kept alive between
+// inputs, and expressions are evaluated or rendered against it.
+
+use builder::{variable::Variable, *};
+use error::*;
+use lexer::{Lexer, Token, TokenType::*};
+use parser::Parser;
+use render::RenderSettings;
+
+// The name of the file that REPL input is parsed as. It is used in error
+// messages, to name the session's top-level chain, and to find included files.
+static REPL_FILE: &str = "repl";
+
+// The name given to the temporary chain that an expression is parsed into.
+// It cannot be written as an id, so it never clashes with user chains.
+static EXPRESSION_CHAIN: &str = "<expression>";
+
+// An interactive session
+pub struct Repl {
+    builder: Builder,
+}
+
+impl Default for Repl {
+    fn default() -> Repl {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    // Makes a new session with nothing declared
+    pub fn new() -> Repl {
+        let mut builder = Builder::new();
+        let loc = Lexer::from_source(REPL_FILE, "").loc();
+        builder
+            .new_chain(Some(REPL_FILE.to_string()), loc)
+            .expect("Unable to create REPL chain");
+        builder.files.push(REPL_FILE.to_string());
+        Repl { builder }
+    }
+    // Check whether the input is complete, or whether it has unclosed
+    // delimeters or ends with "->" or "," and continues on the next line
+    pub fn is_complete(input: &str) -> bool {
+        let mut depth = 0isize;
+        let mut last = String::new();
+        for (Token(token_type, text), _) in Lexer::from_source(REPL_FILE, input) {
+            if token_type == Delimeter {
+                match text.as_str() {
+                    "(" | "[" | "{" => depth += 1,
+                    ")" | "]" | "}" => depth -= 1,
+                    _ => (),
+                }
+            }
+            last = text;
+        }
+        depth <= 0 && last != "->" && last != ","
+    }
+    // Check whether the input declares something, like a chain or a "use",
+    // rather than being an expression to evaluate
+    pub fn is_declaration(input: &str) -> bool {
+        let mut lexer = Lexer::from_source(REPL_FILE, input);
+        match lexer.lex() {
+            Token(Keyword, ref word) => ["tempo", "std", "include", "use"].contains(&word.as_str()),
+            Token(Id, _) => lexer.lex().1 == ":",
+            _ => false,
+        }
+    }
+    // Add declarations to the session. Redeclaring a chain replaces it,
+    // and the chains that use it are checked again.
+    // If there are any errors, the session is left unchanged and all of
+    // them are returned. Otherwise, any warnings are returned.
+    pub fn declare(&mut self, input: &str) -> Result<Vec<Error>, Vec<Error>> {
+        let mut builder = self.builder.clone();
+        let mut lexer = Lexer::from_source(REPL_FILE, input);
+        let mut redeclared = None;
+        if let (Token(Id, name), Token(_, colon)) = (lexer.lex(), lexer.lex()) {
+            let name = format!("{}::{}", REPL_FILE, name);
+            if colon == ":" {
+                if let Some(chain) = builder.find_chain(&ChainName::Scoped(name.clone())) {
+                    let notes = matches!(chain.links, ChainLinks::OnlyNotes(..));
+                    redeclared = Some((chain.name.clone(), notes));
+                }
+                remove_chain(&mut builder, &name);
+            }
+        }
+        let mut builder = Parser::from_source(REPL_FILE, input, builder).parse_more();
+        let mut diagnostics = builder.diagnostics.split_off(0);
+        if let Some((name, notes)) = redeclared {
+            if !diagnostics.iter().any(Error::is_fatal) {
+                diagnostics.extend(check_dependents(&builder, &name, notes));
+            }
+        }
+        if diagnostics.iter().any(Error::is_fatal) {
+            Err(diagnostics)
+        } else {
+            self.builder = builder;
+            Ok(diagnostics)
+        }
+    }
+    // Evaluate an expression at a time. Expressions are evaluated like a
+    // chain with no backlink arguments.
+    pub fn evaluate(
+        &self,
+        expression: &str,
+        time: f64,
+        sample_rate: f64,
+    ) -> Result<Variable, Vec<Error>> {
+        let builder = self.parse_expression(expression)?;
+        builder.tracer.restart();
+        let mut values = builder
+            .evaluate_chain(&expression_chain(), &[], time, 1, 0, sample_rate)
+            .map_err(|error| vec![error])?;
+        Ok(values.pop().unwrap_or(Variable::Number(0.0)))
+    }
+    // Render an expression from the start time to the end time into a
+    // buffer of samples
+    pub fn render(
+        &self,
+        expression: &str,
+        start_time: f64,
+        end_time: f64,
+        settings: RenderSettings,
+    ) -> Result<Vec<f64>, Vec<Error>> {
+        let builder = self.parse_expression(expression)?;
+        let mut errors = builder.check_output_shape(&expression_chain());
+        errors.retain(Error::is_fatal);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        builder.tracer.restart();
+        builder
+            .render(&expression_chain(), start_time, end_time, settings)
+            .map_err(|error| vec![error])
+    }
+    // Take the values of "debug" and "print" expressions that were
+    // recorded while evaluating or rendering
+    pub fn take_messages(&self) -> Vec<Error> {
+        self.builder.tracer.take_messages()
+    }
+    // The names of the chains declared in the session, sorted
+    pub fn chain_names(&self) -> Vec<String> {
+        let prefix = format!("{}::", REPL_FILE);
+        let mut names: Vec<String> = self
+            .builder
+            .chains
+            .keys()
+            .filter_map(|name| match name {
+                ChainName::Scoped(name) if name.starts_with(&prefix) => {
+                    Some(name[prefix.len()..].to_string())
+                }
+                _ => None,
+            })
+            .collect();
+        names.sort();
+        names
+    }
+    // Parse an expression into a temporary chain in a copy of the builder
+    fn parse_expression(&self, expression: &str) -> Result<Builder, Vec<Error>> {
+        let builder = Parser::from_source(REPL_FILE, expression, self.builder.clone())
+            .parse_chain(EXPRESSION_CHAIN)?;
+        let errors = builder.check_backlinks(&expression_chain());
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        // Only shapes that cannot be evaluated stop the expression
+        if let Err(mut errors) = builder.shape(&expression_chain()) {
+            errors.retain(Error::is_fatal);
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+        }
+        Ok(builder)
+    }
+}
+
+// The full name of the temporary chain that expressions are parsed into
+fn expression_chain() -> ChainName {
+    ChainName::Scoped(format!("{}::{}", REPL_FILE, EXPRESSION_CHAIN))
+}
+
+// Check the chains that use a chain that was redeclared, since it may
+// now take more arguments or give a different shape. Chains that use the
+// notes of a chain that was made of notes need it to still be.
+fn check_dependents(builder: &Builder, name: &ChainName, notes: bool) -> Vec<Error> {
+    let chain = match builder.find_chain(name) {
+        Some(chain) => chain,
+        None => return Vec::new(),
+    };
+    if notes && matches!(chain.links, ChainLinks::Generic(..)) {
+        if let Some((user, loc)) = builder.notes_user(name) {
+            return vec![
+                Error::new(ErrorSpec::NotesChainMadeGeneric(name.clone(), user))
+                    .on_line(chain.location.clone())
+                    .with_label(loc, "its notes are used here"),
+            ];
+        }
+    }
+    let mut errors = builder.check_used_backlinks();
+    for dependent in builder.dependents(name) {
+        if builder
+            .find_chain(&dependent)
+            .is_some_and(|chain| chain.play)
+        {
+            errors.extend(builder.check_backlinks(&dependent));
+        }
+        if builder.arity(&dependent) == 0 {
+            if let Err(shape_errors) = builder.shape(&dependent) {
+                errors.extend(shape_errors);
+            }
+        }
+    }
+    errors
+}
+
+// Remove a chain and all of the chains declared inside of it
+fn remove_chain(builder: &mut Builder, name: &str) {
+    let inner = format!("{}::", name);
+    let mut removed_out = false;
+    builder.chains.retain(|chain_name, chain| match chain_name {
+        ChainName::Scoped(chain_name) if chain_name == name || chain_name.starts_with(&inner) => {
+            removed_out |= chain.play;
+            false
+        }
+        _ => true,
+    });
+    if removed_out {
+        builder.out_declared = None;
+    }
+}
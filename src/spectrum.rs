@@ -0,0 +1,49 @@
+// This module contains the windowed Fourier transform of frames of audio,
+// which golden tests compare spectra with and spectrograms are drawn from
+
+use std::sync::Arc;
+
+use rustfft::{num_complex::Complex, num_traits::Zero, FFTplanner, FFT};
+
+// Finds the spectra of frames of samples that all have the same size
+pub struct Stft {
+    fft: Arc<dyn FFT<f64>>,
+    frame_size: usize,
+}
+
+impl Stft {
+    pub fn new(frame_size: usize) -> Stft {
+        let mut planner = FFTplanner::new(false);
+        Stft {
+            fft: planner.plan_fft(frame_size),
+            frame_size,
+        }
+    }
+    // The magnitudes of the frequencies in the frame of samples that starts
+    // at an index, from 0 up to half of the sample rate. Samples outside
+    // of the buffer are silent.
+    pub fn frame(&self, samples: &[f64], start: isize) -> Vec<f64> {
+        let size = self.frame_size;
+        // Each frame is faded in and out so that its edges do not add noise
+        let mut input: Vec<Complex<f64>> = (0..size)
+            .map(|i| {
+                let window =
+                    0.5 - 0.5 * (2.0 * ::std::f64::consts::PI * i as f64 / size as f64).cos();
+                let index = start + i as isize;
+                let sample = if index >= 0 {
+                    samples.get(index as usize).cloned().unwrap_or(0.0)
+                } else {
+                    0.0
+                };
+                Complex::new(sample * window, 0.0)
+            })
+            .collect();
+        let mut output = vec![Complex::zero(); size];
+        self.fft.process(&mut input, &mut output);
+        output
+            .into_iter()
+            .take(size / 2)
+            .map(|bin| bin.norm())
+            .collect()
+    }
+}
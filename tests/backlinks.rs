@@ -0,0 +1,37 @@
+// Checks that backlinks are checked where chains are used
+
+extern crate sonny;
+
+use sonny::error::ErrorSpec;
+
+#[test]
+fn chains_that_feed_too_few_links_are_reported() {
+    let source = "\
+f: !1 + !2
+g: 1 -> f
+song: 2 -> 3 -> f -> out
+";
+    let errors = match sonny::parse_source("backlinks.son", source) {
+        Ok(_) => panic!("\"g\" gives \"f\" too few links"),
+        Err(errors) => errors,
+    };
+    assert_eq!(errors.len(), 1);
+    match errors[0].spec {
+        ErrorSpec::UnsatisfiedBacklink(_, 2, 1) => (),
+        ref spec => panic!("Unexpected error: {}", spec),
+    }
+    assert_eq!(errors[0].labels[0].0.line, 2);
+}
+
+#[test]
+fn chains_that_forward_their_arguments_are_allowed() {
+    let source = "\
+a: !1 + !2 + !3
+b: !1 -> a -> !1
+c: a -> !1 -> !1
+song: 1 -> 2 -> 3 -> b -> c -> out
+";
+    if let Err(errors) = sonny::parse_source("backlinks.son", source) {
+        panic!("Unable to parse: {}", errors[0].spec);
+    }
+}
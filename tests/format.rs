@@ -0,0 +1,33 @@
+// Checks the layout the formatter gives code
+
+extern crate sonny;
+
+#[test]
+fn note_lists_wrap_at_bars() {
+    let source = "long: {C4:q, D4:q, E4:q, F4:h, G4:q, A4:q, B4:q, C5:q, D5:q, E5:q, F5:q, G5:q, \
+                  A5:q, B5:q, C6:q, D6:q}\n";
+    let formatted = sonny::format::format_source("format.son", source)
+        .unwrap_or_else(|error| panic!("Unable to format: {}", error.spec));
+    // The half note goes past the end of the first bar, so it starts the
+    // line that ends with the second bar
+    assert_eq!(
+        formatted,
+        "\
+long: {
+    C4:q, D4:q, E4:q,
+    F4:h, G4:q, A4:q, B4:q,
+    C5:q, D5:q, E5:q, F5:q,
+    G5:q, A5:q, B5:q, C6:q,
+    D6:q
+}
+"
+    );
+}
+
+#[test]
+fn example_is_formatted() {
+    let source = std::fs::read_to_string("example.son").expect("Unable to read example");
+    let formatted = sonny::format::format_source("example.son", &source)
+        .unwrap_or_else(|error| panic!("Unable to format: {}", error.spec));
+    assert_eq!(formatted, source, "example.son is not formatted");
+}
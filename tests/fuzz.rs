@@ -0,0 +1,225 @@
+// Feeds lots of generated source code to the lexer, parser, and evaluator to
+// make sure that no input makes them panic. Bad input should only ever
+// produce errors.
+
+extern crate sonny;
+
+use std::{fs, panic};
+
+use sonny::{lexer::Lexer, repl::Repl};
+
+// Pieces of Sonny code that generated sources are made of
+static FRAGMENTS: &[&str] = &[
+    "a",
+    "b",
+    "mel",
+    "gen",
+    "::",
+    ":",
+    "->",
+    "out",
+    "out:",
+    "!",
+    "!1",
+    "!0",
+    "!99999999999999999999",
+    "{",
+    "}",
+    "(",
+    ")",
+    "[",
+    "]",
+    "|",
+    ",",
+    ".",
+    "..",
+    "~",
+    "_",
+    "+",
+    "-",
+    "*",
+    "/",
+    "%",
+    "^",
+    "?",
+    "==",
+    "!=",
+    "<",
+    ">=",
+    "&&",
+    "||",
+    "&",
+    "=",
+    "0",
+    "1",
+    "3",
+    "440",
+    "1.5",
+    ".5",
+    "pi",
+    "C",
+    "C#4",
+    "Bb",
+    "G9",
+    "A4",
+    "w",
+    "h",
+    "q",
+    "e",
+    "s",
+    "ts",
+    "q.",
+    "3/8",
+    "1/0",
+    "3:2",
+    "tempo",
+    "tempo:",
+    "std",
+    "include",
+    "use",
+    "*",
+    "time",
+    "sin",
+    "cos",
+    "atan2",
+    "clamp",
+    "lerp",
+    "fft",
+    "window",
+    "debug",
+    "print",
+    "assert",
+    "test",
+    "test \"t\" at 0, 1:",
+    "len",
+    "cat",
+    "find",
+    "min",
+    "max",
+    "log",
+    "avg",
+    "swing",
+    "humanize",
+    "euclid",
+    "steps",
+    "true",
+    "false",
+    "wi",
+    "sample_rate",
+    "\"x..x\"",
+    "\"\"",
+    "\"unterminated",
+    "\"\\",
+    "# comment\n",
+    "#/ block /#",
+    "#/",
+    "\n",
+    " ",
+    "\t",
+    "é",
+    "\u{1F3B5}",
+    "$",
+    "\0",
+];
+
+// A small deterministic random number generator
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// Make sure that lexing, parsing, evaluating, and formatting the source does
+// not panic, and that formatting keeps the code the same and does not change
+// formatted code
+fn check(source: &str) {
+    let result = panic::catch_unwind(|| {
+        for _ in Lexer::from_source("fuzz.son", source) {}
+        if let Ok(builder) = sonny::parse_source("fuzz.son", source) {
+            if let Some(out) = builder.out_chain() {
+                let _ = builder.evaluate_chain(out, &[], 0.0, 1, 16, 8000.0);
+            }
+        }
+        let formatted = sonny::format_source("fuzz.son", source);
+        assert!(formatted.is_ok(), "Formatting changed the code");
+        let formatted = formatted.unwrap();
+        let again = sonny::format_source("fuzz.son", &formatted).ok();
+        assert_eq!(
+            again.as_ref(),
+            Some(&formatted),
+            "Formatting is not idempotent"
+        );
+    });
+    assert!(result.is_ok(), "Failed on source:\n{}", source);
+}
+
+#[test]
+fn generated_sources_do_not_panic() {
+    let mut rng = Rng(0x5EED_50DD);
+    for _ in 0..3000 {
+        let len = rng.below(40);
+        let mut source = String::new();
+        for _ in 0..len {
+            source.push_str(FRAGMENTS[rng.below(FRAGMENTS.len())]);
+            if rng.below(3) == 0 {
+                source.push(' ');
+            }
+        }
+        check(&source);
+    }
+}
+
+#[test]
+fn mutated_examples_do_not_panic() {
+    let mut rng = Rng(0xC0FF_EE00);
+    let examples: Vec<String> = ["example.son", "std/gen.son", "std/arsd.son"]
+        .iter()
+        .map(|file| fs::read_to_string(file).expect("Unable to read example file"))
+        .collect();
+    for example in &examples {
+        let chars: Vec<char> = example.chars().collect();
+        // Every prefix of a file is checked for unexpected ends of files
+        for end in (0..chars.len()).step_by(7) {
+            check(&chars[..end].iter().collect::<String>());
+        }
+        // Random characters are deleted, duplicated, or replaced
+        for _ in 0..300 {
+            let mut mutated = chars.clone();
+            for _ in 0..1 + rng.below(4) {
+                let i = rng.below(mutated.len());
+                match rng.below(3) {
+                    0 => {
+                        mutated.remove(i);
+                    }
+                    1 => {
+                        let c = mutated[i];
+                        mutated.insert(i, c);
+                    }
+                    _ => {
+                        let fragment = FRAGMENTS[rng.below(FRAGMENTS.len())];
+                        mutated.splice(i..i + 1, fragment.chars());
+                    }
+                }
+            }
+            check(&mutated.iter().collect::<String>());
+        }
+    }
+}
+
+#[test]
+fn redeclared_chains_do_not_panic() {
+    let mut repl = Repl::new();
+    for input in &["mel: {C:q, D:q}", "props: mel~[0]", "mel: 440"] {
+        let _ = repl.declare(input);
+    }
+    let result = panic::catch_unwind(|| repl.evaluate("props", 0.0, 8000.0));
+    assert!(result.is_ok(), "Evaluating a redeclared chain panicked");
+}
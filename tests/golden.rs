@@ -0,0 +1,35 @@
+// Renders the chains listed in tests/golden/golden.txt and compares them
+// with their references, which are saved with "sonny test --bless"
+
+extern crate sonny;
+
+use sonny::golden::{read_manifest, TestResult};
+
+#[test]
+fn renders_match_references() {
+    let tests = read_manifest("tests/golden/golden.txt").expect("Unable to read manifest");
+    assert!(!tests.is_empty(), "The manifest has no tests");
+    let mut failures = Vec::new();
+    for test in &tests {
+        match test.run() {
+            TestResult::Passed(..) => (),
+            TestResult::Failed(reasons, ..) => {
+                failures.push(format!("{}: {}", test.description(), reasons.join(", ")))
+            }
+            TestResult::Errors(errors) => failures.push(format!(
+                "{}: {}",
+                test.description(),
+                errors
+                    .iter()
+                    .map(|error| error.spec.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Golden tests failed:\n{}",
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,67 @@
+// Draws images of simple signals and checks that they are where they
+// should be in the images
+
+extern crate sonny;
+
+use sonny::image::{spectrogram, waveform, FrequencyScale, ImageSettings};
+
+fn brightness(color: [u8; 3]) -> u32 {
+    color.iter().map(|&c| u32::from(c)).sum()
+}
+
+#[test]
+fn spectrogram_shows_sine_frequency() {
+    let sample_rate = 8000.0;
+    let samples: Vec<f64> = (0..8000)
+        .map(|i| (i as f64 / sample_rate * 1000.0 * 2.0 * std::f64::consts::PI).sin())
+        .collect();
+    let settings = ImageSettings {
+        width: 20,
+        height: 400,
+        frequency_scale: FrequencyScale::Linear,
+        ..ImageSettings::default()
+    };
+    let image = spectrogram(&samples, sample_rate, &settings);
+    let x = image.width / 2;
+    let brightest = (0..image.height)
+        .max_by_key(|&y| brightness(image.get(x, y)))
+        .unwrap();
+    // 1000 Hz is a quarter of the way up from 0 to 4000 Hz
+    let expected = image.height * 3 / 4;
+    assert!(
+        (brightest as isize - expected as isize).abs() <= 2,
+        "The sine is drawn at row {} instead of {}",
+        brightest,
+        expected
+    );
+}
+
+#[test]
+fn waveform_of_silence_is_flat() {
+    let settings = ImageSettings {
+        width: 50,
+        height: 21,
+        ..ImageSettings::default()
+    };
+    let samples = vec![0.0; 1000];
+    let image = waveform(&[(&samples, [255, 255, 255])], &settings);
+    for x in 0..image.width {
+        for y in 0..image.height {
+            let white = image.get(x, y) == [255, 255, 255];
+            assert_eq!(white, y == 10, "pixel ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn empty_images_are_errors() {
+    let settings = ImageSettings {
+        width: 0,
+        height: 0,
+        ..ImageSettings::default()
+    };
+    let samples = vec![0.0; 1000];
+    let image = waveform(&[(&samples, [255, 255, 255])], &settings);
+    assert!(image.to_png().is_err());
+    assert!(image.to_svg().is_err());
+}
@@ -0,0 +1,31 @@
+// Checks the JSON that errors are printed as with --error-format=json
+
+extern crate serde_json;
+extern crate sonny;
+
+use std::fs;
+
+use serde_json::Value;
+
+#[test]
+fn errors_in_nested_files_have_their_paths() {
+    let folder = std::env::temp_dir().join("sonny_json_path");
+    fs::create_dir_all(folder.join("lib")).expect("Unable to create folder");
+    fs::write(folder.join("lib/bass.son"), "bass: missing\n").expect("Unable to write file");
+    let main = folder.join("song.son");
+    fs::write(&main, "include lib::bass\nsong: lib::bass -> out\n").expect("Unable to write file");
+    let errors = match sonny::parse_file(main.to_str().expect("Temporary folder is not UTF-8")) {
+        Ok(_) => panic!("The missing chain was not reported"),
+        Err(errors) => errors,
+    };
+    let json: Value = serde_json::from_str(&errors[0].to_json()).expect("Invalid JSON");
+    assert_eq!(json["code"], "cant_find_chain");
+    assert_eq!(json["location"]["file"], "bass.son");
+    let path = json["location"]["path"]
+        .as_str()
+        .expect("The location has no path");
+    assert_eq!(
+        fs::canonicalize(path).ok(),
+        fs::canonicalize(folder.join("lib/bass.son")).ok()
+    );
+}
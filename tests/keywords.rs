@@ -0,0 +1,63 @@
+// Checks that chains can have the names of contextual keywords
+
+extern crate sonny;
+
+#[test]
+fn chains_can_have_contextual_keyword_names() {
+    let source = "\
+std gen
+round: {C:q, E:q}
+steps: 2
+true: 0.5
+test: 3
+swing: round -> swing(e, 2/3)
+uses: round -> !1[0] * steps * test * true
+functions: 1.6 -> round !1 + sign -1 + tan 0 -> (false ? 1 : 0) + !1
+song: uses -> |swing~ -> !1[0] -> gen::sine| -> !1 * 0 -> out
+test \"round is still a function\": 1.6 -> assert(round !1 == 2)
+test \"true is a chain\": true -> assert(!1 == 0.5)
+";
+    let builder = sonny::parse_source("keywords.son", source)
+        .unwrap_or_else(|errors| panic!("Unable to parse: {}", errors[0].spec));
+    let tests = builder.tests_in("keywords.son");
+    assert_eq!(tests.len(), 2);
+    for test in tests {
+        let errors = builder.run_test(test, 8000.0);
+        assert!(errors.is_empty(), "Test \"{}\" failed", test.name);
+    }
+}
+
+#[test]
+fn chains_with_keyword_names_are_operands() {
+    let source = "\
+exp: 3
+minus: exp - 1
+commented: exp # not the exp function
+-> !1 * 2
+test \"exp can be subtracted from\": minus -> assert(!1 == 2)
+test \"exp can be before a comment\": commented -> assert(!1 == 6)
+test \"exp is still a function\": exp 0 -> assert(!1 == 1)
+";
+    let builder = sonny::parse_source("operands.son", source)
+        .unwrap_or_else(|errors| panic!("Unable to parse: {}", errors[0].spec));
+    let tests = builder.tests_in("operands.son");
+    assert_eq!(tests.len(), 3);
+    for test in tests {
+        let errors = builder.run_test(test, 8000.0);
+        assert!(errors.is_empty(), "Test \"{}\" failed", test.name);
+    }
+    let formatted = sonny::format::format_source("operands.son", source)
+        .unwrap_or_else(|error| panic!("Unable to format: {}", error.spec));
+    assert!(formatted.contains("minus: exp - 1"), "{}", formatted);
+}
+
+#[test]
+fn keywords_are_words() {
+    for &(keyword, _) in sonny::lexer::KEYWORDS {
+        assert!(
+            keyword.chars().all(|c| c.is_alphanumeric() || c == '_'),
+            "\"{}\" is not a word",
+            keyword
+        );
+    }
+}
@@ -0,0 +1,58 @@
+// Checks loudness analysis against the values that BS.1770 gives for
+// simple signals
+
+extern crate sonny;
+
+use std::f64::consts::PI;
+
+use sonny::loudness::{analyze, apply_gain, normalization_gain};
+
+fn sine(frequency: f64, amplitude: f64, phase: f64, seconds: f64, sample_rate: f64) -> Vec<f64> {
+    (0..(seconds * sample_rate) as usize)
+        .map(|i| amplitude * (2.0 * PI * frequency * i as f64 / sample_rate + phase).sin())
+        .collect()
+}
+
+#[test]
+fn full_scale_sine_is_minus_3_lufs() {
+    for &sample_rate in &[32000.0, 48000.0] {
+        let loudness = analyze(&sine(1000.0, 1.0, 0.0, 5.0, sample_rate), sample_rate);
+        assert!(
+            (loudness.integrated + 3.01).abs() < 0.05,
+            "A full scale 1 kHz sine at {} Hz is {} LUFS",
+            sample_rate,
+            loudness.integrated
+        );
+        assert!((loudness.rms + 3.01).abs() < 0.01);
+    }
+}
+
+#[test]
+fn true_peak_is_found_between_samples() {
+    // A sine at a quarter of the sample rate that is sampled halfway
+    // between its peaks
+    let samples = sine(8000.0, 1.0, PI / 4.0, 1.0, 32000.0);
+    let loudness = analyze(&samples, 32000.0);
+    assert!((loudness.peak + 3.01).abs() < 0.01);
+    assert!(
+        loudness.true_peak.abs() < 0.2,
+        "The true peak is {} dBTP",
+        loudness.true_peak
+    );
+}
+
+#[test]
+fn normalizes_to_loudness_without_going_over_peak() {
+    let sample_rate = 32000.0;
+    let mut samples = sine(1000.0, 0.1, 0.0, 3.0, sample_rate);
+    let loudness = analyze(&samples, sample_rate);
+    let gain = normalization_gain(&loudness, None, Some(-14.0)).unwrap();
+    apply_gain(&mut samples, gain);
+    assert!((analyze(&samples, sample_rate).integrated + 14.0).abs() < 0.01);
+    // Reaching 0 LUFS would take the peak over -1 dBTP
+    let gain = normalization_gain(&loudness, Some(-1.0), Some(0.0)).unwrap();
+    assert!((loudness.true_peak + gain + 1.0).abs() < 1e-9);
+    // Silence can't be normalized
+    let silence = analyze(&[0.0; 32000], sample_rate);
+    assert!(normalization_gain(&silence, Some(-1.0), Some(-14.0)).is_none());
+}
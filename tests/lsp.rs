@@ -0,0 +1,129 @@
+// Talks to the language server over its stdin and stdout like an editor would
+
+#[macro_use]
+extern crate serde_json;
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use serde_json::Value;
+
+// A running language server
+struct Server {
+    child: Child,
+    output: BufReader<ChildStdout>,
+}
+
+impl Server {
+    fn start() -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_sonny-lsp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Unable to start language server");
+        let output = BufReader::new(child.stdout.take().expect("No server output"));
+        let mut server = Server { child, output };
+        server.send(json!({ "jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {} }));
+        server.receive();
+        server
+    }
+    fn send(&mut self, message: Value) {
+        let content = message.to_string();
+        let input = self.child.stdin.as_mut().expect("No server input");
+        write!(
+            input,
+            "Content-Length: {}\r\n\r\n{}",
+            content.len(),
+            content
+        )
+        .expect("Unable to write to server");
+    }
+    fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            self.output
+                .read_line(&mut header)
+                .expect("Unable to read from server");
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse().expect("Invalid content length");
+            }
+        }
+        let mut content = vec![0; length];
+        self.output
+            .read_exact(&mut content)
+            .expect("Unable to read from server");
+        serde_json::from_slice(&content).expect("Invalid message from server")
+    }
+    fn open(&mut self, uri: &str, text: &str) -> Value {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "languageId": "sonny", "version": 1, "text": text } }
+        }));
+        self.receive()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn opening_a_document_publishes_its_diagnostics() {
+    let mut server = Server::start();
+    let message = server.open(
+        "file:///tmp/sonny_lsp/a.son",
+        "mel: {C:q}\nsong: mel -> nope -> out\n",
+    );
+    assert_eq!(message["method"], "textDocument/publishDiagnostics");
+    assert_eq!(message["params"]["uri"], "file:///tmp/sonny_lsp/a.son");
+    let diagnostics = message["params"]["diagnostics"]
+        .as_array()
+        .expect("No diagnostics");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["code"], "cant_find_chain");
+    assert_eq!(
+        diagnostics[0]["range"]["start"],
+        json!({ "line": 1, "character": 13 })
+    );
+}
+
+#[test]
+fn definitions_are_found_in_included_files_with_the_same_name() {
+    // The included file has the same name as a file in another folder
+    let folder = std::env::temp_dir().join("sonny_lsp_definition");
+    fs::create_dir_all(folder.join("lib")).expect("Unable to create folder");
+    fs::write(folder.join("lib/lib.son"), "bass: {C2:h}\n").expect("Unable to write file");
+    let main = folder.join("lib.son");
+    let uri = format!("file://{}", main.display());
+    let mut server = Server::start();
+    let message = server.open(&uri, "include lib::lib\nsong: lib::bass -> out\n");
+    assert_eq!(message["params"]["diagnostics"], json!([]));
+    server.send(json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "textDocument/definition",
+        "params": { "textDocument": { "uri": uri }, "position": { "line": 1, "character": 12 } }
+    }));
+    let response = server.receive();
+    assert_eq!(response["id"], 1);
+    assert_eq!(
+        response["result"]["uri"],
+        format!("file://{}", folder.join("lib/lib.son").display())
+    );
+    assert_eq!(
+        response["result"]["range"]["start"],
+        json!({ "line": 0, "character": 0 })
+    );
+}
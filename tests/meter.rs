@@ -0,0 +1,28 @@
+// Measures the levels of simple signals
+
+extern crate sonny;
+
+use sonny::meter::{decibels, Levels, Meter};
+
+#[test]
+fn levels_of_sine() {
+    let samples: Vec<f64> = (0..8000)
+        .map(|i| 0.5 * (i as f64 / 8000.0 * 100.0 * 2.0 * std::f64::consts::PI).sin())
+        .collect();
+    let levels = Levels::of(&samples);
+    assert!((levels.peak - 0.5).abs() < 1e-3);
+    assert!((levels.rms - 0.5 / 2f64.sqrt()).abs() < 1e-3);
+    assert!((decibels(levels.peak) + 6.02).abs() < 0.01);
+    assert_eq!(levels.clipped, 0);
+}
+
+#[test]
+fn meter_counts_clipping() {
+    let mut meter = Meter::default();
+    meter.update(&[0.5, 1.5, -2.0, 0.0]);
+    meter.update(&[f64::NAN, 1.0]);
+    assert_eq!(meter.window.clipped, 1);
+    assert_eq!(meter.total.clipped, 3);
+    assert_eq!(meter.total.peak, 2.0);
+    assert_eq!(meter.lines(10, 4).len(), 5);
+}
@@ -0,0 +1,18 @@
+// Checks that bad numbers in note lists are reported instead of played
+
+extern crate sonny;
+
+#[test]
+fn pitches_that_are_not_numbers_are_errors() {
+    let source = format!("mel: {{440:q, {}:q}}\n", "9".repeat(400));
+    let errors = match sonny::parse_source("notes.son", &source) {
+        Ok(_) => panic!("A pitch too large to be a number parsed"),
+        Err(errors) => errors,
+    };
+    assert_eq!(errors[0].spec.code(), "invalid_number");
+    let loc = errors[0]
+        .location
+        .as_ref()
+        .expect("The error has no location");
+    assert_eq!((loc.line, loc.column), (1, 14));
+}
@@ -0,0 +1,49 @@
+// Profiles a render of the example and checks that the time spent in
+// nested chains is attributed to them
+
+extern crate sonny;
+
+use sonny::{ChainName, RenderSettings};
+
+#[test]
+fn profile_records_nested_chains() {
+    let builder = sonny::parse_file("example.son").expect("Unable to parse example");
+    let name = ChainName::Scoped("example::example".to_string());
+    let settings = RenderSettings {
+        sample_rate: 8000.0,
+        ..RenderSettings::default()
+    };
+    builder.profiler.enable();
+    builder
+        .render(&name, 0.0, 1.0, settings)
+        .expect("Unable to render example");
+    let chains = builder.profiler.chains();
+    let example = chains
+        .iter()
+        .find(|chain| chain.name == "example::example")
+        .expect("The out chain was not profiled");
+    // The buffers before windows are evaluated too
+    assert_eq!(example.samples, 8000 + settings.buffer_size);
+    for chain in &chains {
+        assert!(
+            chain.own <= chain.total,
+            "{} has more own time than total",
+            chain.name
+        );
+        assert!(
+            chain.total <= example.total,
+            "{} took longer than the out chain",
+            chain.name
+        );
+    }
+    assert!(chains.iter().any(|chain| chain.name == "gen::saw"));
+    assert!(builder
+        .profiler
+        .operations()
+        .iter()
+        .any(|op| op.name == "Multiply"));
+    let folded = builder.profiler.folded();
+    assert!(folded
+        .lines()
+        .any(|line| line.starts_with("example::example;") && line.contains("gen::saw")));
+}
@@ -0,0 +1,52 @@
+// Checks that redeclaring chains in the REPL checks the chains that use them
+
+extern crate sonny;
+
+use sonny::{error::ErrorSpec, repl::Repl};
+
+fn declare_all(repl: &mut Repl, inputs: &[&str]) {
+    for input in inputs {
+        if let Err(errors) = repl.declare(input) {
+            panic!("Unable to declare \"{}\": {}", input, errors[0].spec);
+        }
+    }
+}
+
+#[test]
+fn notes_chains_with_used_notes_stay_notes() {
+    let mut repl = Repl::new();
+    declare_all(&mut repl, &["mel: {C:q, D:q}", "props: mel~[0]"]);
+    let errors = repl.declare("mel: 440").unwrap_err();
+    assert!(matches!(
+        errors[0].spec,
+        ErrorSpec::NotesChainMadeGeneric(..)
+    ));
+    // The session is unchanged, so the old chain is still used
+    assert!(repl.evaluate("props", 0.0, 8000.0).is_ok());
+}
+
+#[test]
+fn dependents_are_checked_for_backlinks() {
+    let mut repl = Repl::new();
+    declare_all(&mut repl, &["f: !1", "g: 1 -> f"]);
+    let errors = repl.declare("f: !1 + !2").unwrap_err();
+    assert!(matches!(
+        errors[0].spec,
+        ErrorSpec::UnsatisfiedBacklink(_, 2, 1)
+    ));
+}
+
+#[test]
+fn dependents_are_checked_for_shapes() {
+    let mut repl = Repl::new();
+    declare_all(&mut repl, &["a: [1, 2]", "b: a + [1, 2]"]);
+    // Mixed shapes are warnings, so the chain is still redeclared
+    let warnings = repl
+        .declare("a: [1, 2, 3]")
+        .unwrap_or_else(|errors| panic!("Unable to declare: {}", errors[0].spec));
+    assert!(matches!(
+        warnings[0].spec,
+        ErrorSpec::ArrayLengthMismatch(..)
+    ));
+    declare_all(&mut repl, &["a: [3, 4]"]);
+}
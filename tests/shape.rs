@@ -0,0 +1,50 @@
+// Checks that the shapes of chains are checked even when nothing uses them
+
+extern crate sonny;
+
+use sonny::error::ErrorSpec;
+
+// The errors and warnings found in a source
+fn diagnostics(source: &str) -> Vec<ErrorSpec> {
+    match sonny::parse_source("shape.son", source) {
+        Ok(builder) => builder.diagnostics,
+        Err(errors) => errors,
+    }
+    .into_iter()
+    .map(|error| error.spec)
+    .collect()
+}
+
+#[test]
+fn unused_chains_are_checked() {
+    let errors = diagnostics("bad: [1, 2] + [1, 2, 3]\nsong: 1 -> !1 * 0 -> out\n");
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], ErrorSpec::ArrayLengthMismatch(..)));
+}
+
+#[test]
+fn shapes_are_checked_with_backlink_errors() {
+    let errors = diagnostics("song: !1 -> [1, 2] + [1, 2, 3] -> out\n");
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .any(|spec| matches!(*spec, ErrorSpec::UnsatisfiedBacklink(..))));
+    assert!(errors
+        .iter()
+        .any(|spec| matches!(*spec, ErrorSpec::ArrayLengthMismatch(..))));
+}
+
+#[test]
+fn mixed_shapes_still_render() {
+    let source = "song: [1, 2] + [1, 2, 3] -> out\n";
+    let builder = sonny::parse_source("shape.son", source)
+        .unwrap_or_else(|errors| panic!("Unable to parse: {}", errors[0].spec));
+    assert!(builder
+        .diagnostics
+        .iter()
+        .all(|warning| !warning.is_fatal()));
+    assert!(matches!(
+        builder.diagnostics[0].spec,
+        ErrorSpec::ArrayLengthMismatch(..)
+    ));
+}
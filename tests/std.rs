@@ -0,0 +1,51 @@
+// Runs the tests declared in the standard library
+
+extern crate sonny;
+
+use std::fs;
+
+#[test]
+fn std_tests_pass() {
+    for file in &["std/gen.son", "std/arsd.son"] {
+        let builder = sonny::parse_file(file).expect("Unable to parse std file");
+        let tests = builder.tests_in(file);
+        assert!(!tests.is_empty(), "{} has no tests", file);
+        for test in tests {
+            let errors = builder.run_test(test, 32000.0);
+            assert!(
+                errors.is_empty(),
+                "Test \"{}\" in {} failed: {}",
+                test.name,
+                file,
+                errors[0].spec
+            );
+        }
+    }
+}
+
+#[test]
+fn tests_are_found_by_path() {
+    // The included file has the same name as the file that includes it
+    let folder = std::env::temp_dir().join("sonny_tests_by_path");
+    fs::create_dir_all(folder.join("lib")).expect("Unable to create folder");
+    fs::write(
+        folder.join("lib/lib.son"),
+        "bass: {C2:h}\ntest \"bass\": bass -> assert(len !1 == 1)\n",
+    )
+    .expect("Unable to write file");
+    let main = folder.join("lib.son");
+    fs::write(
+        &main,
+        "include lib::lib\nsong: lib::bass -> out\ntest \"song\": song -> assert(len !1 == 1)\n",
+    )
+    .expect("Unable to write file");
+    let main = main.to_str().expect("Temporary folder is not UTF-8");
+    let builder = sonny::parse_file(main)
+        .unwrap_or_else(|errors| panic!("Unable to parse: {}", errors[0].spec));
+    let names: Vec<&str> = builder
+        .tests_in(main)
+        .into_iter()
+        .map(|test| test.name.as_str())
+        .collect();
+    assert_eq!(names, ["song"]);
+}
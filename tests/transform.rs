@@ -0,0 +1,66 @@
+// Checks the timing of notes after they are transformed
+
+extern crate sonny;
+
+use sonny::builder::{ChainLinks, ChainName};
+
+// The start and end of each note in a chain, and the chain's end
+fn note_times(source: &str, chain: &str) -> (Vec<(f64, f64)>, f64) {
+    let builder = sonny::parse_source("transform.son", source).expect("Unable to parse source");
+    let chain = builder
+        .find_chain(&ChainName::Scoped(chain.to_string()))
+        .expect("Unable to find chain");
+    match chain.links {
+        ChainLinks::OnlyNotes(ref notes_or_ids, period) => (
+            builder
+                .flatten_notes(notes_or_ids, 0.0)
+                .iter()
+                .map(|note| (note.period.start, note.period.end))
+                .collect(),
+            period.end,
+        ),
+        _ => panic!("The chain is not a notes chain"),
+    }
+}
+
+#[test]
+fn swing_stays_inside_chain() {
+    // Three eighth notes are one and a half swing pairs, so the last
+    // note would end past the chain if it were not clamped
+    let (notes, end) = note_times(
+        "tempo: 120\nmel: {C:e, D:e, E:e} -> swing(e, 2/3)\nsong: mel -> out",
+        "mel",
+    );
+    assert_eq!(end, 0.75);
+    assert!((notes[0].1 - 1.0 / 3.0).abs() < 1e-9);
+    assert_eq!(notes[2], (0.5, 0.75));
+    for (start, stop) in notes {
+        assert!(start <= stop && stop <= end);
+    }
+}
+
+// The code of the error that parsing a source gives
+fn error_code(source: &str) -> &'static str {
+    match sonny::parse_source("transform.son", source) {
+        Ok(_) => panic!("\"{}\" parsed without errors", source),
+        Err(errors) => errors[0].spec.code(),
+    }
+}
+
+#[test]
+fn invalid_transforms_are_errors() {
+    let swing = |args: &str| format!("mel: {{C:q, D:q}} -> swing({})\n", args);
+    let humanize = |args: &str| format!("mel: {{C:q, D:q}} -> humanize({})\n", args);
+    assert_eq!(error_code(&swing("e, 0/0")), "invalid_ratio");
+    assert_eq!(error_code(&swing("e, 1/0")), "invalid_ratio");
+    assert_eq!(error_code(&swing("e, 3/2")), "invalid_swing");
+    assert_eq!(
+        error_code(&swing(&format!("{}/8, 2/3", "9".repeat(400)))),
+        "invalid_swing"
+    );
+    assert_eq!(error_code(&humanize("0.01, 0/0")), "invalid_ratio");
+    assert_eq!(
+        error_code(&humanize(&format!("{}, 0.1", "9".repeat(400)))),
+        "invalid_humanize"
+    );
+}